@@ -36,9 +36,10 @@ func Backfill(w http.ResponseWriter, r *http.Request) {
 		URL        string
 		Body       string
 		DocumentID sql.NullInt64
+		Published  sql.NullTime
 	}
 	rows, err := db.Pool.Query(ctx, `
-		SELECT id, url, body_text, document_id
+		SELECT id, url, body_text, document_id, published_at
 		FROM ingested_documents
 		WHERE processed = false
 		ORDER BY fetched_at DESC
@@ -49,7 +50,7 @@ func Backfill(w http.ResponseWriter, r *http.Request) {
 	var items []row
 	for rows.Next() {
 		var it row
-		if err := rows.Scan(&it.ID, &it.URL, &it.Body, &it.DocumentID); err != nil {
+		if err := rows.Scan(&it.ID, &it.URL, &it.Body, &it.DocumentID, &it.Published); err != nil {
 			http.Error(w, err.Error(), 500); return
 		}
 		items = append(items, it)
@@ -74,10 +75,11 @@ func Backfill(w http.ResponseWriter, r *http.Request) {
 		if !items[i].DocumentID.Valid {
 			if err := tx.QueryRow(ctx, `
 				INSERT INTO documents (company_id, url, doc_type, published_at, text, created_at)
-				VALUES (NULL, $1, 'webpage', NULL, $2, now())
-				ON CONFLICT (url) DO UPDATE SET text=EXCLUDED.text
+				VALUES (NULL, $1, 'webpage', $3, $2, now())
+				ON CONFLICT (url) DO UPDATE SET text=EXCLUDED.text,
+				  published_at=COALESCE(EXCLUDED.published_at, documents.published_at)
 				RETURNING id
-			`, items[i].URL, items[i].Body).Scan(&docID); err != nil {
+			`, items[i].URL, items[i].Body, items[i].Published).Scan(&docID); err != nil {
 				http.Error(w, "insert document: "+err.Error(), 500); return
 			}
 			resp.DocsCreated++
@@ -97,8 +99,8 @@ func Backfill(w http.ResponseWriter, r *http.Request) {
 				var pid int64
 				if err := tx.QueryRow(ctx, `
 					INSERT INTO passages (company_id, document_id, text, published_at, created_at)
-					VALUES (NULL, $1, $2, NULL, $3) RETURNING id
-				`, docID, c, now).Scan(&pid); err != nil {
+					VALUES (NULL, $1, $2, $3, $4) RETURNING id
+				`, docID, c, items[i].Published, now).Scan(&pid); err != nil {
 					http.Error(w, "insert passage: "+err.Error(), 500); return
 				}
 				pending = append(pending, p2e{ID: pid, Text: c})
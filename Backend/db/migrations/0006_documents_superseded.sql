@@ -0,0 +1,15 @@
+-- Supersedes 0005_documents.sql, which described the crawler's page store
+-- under the name `documents` already taken by 0001_init.sql. Where 0001 ran
+-- first it was a no-op; where it ran on its own it created a table nothing
+-- reads, because the worker stores pages in ingested_documents under its
+-- own migrations (services/worker-rust/migrations, `worker-rust migrate up`).
+-- Move that shape out of the way (0001's table has an id column, it doesn't).
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM information_schema.columns
+             WHERE table_schema = 'public' AND table_name = 'documents' AND column_name = 'url')
+     AND NOT EXISTS (SELECT 1 FROM information_schema.columns
+                     WHERE table_schema = 'public' AND table_name = 'documents' AND column_name = 'id') THEN
+    ALTER TABLE public.documents RENAME TO documents_0005_unused;
+  END IF;
+END $$;
@@ -0,0 +1,38 @@
+-- Content store written by the worker. Written with IF NOT EXISTS because
+-- databases created before the migration runner already have the table
+-- (from the old ensure_tables) in some earlier shape.
+CREATE TABLE IF NOT EXISTS public.ingested_documents (
+  id            bigserial PRIMARY KEY,
+  url           text NOT NULL UNIQUE,
+  fetched_at    timestamptz NOT NULL,
+  title         text,
+  description   text,
+  body_text     text NOT NULL,
+  content_type  text,
+  http_status   int NOT NULL,
+  content_hash  text,
+  lang          text,
+  etag          text,
+  created_at    timestamptz NOT NULL DEFAULT now(),
+  updated_at    timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_ingested_fetched_at
+  ON public.ingested_documents (fetched_at DESC);
+
+ALTER TABLE public.ingested_documents
+  ADD COLUMN IF NOT EXISTS last_modified text,
+  ADD COLUMN IF NOT EXISTS author        text,
+  ADD COLUMN IF NOT EXISTS published_at  timestamptz,
+  ADD COLUMN IF NOT EXISTS page_offsets  int[],
+  ADD COLUMN IF NOT EXISTS encoding      text,
+  ADD COLUMN IF NOT EXISTS fetched_url   text,
+  ADD COLUMN IF NOT EXISTS publisher     text,
+  ADD COLUMN IF NOT EXISTS section       text,
+  ADD COLUMN IF NOT EXISTS image_url     text,
+  ADD COLUMN IF NOT EXISTS metadata      jsonb,
+  ADD COLUMN IF NOT EXISTS truncated     boolean NOT NULL DEFAULT false,
+  ADD COLUMN IF NOT EXISTS original_size bigint;
+CREATE INDEX IF NOT EXISTS idx_ingested_published_at
+  ON public.ingested_documents (published_at DESC);
+CREATE INDEX IF NOT EXISTS idx_ingested_fetched_url
+  ON public.ingested_documents (fetched_url);
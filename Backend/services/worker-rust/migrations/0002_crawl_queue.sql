@@ -0,0 +1,27 @@
+-- Crawl frontier. IF NOT EXISTS for the same reason as 0001.
+CREATE TABLE IF NOT EXISTS public.crawl_queue (
+  id            bigserial PRIMARY KEY,
+  url           text NOT NULL UNIQUE,
+  priority      int  NOT NULL DEFAULT 0,
+  next_fetch_at timestamptz NOT NULL DEFAULT now(),
+  last_status   int,
+  last_error    text,
+  tries         int  NOT NULL DEFAULT 0,
+  created_at    timestamptz NOT NULL DEFAULT now(),
+  updated_at    timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_crawl_next
+  ON public.crawl_queue (next_fetch_at, priority DESC);
+
+ALTER TABLE public.crawl_queue
+  ADD COLUMN IF NOT EXISTS depth           int NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS discovered_from text,
+  ADD COLUMN IF NOT EXISTS error_code      text,
+  ADD COLUMN IF NOT EXISTS state           text NOT NULL DEFAULT 'active',
+  ADD COLUMN IF NOT EXISTS dead_at         timestamptz,
+  ADD COLUMN IF NOT EXISTS leased_until    timestamptz,
+  ADD COLUMN IF NOT EXISTS leased_by       text;
+CREATE INDEX IF NOT EXISTS idx_crawl_leased_by
+  ON public.crawl_queue (leased_by) WHERE leased_by IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_crawl_error_code
+  ON public.crawl_queue (error_code) WHERE error_code IS NOT NULL;
@@ -0,0 +1,32 @@
+-- Feed subscriptions and the entries seen in them.
+CREATE TABLE IF NOT EXISTS public.feeds (
+  id                 bigserial PRIMARY KEY,
+  url                text NOT NULL UNIQUE,
+  title              text,
+  priority           int  NOT NULL DEFAULT 90,
+  poll_interval_secs int  NOT NULL DEFAULT 900,
+  next_poll_at       timestamptz NOT NULL DEFAULT now(),
+  etag               text,
+  last_modified      text,
+  last_polled_at     timestamptz,
+  last_status        int,
+  last_error         text,
+  created_at         timestamptz NOT NULL DEFAULT now(),
+  updated_at         timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_feeds_next_poll
+  ON public.feeds (next_poll_at);
+
+CREATE TABLE IF NOT EXISTS public.feed_items (
+  id            bigserial PRIMARY KEY,
+  feed_id       bigint NOT NULL REFERENCES public.feeds(id) ON DELETE CASCADE,
+  guid          text NOT NULL,
+  url           text NOT NULL,
+  title         text,
+  author        text,
+  published_at  timestamptz,
+  first_seen_at timestamptz NOT NULL DEFAULT now(),
+  UNIQUE (feed_id, guid)
+);
+CREATE INDEX IF NOT EXISTS idx_feed_items_url
+  ON public.feed_items (url);
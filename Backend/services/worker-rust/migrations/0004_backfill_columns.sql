@@ -0,0 +1,7 @@
+-- Columns the api-gateway backfill (pipeline.go) reads and writes but which
+-- nothing used to create.
+ALTER TABLE public.ingested_documents
+  ADD COLUMN IF NOT EXISTS processed   boolean NOT NULL DEFAULT false,
+  ADD COLUMN IF NOT EXISTS document_id bigint;
+CREATE INDEX IF NOT EXISTS idx_ingested_unprocessed
+  ON public.ingested_documents (fetched_at DESC) WHERE NOT processed;
@@ -0,0 +1,19 @@
+-- One row per distinct content_hash seen for a document, so edits to a page
+-- can be listed and diffed after ingested_documents has been overwritten.
+CREATE TABLE public.document_versions (
+  id           bigserial PRIMARY KEY,
+  document_id  bigint NOT NULL REFERENCES public.ingested_documents(id) ON DELETE CASCADE,
+  version      int    NOT NULL,
+  content_hash text,
+  title        text,
+  body_text    text   NOT NULL,
+  http_status  int    NOT NULL,
+  fetched_at   timestamptz NOT NULL,
+  created_at   timestamptz NOT NULL DEFAULT now(),
+  UNIQUE (document_id, version)
+);
+
+-- what we already hold becomes version 1
+INSERT INTO public.document_versions (document_id, version, content_hash, title, body_text, http_status, fetched_at)
+SELECT id, 1, content_hash, title, body_text, http_status, fetched_at
+FROM public.ingested_documents;
@@ -0,0 +1,31 @@
+-- URLs or whole domains whose content changes should raise alerts.
+CREATE TABLE public.watches (
+  id          bigserial PRIMARY KEY,
+  kind        text NOT NULL CHECK (kind IN ('url', 'domain')),
+  target      text NOT NULL,
+  label       text,
+  -- alert when 1 - similarity of the extracted text reaches this
+  min_change  double precision NOT NULL DEFAULT 0.02,
+  webhook_url text,
+  created_at  timestamptz NOT NULL DEFAULT now(),
+  UNIQUE (kind, target)
+);
+
+CREATE TABLE public.alerts (
+  id              bigserial PRIMARY KEY,
+  watch_id        bigint NOT NULL REFERENCES public.watches(id) ON DELETE CASCADE,
+  document_id     bigint NOT NULL REFERENCES public.ingested_documents(id) ON DELETE CASCADE,
+  url             text NOT NULL,
+  from_version    int NOT NULL,
+  to_version      int NOT NULL,
+  similarity      double precision NOT NULL,
+  lines_added     int NOT NULL,
+  lines_removed   int NOT NULL,
+  diff            text NOT NULL,
+  created_at      timestamptz NOT NULL DEFAULT now(),
+  webhook_status  int,
+  webhook_error   text,
+  delivered_at    timestamptz
+);
+CREATE INDEX idx_alerts_watch ON public.alerts (watch_id, created_at DESC);
+CREATE INDEX idx_alerts_created ON public.alerts (created_at DESC);
@@ -0,0 +1,14 @@
+-- Raw response archive when ARCHIVE=postgres; see archive.rs.
+CREATE TABLE public.raw_responses (
+  content_hash text PRIMARY KEY,
+  url          text NOT NULL,
+  fetched_at   timestamptz NOT NULL,
+  http_status  int NOT NULL,
+  headers      jsonb NOT NULL,
+  truncated    boolean NOT NULL DEFAULT false,
+  body_size    bigint NOT NULL,
+  body_gz      bytea NOT NULL,
+  created_at   timestamptz NOT NULL DEFAULT now()
+);
+-- exports walk document_versions by content_hash
+CREATE INDEX idx_document_versions_hash ON public.document_versions (content_hash);
@@ -0,0 +1,5 @@
+-- Which version of the extraction code produced each row's derived fields
+-- (scrape::EXTRACTOR_VERSION). Rows from before versioning count as 0 and
+-- are picked up by the first reprocess run.
+ALTER TABLE public.ingested_documents ADD COLUMN IF NOT EXISTS extractor_version int NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS idx_ingested_documents_extractor ON public.ingested_documents (extractor_version, id);
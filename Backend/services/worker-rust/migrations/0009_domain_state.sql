@@ -0,0 +1,22 @@
+-- Per-host health, written by the crawler's circuit breaker (breaker.rs):
+-- whether the host's circuit is open, running latency and error-rate
+-- figures, and its cached robots.txt and crawl-delay.
+CREATE TABLE public.domain_state (
+  host                 text PRIMARY KEY,
+  origin               text NOT NULL,
+  circuit              text NOT NULL DEFAULT 'closed' CHECK (circuit IN ('closed', 'open', 'half_open')),
+  consecutive_failures int NOT NULL DEFAULT 0,
+  open_until           timestamptz,
+  requests             bigint NOT NULL DEFAULT 0,
+  failures             bigint NOT NULL DEFAULT 0,
+  -- exponentially weighted, newest sample weighs 0.1 (a plain mean for the first 10)
+  avg_latency_ms       double precision,
+  error_rate           double precision NOT NULL DEFAULT 0,
+  last_success_at      timestamptz,
+  last_failure_at      timestamptz,
+  last_error           text,
+  robots_txt           text,
+  robots_fetched_at    timestamptz,
+  crawl_delay_ms       int,
+  updated_at           timestamptz NOT NULL DEFAULT now()
+);
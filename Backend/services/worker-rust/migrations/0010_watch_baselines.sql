@@ -0,0 +1,11 @@
+-- The version of a document each watch last alerted on. New versions are
+-- compared with it rather than with the version just before, so a page
+-- edited in several small steps still adds up to an alert. Without a row
+-- the baseline is the version held when the watch was created.
+CREATE TABLE public.watch_baselines (
+  watch_id    bigint NOT NULL REFERENCES public.watches(id) ON DELETE CASCADE,
+  document_id bigint NOT NULL REFERENCES public.ingested_documents(id) ON DELETE CASCADE,
+  version     int    NOT NULL,
+  updated_at  timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (watch_id, document_id)
+);
@@ -0,0 +1,9 @@
+-- Which extractor produced each version's title and text, so reprocess can
+-- bring older versions up to date as well and diffs compare the output of
+-- one extractor. Versions holding their document's current body take the
+-- document's version; the rest count as 0.
+ALTER TABLE public.document_versions ADD COLUMN IF NOT EXISTS extractor_version int NOT NULL DEFAULT 0;
+UPDATE public.document_versions v
+SET extractor_version = d.extractor_version
+FROM public.ingested_documents d
+WHERE d.id = v.document_id AND d.content_hash IS NOT DISTINCT FROM v.content_hash;
@@ -0,0 +1,466 @@
+//! Raw response archive: status, headers and gzipped body of every page we
+//! extract, keyed by `content_hash` (SHA-256 of the body), so an improved
+//! extractor can be re-run and what a page said on a date can be shown.
+//! Lives either in a content-addressed directory or in `raw_responses`;
+//! `export_warc`/`import_warc` move it between environments as WARC 1.1.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use url::Url;
+
+use crate::pdf::PdfLimits;
+use crate::scrape;
+use crate::store::{self, DocumentRow, PgPool};
+use crate::warc::{self, WarcReader, WarcWriter};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RawMeta {
+    pub content_hash: String,
+    /// First URL this body was fetched from.
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    pub http_status: u16,
+    /// As received; the body below is already content-decoded.
+    pub headers: Vec<(String, String)>,
+    /// The body hit its byte cap; only the prefix is archived.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+    pub meta: RawMeta,
+    pub body: Bytes,
+}
+
+#[derive(Clone)]
+pub enum Archive {
+    /// `{root}/{hash[..2]}/{hash}.json` plus `{hash}.body.gz`.
+    Disk(PathBuf),
+    Postgres(PgPool),
+}
+
+impl Archive {
+    /// `ARCHIVE=disk|postgres|off` (default off) with `ARCHIVE_DIR` for disk.
+    pub fn from_env(pool: &PgPool) -> Result<Option<Self>> {
+        match std::env::var("ARCHIVE").unwrap_or_default().to_ascii_lowercase().as_str() {
+            "" | "off" | "none" => Ok(None),
+            "postgres" | "pg" => Ok(Some(Archive::Postgres(pool.clone()))),
+            "disk" => {
+                let root = PathBuf::from(std::env::var("ARCHIVE_DIR").unwrap_or_else(|_| "./archive".into()));
+                fs::create_dir_all(&root).with_context(|| format!("create {}", root.display()))?;
+                Ok(Some(Archive::Disk(root)))
+            }
+            other => bail!("unknown ARCHIVE backend {other:?}; expected disk, postgres or off"),
+        }
+    }
+
+    /// Store a response unless its hash is already archived. Returns whether
+    /// it was new.
+    pub async fn put(&self, r: &RawResponse) -> Result<bool> {
+        match self {
+            Archive::Postgres(pool) => {
+                let body = r.body.clone();
+                let gz = tokio::task::spawn_blocking(move || gzip(&body)).await??;
+                store::put_raw(pool, &r.meta, &gz, r.body.len() as i64).await
+            }
+            Archive::Disk(root) => {
+                let (root, r) = (root.clone(), r.clone());
+                tokio::task::spawn_blocking(move || disk_put(&root, &r)).await?
+            }
+        }
+    }
+
+    pub async fn get(&self, content_hash: &str) -> Result<Option<RawResponse>> {
+        if !is_hash(content_hash) {
+            return Ok(None);
+        }
+        match self {
+            Archive::Postgres(pool) => {
+                let Some((meta, gz)) = store::get_raw(pool, content_hash).await? else { return Ok(None) };
+                let body = tokio::task::spawn_blocking(move || gunzip(&gz)).await??;
+                Ok(Some(RawResponse { meta, body: body.into() }))
+            }
+            Archive::Disk(root) => {
+                let (root, hash) = (root.clone(), content_hash.to_string());
+                tokio::task::spawn_blocking(move || disk_get(&root, &hash)).await?
+            }
+        }
+    }
+}
+
+/// Hashes become path components, so only accept what we generate.
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+    enc.write_all(data)?;
+    Ok(enc.finish()?)
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn disk_paths(root: &Path, hash: &str) -> (PathBuf, PathBuf) {
+    let dir = root.join(&hash[..2]);
+    (dir.join(format!("{hash}.json")), dir.join(format!("{hash}.body.gz")))
+}
+
+/// Body first, then metadata, each via rename: a `.json` only ever exists
+/// next to a complete body.
+fn disk_put(root: &Path, r: &RawResponse) -> Result<bool> {
+    let hash = &r.meta.content_hash;
+    if !is_hash(hash) {
+        bail!("not a content hash: {hash:?}");
+    }
+    let (meta_path, body_path) = disk_paths(root, hash);
+    if meta_path.exists() {
+        return Ok(false);
+    }
+    fs::create_dir_all(meta_path.parent().unwrap_or(root))?;
+    let tmp = body_path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, gzip(&r.body)?)?;
+    fs::rename(&tmp, &body_path)?;
+    let tmp = meta_path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec_pretty(&r.meta)?)?;
+    fs::rename(&tmp, &meta_path)?;
+    Ok(true)
+}
+
+fn disk_get(root: &Path, hash: &str) -> Result<Option<RawResponse>> {
+    let (meta_path, body_path) = disk_paths(root, hash);
+    let meta: RawMeta = match fs::read(&meta_path) {
+        Ok(b) => serde_json::from_slice(&b).with_context(|| format!("parse {}", meta_path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let body = gunzip(&fs::read(&body_path)?)?;
+    Ok(Some(RawResponse { meta, body: body.into() }))
+}
+
+/* ------------------------ WARC ------------------------ */
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ExportReport {
+    pub captures: usize,
+    pub responses: usize,
+    /// Repeat captures of an identical body, written as `revisit` records.
+    pub revisits: usize,
+    /// Captures whose body was never archived (e.g. from before archiving).
+    pub missing: usize,
+}
+
+/// Write every document version (optionally only URLs starting with
+/// `url_prefix`, fetched at or after `since`) whose body is archived.
+pub async fn export_warc(
+    pool: &PgPool,
+    archive: &Archive,
+    path: &Path,
+    url_prefix: Option<&str>,
+    since: Option<DateTime<Utc>>,
+) -> Result<ExportReport> {
+    let captures = store::list_captures(pool, url_prefix, since).await?;
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut w = WarcWriter::new(BufWriter::new(file));
+    w.warcinfo(&format!("worker-rust/{}", env!("CARGO_PKG_VERSION")))?;
+
+    let mut report = ExportReport { captures: captures.len(), ..Default::default() };
+    // hash -> (uri, date) of the response record holding that body
+    let mut written: HashMap<String, (String, DateTime<Utc>)> = HashMap::new();
+    for c in &captures {
+        if let Some((uri, date)) = written.get(&c.content_hash) {
+            let Some(raw) = archive.get(&c.content_hash).await? else { continue };
+            w.revisit(&raw.meta, &c.url, c.fetched_at, uri, *date)?;
+            report.revisits += 1;
+            continue;
+        }
+        match archive.get(&c.content_hash).await? {
+            Some(raw) => {
+                w.response(&raw, &c.url, c.fetched_at)?;
+                written.insert(c.content_hash.clone(), (c.url.clone(), c.fetched_at));
+                report.responses += 1;
+            }
+            None => report.missing += 1,
+        }
+    }
+    w.finish()?.flush()?;
+    Ok(report)
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ImportReport {
+    pub records: usize,
+    pub responses: usize,
+    /// Revisits resolved to a body from an earlier record of the file.
+    pub revisits: usize,
+    pub stored: usize,
+    /// Already archived under the same hash.
+    pub existing: usize,
+    /// Captures added as document versions, so export and reprocess see them.
+    pub versions: usize,
+    /// Captures that were archived but not indexed: non-2xx, or extraction failed.
+    pub unindexed: usize,
+    /// Other record types, and responses we couldn't parse.
+    pub skipped: usize,
+}
+
+/// Archive every HTTP `response` record of a `.warc` or `.warc.gz` file and
+/// record each 2xx capture (revisits included) as a document version, the
+/// same way a crawl would have stored it.
+pub async fn import_warc(pool: &PgPool, archive: &Archive, path: &Path, pdf_limits: PdfLimits) -> Result<ImportReport> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = WarcReader::new(BufReader::new(file))?;
+    let mut report = ImportReport::default();
+    // (target uri, date) of each response record -> its body's hash, for revisits
+    let mut seen: HashMap<(String, String), String> = HashMap::new();
+    while let Some(rec) = reader.next_record()? {
+        report.records += 1;
+        let is_http = rec.header("Content-Type").is_some_and(|ct| ct.starts_with("application/http"));
+        let uri = rec.header("WARC-Target-URI").unwrap_or_default().to_string();
+        let fetched_at = rec
+            .header("WARC-Date")
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map_or_else(Utc::now, |d| d.with_timezone(&Utc));
+
+        let raw = match rec.header("WARC-Type") {
+            Some("response") if is_http => {
+                let Some(msg) = warc::parse_http_response(&rec.block) else {
+                    report.skipped += 1;
+                    continue;
+                };
+                report.responses += 1;
+                let raw = RawResponse {
+                    meta: RawMeta {
+                        content_hash: format!("{:x}", Sha256::digest(&msg.body)),
+                        url: uri.clone(),
+                        fetched_at,
+                        http_status: msg.status,
+                        headers: warc::original_headers(msg.headers),
+                        truncated: rec.header("WARC-Truncated").is_some(),
+                    },
+                    body: msg.body.into(),
+                };
+                if archive.put(&raw).await? {
+                    report.stored += 1;
+                } else {
+                    report.existing += 1;
+                }
+                if let Some(date) = rec.header("WARC-Date") {
+                    seen.insert((uri.clone(), date.to_string()), raw.meta.content_hash.clone());
+                }
+                raw
+            }
+            Some("revisit") if is_http => {
+                let refers = (
+                    rec.header("WARC-Refers-To-Target-URI").unwrap_or_default().to_string(),
+                    rec.header("WARC-Refers-To-Date").unwrap_or_default().to_string(),
+                );
+                let hash = seen.get(&refers).cloned();
+                let original = match hash {
+                    Some(h) => archive.get(&h).await?,
+                    None => None,
+                };
+                let Some(mut raw) = original else {
+                    report.skipped += 1;
+                    continue;
+                };
+                report.revisits += 1;
+                raw.meta.url = uri.clone();
+                raw.meta.fetched_at = fetched_at;
+                raw
+            }
+            _ => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        match index_capture(pool, &uri, &raw, pdf_limits).await? {
+            Some(true) => report.versions += 1,
+            Some(false) => {}
+            None => report.unindexed += 1,
+        }
+    }
+    Ok(report)
+}
+
+/// Extract a capture and record it with `store::import_capture`. `None` if
+/// it isn't something a crawl would have stored; otherwise whether a new
+/// version was added.
+async fn index_capture(pool: &PgPool, uri: &str, raw: &RawResponse, pdf_limits: PdfLimits) -> Result<Option<bool>> {
+    let Ok(url) = Url::parse(uri) else { return Ok(None) };
+    if !(200..300).contains(&raw.meta.http_status) {
+        return Ok(None);
+    }
+    match scrape::extract_document(&url, raw, pdf_limits).await {
+        Ok(doc) => Ok(Some(store::import_capture(pool, &DocumentRow::from(&doc)).await?.is_some())),
+        Err(e) => {
+            warn!(error=%e, url=%uri, "imported capture not extracted");
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate;
+
+    fn raw(url: &str, status: u16, body: impl Into<Bytes>, fetched_at: DateTime<Utc>) -> RawResponse {
+        let body: Bytes = body.into();
+        RawResponse {
+            meta: RawMeta {
+                content_hash: format!("{:x}", Sha256::digest(&body)),
+                url: url.into(),
+                fetched_at,
+                http_status: status,
+                headers: vec![("Content-Type".into(), "text/html; charset=utf-8".into())],
+                truncated: false,
+            },
+            body,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[tokio::test]
+    async fn disk_archive_round_trips_and_dedups_by_hash() {
+        let root = temp_dir("archive");
+        let archive = Archive::Disk(root.clone());
+        let r = raw("https://example.org/a", 200, "<p>archived</p>", at("2024-03-01T10:00:00Z"));
+        let hash = r.meta.content_hash.clone();
+
+        assert!(archive.put(&r).await.unwrap());
+        let (meta_path, body_path) = disk_paths(&root, &hash);
+        assert!(meta_path.starts_with(root.join(&hash[..2])) && meta_path.exists() && body_path.exists());
+        assert_eq!(gunzip(&fs::read(&body_path).unwrap()).unwrap(), b"<p>archived</p>");
+
+        // the same body from another URL is already archived
+        let again = RawResponse { meta: RawMeta { url: "https://example.org/b".into(), ..r.meta.clone() }, ..r.clone() };
+        assert!(!archive.put(&again).await.unwrap());
+
+        let got = archive.get(&hash).await.unwrap().unwrap();
+        assert_eq!(got.body, r.body);
+        assert_eq!(got.meta.url, "https://example.org/a");
+        assert_eq!(got.meta.fetched_at, r.meta.fetched_at);
+        assert_eq!((got.meta.http_status, got.meta.truncated), (200, false));
+        assert_eq!(got.meta.headers, r.meta.headers);
+
+        assert!(archive.get(&"0".repeat(64)).await.unwrap().is_none());
+        // anything that isn't a hash never reaches the filesystem
+        assert!(archive.get("../../etc/passwd").await.unwrap().is_none());
+        let bad = RawResponse { meta: RawMeta { content_hash: "../x".into(), ..r.meta.clone() }, ..r };
+        assert!(archive.put(&bad).await.is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn postgres_archive_round_trips() {
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let archive = Archive::Postgres(pg.clone());
+        // a body no other run has archived
+        let body = format!("<p>{:016x}</p>", rand::random::<u64>());
+        let r = raw("https://archive.example/pg", 203, body, at("2024-03-01T10:00:00Z"));
+
+        assert!(archive.put(&r).await.unwrap());
+        assert!(!archive.put(&r).await.unwrap());
+        let got = archive.get(&r.meta.content_hash).await.unwrap().unwrap();
+        assert_eq!(got.body, r.body);
+        assert_eq!((got.meta.url.as_str(), got.meta.http_status), ("https://archive.example/pg", 203));
+        assert_eq!(got.meta.headers, r.meta.headers);
+        pg.get()
+            .await
+            .unwrap()
+            .execute("DELETE FROM public.raw_responses WHERE content_hash = $1", &[&r.meta.content_hash])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn import_archives_responses_and_indexes_captures_as_versions() {
+        const PAGE: &str = "https://archive-import.example/report";
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.ingested_documents WHERE url = $1", &[&PAGE]).await.unwrap();
+
+        let first = raw(PAGE, 200, "<html><head><title>Report</title></head><body><p>Emissions were 1.2 Mt.</p></body></html>", at("2024-01-01T00:00:00Z"));
+        let second = raw(PAGE, 200, "<html><head><title>Report</title></head><body><p>Emissions were 1.1 Mt.</p></body></html>", at("2024-03-01T00:00:00Z"));
+        let gone = raw(PAGE, 404, "<p>not found</p>", at("2024-04-01T00:00:00Z"));
+        let mut w = WarcWriter::new(Vec::new());
+        w.warcinfo("test").unwrap();
+        w.response(&first, PAGE, first.meta.fetched_at).unwrap();
+        // an unchanged recapture a month later
+        w.revisit(&first.meta, PAGE, at("2024-02-01T00:00:00Z"), PAGE, first.meta.fetched_at).unwrap();
+        w.response(&second, PAGE, second.meta.fetched_at).unwrap();
+        w.response(&gone, PAGE, gone.meta.fetched_at).unwrap();
+        let root = temp_dir("import");
+        let path = root.join("captures.warc");
+        fs::write(&path, w.finish().unwrap()).unwrap();
+        let archive = Archive::Disk(root.join("raw"));
+
+        let report = import_warc(&pg, &archive, &path, PdfLimits::default()).await.unwrap();
+        assert_eq!((report.records, report.responses, report.revisits, report.skipped), (5, 3, 1, 1));
+        assert_eq!((report.stored, report.existing), (3, 0));
+        // the 404 is archived but a crawl would not have stored it
+        assert_eq!((report.versions, report.unindexed), (3, 1));
+        assert!(archive.get(&gone.meta.content_hash).await.unwrap().is_some());
+
+        let versions: Vec<(i32, String, DateTime<Utc>, String)> = client
+            .query(
+                "SELECT v.version, v.content_hash, v.fetched_at, v.body_text FROM public.document_versions v \
+                 JOIN public.ingested_documents d ON d.id = v.document_id WHERE d.url = $1 ORDER BY v.version",
+                &[&PAGE],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get(0), r.get(1), r.get(2), r.get(3)))
+            .collect();
+        let want = [
+            (1, &first.meta.content_hash, "2024-01-01T00:00:00Z", "1.2 Mt"),
+            (2, &first.meta.content_hash, "2024-02-01T00:00:00Z", "1.2 Mt"),
+            (3, &second.meta.content_hash, "2024-03-01T00:00:00Z", "1.1 Mt"),
+        ];
+        assert_eq!(versions.len(), want.len());
+        for (got, (version, hash, fetched_at, text)) in versions.iter().zip(want) {
+            assert_eq!((got.0, &got.1, got.2), (version, hash, at(fetched_at)));
+            assert!(got.3.contains(text), "{}", got.3);
+        }
+
+        // importing the same file again adds nothing
+        let report = import_warc(&pg, &archive, &path, PdfLimits::default()).await.unwrap();
+        assert_eq!((report.stored, report.existing, report.versions), (0, 3, 0));
+
+        // and what was imported exports again, the recapture as a revisit
+        let out = root.join("export.warc");
+        let export = export_warc(&pg, &archive, &out, Some(PAGE), None).await.unwrap();
+        assert_eq!((export.captures, export.responses, export.revisits, export.missing), (3, 2, 1, 0));
+
+        client.execute("DELETE FROM public.ingested_documents WHERE url = $1", &[&PAGE]).await.unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,531 @@
+//! Per-host health: a circuit breaker that stops requests to a host after a
+//! run of timeouts, connection failures or 5xx/429 answers, and the running
+//! latency and error-rate figures kept in `domain_state`. A host's circuit
+//! opens after `failures` consecutive failures, stays open for `cooldown`,
+//! then lets one probe request through (half-open): success closes it,
+//! failure opens it for another cooldown.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::error::ScrapeError;
+
+/// Weight of the newest sample in the latency and error-rate averages.
+pub const EWMA_ALPHA: f64 = 0.1;
+/// A half-open probe that hasn't reported back by now is presumed lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open a host's circuit.
+    pub failures: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self { failures: 5, cooldown: Duration::from_secs(5 * 60) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Circuit {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Circuit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half_open",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "open" => Circuit::Open,
+            "half_open" => Circuit::HalfOpen,
+            _ => Circuit::Closed,
+        }
+    }
+}
+
+/// What `domain_state` holds for one host. The robots fields are filled in
+/// by whoever persists it, from the robots cache.
+#[derive(Debug, Clone)]
+pub struct DomainSnapshot {
+    pub host: String,
+    pub origin: String,
+    pub circuit: Circuit,
+    pub consecutive_failures: i32,
+    pub open_until: Option<DateTime<Utc>>,
+    pub requests: i64,
+    pub failures: i64,
+    pub avg_latency_ms: Option<f64>,
+    pub error_rate: f64,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_failure_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub robots_txt: Option<String>,
+    pub robots_fetched_at: Option<DateTime<Utc>>,
+    pub crawl_delay_ms: Option<i32>,
+    /// Requests since the host was last saved. A save adds these to the
+    /// stored lifetime figures, so restarts and other workers don't
+    /// overwrite them.
+    pub unsaved: Unsaved,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unsaved {
+    pub requests: i64,
+    pub failures: i64,
+    pub latency_ms_sum: f64,
+}
+
+struct HostHealth {
+    snap: DomainSnapshot,
+    probe_at: Option<Instant>,
+    dirty: bool,
+}
+
+impl HostHealth {
+    /// How long until a request may go out, if one may not now.
+    fn refusal(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self.snap.circuit {
+            Circuit::Closed => None,
+            Circuit::Open => self.snap.open_until.filter(|u| *u > now).map(|u| (u - now).to_std().unwrap_or_default()),
+            Circuit::HalfOpen => self.probe_at.filter(|at| at.elapsed() < PROBE_TIMEOUT).map(|at| PROBE_TIMEOUT - at.elapsed()),
+        }
+    }
+}
+
+pub struct DomainHealth {
+    cfg: BreakerConfig,
+    hosts: DashMap<String, HostHealth>,
+}
+
+/// Failures that say the host is unwell, as opposed to the page or our
+/// request being wrong.
+pub fn is_host_failure(e: &ScrapeError) -> bool {
+    matches!(e, ScrapeError::Dns(_) | ScrapeError::Tls(_) | ScrapeError::Connect(_) | ScrapeError::Timeout | ScrapeError::Network(_))
+}
+
+pub fn host_key(url: &Url) -> String {
+    url.host_str().unwrap_or("").trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl DomainHealth {
+    pub fn new(cfg: BreakerConfig) -> Self {
+        Self { cfg, hosts: DashMap::new() }
+    }
+
+    /// May a request go to `host` now? If not, how long until it may. Moves
+    /// an expired open circuit to half-open and claims its one probe.
+    pub fn allow(&self, host: &str) -> Result<(), Duration> {
+        let Some(mut h) = self.hosts.get_mut(host) else { return Ok(()) };
+        if let Some(wait) = h.refusal(Utc::now()) {
+            return Err(wait);
+        }
+        if h.snap.circuit != Circuit::Closed {
+            if h.snap.circuit == Circuit::Open {
+                h.snap.circuit = Circuit::HalfOpen;
+                h.dirty = true;
+            }
+            h.probe_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Like `allow`, but only looks: claims no probe and changes nothing.
+    pub fn check(&self, host: &str) -> Result<(), Duration> {
+        match self.hosts.get(host) {
+            Some(h) => h.refusal(Utc::now()).map_or(Ok(()), Err),
+            None => Ok(()),
+        }
+    }
+
+    /// Hosts that `allow` would currently refuse.
+    pub fn blocked_hosts(&self) -> Vec<String> {
+        let now = Utc::now();
+        self.hosts.iter().filter(|h| h.refusal(now).is_some()).map(|h| h.key().clone()).collect()
+    }
+
+    /// Feed back one request to `url`'s host: its time to response headers
+    /// and, if it counts against the host, why it failed.
+    pub fn record(&self, url: &Url, latency: Duration, failure: Option<String>) {
+        let host = host_key(url);
+        let mut h = self.hosts.entry(host.clone()).or_insert_with(|| HostHealth {
+            snap: blank(host, url.origin().ascii_serialization()),
+            probe_at: None,
+            dirty: true,
+        });
+        let now = Utc::now();
+        let s = &mut h.snap;
+        // the host's robots.txt is looked up by origin, so keep the one last used
+        s.origin = url.origin().ascii_serialization();
+        let ms = latency.as_secs_f64() * 1000.0;
+        s.requests += 1;
+        // a plain mean until there are enough samples for the average to mean something
+        let alpha = EWMA_ALPHA.max(1.0 / s.requests as f64);
+        s.avg_latency_ms = Some(s.avg_latency_ms.map_or(ms, |avg| avg + alpha * (ms - avg)));
+        s.error_rate += alpha * (if failure.is_some() { 1.0 } else { 0.0 } - s.error_rate);
+        s.unsaved.requests += 1;
+        s.unsaved.failures += i64::from(failure.is_some());
+        s.unsaved.latency_ms_sum += ms;
+        match failure {
+            None => {
+                s.consecutive_failures = 0;
+                s.circuit = Circuit::Closed;
+                s.open_until = None;
+                s.last_success_at = Some(now);
+            }
+            Some(err) => {
+                s.failures += 1;
+                s.consecutive_failures += 1;
+                s.last_failure_at = Some(now);
+                s.last_error = Some(err);
+                let trips = s.circuit == Circuit::HalfOpen || s.consecutive_failures >= self.cfg.failures as i32;
+                if trips {
+                    s.circuit = Circuit::Open;
+                    s.open_until = chrono::Duration::from_std(self.cfg.cooldown).ok().map(|d| now + d);
+                    tracing::warn!(host=%s.host, failures = s.consecutive_failures, "circuit opened");
+                }
+            }
+        }
+        h.probe_at = None;
+        h.dirty = true;
+    }
+
+    /// Close `host`'s circuit by hand. False if we know nothing about it.
+    pub fn reset(&self, host: &str) -> bool {
+        let Some(mut h) = self.hosts.get_mut(host) else { return false };
+        h.snap.circuit = Circuit::Closed;
+        h.snap.consecutive_failures = 0;
+        h.snap.open_until = None;
+        h.probe_at = None;
+        h.dirty = true;
+        true
+    }
+
+    /// Pick up state persisted by an earlier run, so a restart doesn't
+    /// forget which hosts are down.
+    pub fn restore(&self, rows: Vec<DomainSnapshot>) {
+        for snap in rows {
+            self.hosts.entry(snap.host.clone()).or_insert(HostHealth { snap, probe_at: None, dirty: false });
+        }
+    }
+
+    /// Hosts changed since the last call, clearing their changed flag and
+    /// unsaved counts.
+    pub fn take_dirty(&self) -> Vec<DomainSnapshot> {
+        let mut out = Vec::new();
+        for mut h in self.hosts.iter_mut() {
+            if h.dirty {
+                h.dirty = false;
+                out.push(h.snap.clone());
+                h.snap.unsaved = Unsaved::default();
+            }
+        }
+        out
+    }
+
+    /// Put back the unsaved counts of snapshots whose save failed, so the
+    /// next save includes them.
+    pub fn unsaved_again(&self, states: &[DomainSnapshot]) {
+        for st in states {
+            if let Some(mut h) = self.hosts.get_mut(&st.host) {
+                h.snap.unsaved.requests += st.unsaved.requests;
+                h.snap.unsaved.failures += st.unsaved.failures;
+                h.snap.unsaved.latency_ms_sum += st.unsaved.latency_ms_sum;
+                h.dirty = true;
+            }
+        }
+    }
+}
+
+fn blank(host: String, origin: String) -> DomainSnapshot {
+    DomainSnapshot {
+        host,
+        origin,
+        circuit: Circuit::Closed,
+        consecutive_failures: 0,
+        open_until: None,
+        requests: 0,
+        failures: 0,
+        avg_latency_ms: None,
+        error_rate: 0.0,
+        last_success_at: None,
+        last_failure_at: None,
+        last_error: None,
+        robots_txt: None,
+        robots_fetched_at: None,
+        crawl_delay_ms: None,
+        unsaved: Unsaved::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canon;
+    use crate::migrate;
+    use crate::store::{self, Enqueue, Lease};
+
+    const HOST: &str = "flaky.example";
+
+    fn url() -> Url {
+        Url::parse("https://Flaky.Example./page").unwrap()
+    }
+
+    fn health(failures: u32) -> DomainHealth {
+        DomainHealth::new(BreakerConfig { failures, cooldown: Duration::from_secs(60) })
+    }
+
+    fn fail(h: &DomainHealth) {
+        h.record(&url(), Duration::from_millis(100), Some("timeout".into()));
+    }
+
+    fn succeed(h: &DomainHealth) {
+        h.record(&url(), Duration::from_millis(100), None);
+    }
+
+    fn circuit(h: &DomainHealth) -> Circuit {
+        h.hosts.get(HOST).unwrap().snap.circuit
+    }
+
+    /// Skip the rest of the cooldown.
+    fn cool_down(h: &DomainHealth) {
+        h.hosts.get_mut(HOST).unwrap().snap.open_until = Some(Utc::now() - chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_then_probes_and_closes() {
+        let h = health(3);
+        assert_eq!(host_key(&url()), HOST);
+        assert!(h.allow(HOST).is_ok());
+
+        // a success in between starts the count again
+        fail(&h);
+        fail(&h);
+        succeed(&h);
+        fail(&h);
+        fail(&h);
+        assert_eq!(circuit(&h), Circuit::Closed);
+        fail(&h);
+        assert_eq!(circuit(&h), Circuit::Open);
+        let wait = h.allow(HOST).unwrap_err();
+        assert!(wait > Duration::from_secs(58) && wait <= Duration::from_secs(60), "{wait:?}");
+        assert_eq!(h.blocked_hosts(), vec![HOST.to_string()]);
+
+        cool_down(&h);
+        assert!(h.check(HOST).is_ok());
+        assert_eq!(circuit(&h), Circuit::Open, "check only looks");
+        assert!(h.allow(HOST).is_ok());
+        assert_eq!(circuit(&h), Circuit::HalfOpen);
+
+        succeed(&h);
+        assert_eq!(circuit(&h), Circuit::Closed);
+        assert!(h.allow(HOST).is_ok() && h.allow(HOST).is_ok());
+        assert!(h.blocked_hosts().is_empty());
+        let snap = &h.hosts.get(HOST).unwrap().snap;
+        assert_eq!((snap.consecutive_failures, snap.open_until), (0, None));
+        assert_eq!((snap.requests, snap.failures), (7, 5));
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let h = health(1);
+        fail(&h);
+        cool_down(&h);
+
+        assert!(h.allow(HOST).is_ok());
+        // the probe is out: everyone else waits for it
+        let wait = h.allow(HOST).unwrap_err();
+        assert!(wait > PROBE_TIMEOUT - Duration::from_secs(2) && wait <= PROBE_TIMEOUT, "{wait:?}");
+        assert!(h.check(HOST).is_err());
+        assert_eq!(h.blocked_hosts(), vec![HOST.to_string()]);
+
+        // a probe that never reports back is given up on
+        h.hosts.get_mut(HOST).unwrap().probe_at = Instant::now().checked_sub(PROBE_TIMEOUT + Duration::from_secs(1));
+        assert!(h.allow(HOST).is_ok());
+        assert!(h.allow(HOST).is_err());
+
+        // a failed probe opens the circuit again at once
+        fail(&h);
+        assert_eq!(circuit(&h), Circuit::Open);
+        assert!(h.allow(HOST).unwrap_err() > Duration::from_secs(58));
+    }
+
+    #[test]
+    fn reset_closes_by_hand() {
+        let h = health(1);
+        assert!(!h.reset(HOST));
+        fail(&h);
+        assert!(h.reset(HOST));
+        assert_eq!(circuit(&h), Circuit::Closed);
+        assert!(h.allow(HOST).is_ok());
+    }
+
+    #[test]
+    fn averages_start_as_a_plain_mean_then_weight_new_samples() {
+        let h = health(100);
+        let ms = |n| Duration::from_millis(n);
+        h.record(&url(), ms(100), None);
+        h.record(&url(), ms(300), Some("timeout".into()));
+        let snap = h.hosts.get(HOST).unwrap().snap.clone();
+        assert!((snap.avg_latency_ms.unwrap() - 200.0).abs() < 1e-9);
+        assert!((snap.error_rate - 0.5).abs() < 1e-9);
+
+        for _ in 0..20 {
+            h.record(&url(), ms(200), None);
+        }
+        let before = h.hosts.get(HOST).unwrap().snap.avg_latency_ms.unwrap();
+        h.record(&url(), ms(1200), None);
+        let after = h.hosts.get(HOST).unwrap().snap.avg_latency_ms.unwrap();
+        assert!((after - (before + EWMA_ALPHA * (1200.0 - before))).abs() < 1e-9);
+    }
+
+    #[test]
+    fn dirty_hosts_hand_over_their_unsaved_counts_once() {
+        let h = health(5);
+        succeed(&h);
+        fail(&h);
+        let taken = h.take_dirty();
+        assert_eq!(taken.len(), 1);
+        assert_eq!((taken[0].unsaved.requests, taken[0].unsaved.failures), (2, 1));
+        assert!((taken[0].unsaved.latency_ms_sum - 200.0).abs() < 1e-9);
+        assert!(h.take_dirty().is_empty());
+
+        // a failed save puts them back for the next one
+        h.unsaved_again(&taken);
+        succeed(&h);
+        let again = h.take_dirty();
+        assert_eq!((again[0].unsaved.requests, again[0].unsaved.failures), (3, 1));
+
+        // restored state doesn't clobber what this process has seen since
+        let mut stale = blank(HOST.into(), "https://flaky.example".into());
+        stale.circuit = Circuit::Open;
+        h.restore(vec![stale, DomainSnapshot { host: "other.example".into(), ..blank(String::new(), String::new()) }]);
+        assert_eq!(circuit(&h), Circuit::Closed);
+        assert!(h.hosts.contains_key("other.example"));
+        assert!(h.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn host_failures() {
+        let cases = [
+            (ScrapeError::Dns("nxdomain".into()), true),
+            (ScrapeError::Tls("handshake".into()), true),
+            (ScrapeError::Connect("refused".into()), true),
+            (ScrapeError::Timeout, true),
+            (ScrapeError::Network("reset".into()), true),
+            (ScrapeError::HttpStatus { status: 404, retry_after: None }, false),
+            (ScrapeError::RobotsBlocked, false),
+            (ScrapeError::TooLarge { limit: 1 }, false),
+            (ScrapeError::EgressBlocked("localhost".into()), false),
+            (ScrapeError::Decode("utf-8".into()), false),
+        ];
+        for (err, want) in cases {
+            assert_eq!(is_host_failure(&err), want, "{err:?}");
+        }
+    }
+
+    fn saved(host: &str, circuit: Circuit, requests: i64, failures: i64, latency_ms_sum: f64) -> DomainSnapshot {
+        DomainSnapshot {
+            circuit,
+            unsaved: Unsaved { requests, failures, latency_ms_sum },
+            ..blank(host.into(), format!("https://{host}"))
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn saves_merge_new_requests_into_the_stored_averages() {
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let (busy, quiet) = ("busy.breaker.example", "quiet.breaker.example");
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.domain_state WHERE host = ANY($1)", &[&vec![busy, quiet]]).await.unwrap();
+        let get = |host| {
+            let pg = pg.clone();
+            async move { store::get_domain_state(&pg, host).await.unwrap().unwrap() }
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+
+        // a busy host: one new request moves the averages by EWMA_ALPHA
+        store::save_domain_states(&pg, &[saved(busy, Circuit::Closed, 1000, 0, 100.0 * 1000.0)]).await.unwrap();
+        let d = get(busy).await;
+        assert_eq!((d.requests, d.failures), (1000, 0));
+        assert!(close(d.avg_latency_ms.unwrap(), 100.0) && close(d.error_rate, 0.0), "{d:?}");
+        store::save_domain_states(&pg, &[saved(busy, Circuit::Closed, 1, 1, 1100.0)]).await.unwrap();
+        let d = get(busy).await;
+        assert_eq!((d.requests, d.failures), (1001, 1));
+        assert!(close(d.avg_latency_ms.unwrap(), 200.0) && close(d.error_rate, EWMA_ALPHA), "{d:?}");
+
+        // a save without requests only moves the circuit
+        store::save_domain_states(&pg, &[saved(busy, Circuit::Open, 0, 0, 0.0)]).await.unwrap();
+        let d = get(busy).await;
+        assert_eq!((d.circuit.as_str(), d.requests), ("open", 1001));
+        assert!(close(d.avg_latency_ms.unwrap(), 200.0) && close(d.error_rate, EWMA_ALPHA), "{d:?}");
+
+        // a host with few requests gets a plain mean across saves
+        store::save_domain_states(&pg, &[saved(quiet, Circuit::Closed, 2, 0, 200.0)]).await.unwrap();
+        store::save_domain_states(&pg, &[saved(quiet, Circuit::Closed, 2, 2, 800.0)]).await.unwrap();
+        let d = get(quiet).await;
+        assert_eq!((d.requests, d.failures), (4, 2));
+        assert!(close(d.avg_latency_ms.unwrap(), 250.0) && close(d.error_rate, 0.5), "{d:?}");
+
+        client.execute("DELETE FROM public.domain_state WHERE host = ANY($1)", &[&vec![busy, quiet]]).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn dequeue_skips_blocked_hosts_by_their_url_host() {
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let blocked = [
+            "https://blocked.breaker.example/a",
+            "https://user:pw@BLOCKED.breaker.example:8443/b",
+            "http://[::1]:8080/c",
+        ];
+        let allowed = [
+            "https://sub.blocked.breaker.example/d",
+            "https://open.breaker.example/blocked.breaker.example",
+            "https://open.breaker.example/e?next=https://blocked.breaker.example/",
+        ];
+        let ours: Vec<String> = blocked.iter().chain(&allowed).map(|u| canon::canonical_str(u)).collect();
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.crawl_queue WHERE url = ANY($1)", &[&ours]).await.unwrap();
+        let items: Vec<Enqueue> = blocked
+            .iter()
+            .chain(&allowed)
+            .map(|url| Enqueue { url, priority: i32::MAX - 1, depth: 0, discovered_from: None, next_fetch_at: None })
+            .collect();
+        store::enqueue_many(&pg, &items).await.unwrap();
+
+        // a zero lease leaves any other test's rows we pick up free for it to take
+        let lease = Lease { owner: "breaker-test".into(), ttl: Duration::ZERO };
+        let skip = vec![host_key(&Url::parse(blocked[0]).unwrap()), host_key(&Url::parse(blocked[2]).unwrap())];
+        assert_eq!(skip, ["blocked.breaker.example", "[::1]"]);
+        let leased = |items: Vec<store::QueueItem>| {
+            let mut urls: Vec<String> = items.into_iter().map(|i| i.url).filter(|u| ours.contains(u)).collect();
+            urls.sort();
+            urls
+        };
+        let mut want: Vec<String> = allowed.iter().map(|u| canon::canonical_str(u)).collect();
+        want.sort();
+        assert_eq!(leased(store::dequeue_due(&pg, 20, &lease, &skip).await.unwrap()), want);
+
+        let mut all = ours.clone();
+        all.sort();
+        assert_eq!(leased(store::dequeue_due(&pg, 20, &lease, &[]).await.unwrap()), all);
+
+        client.execute("DELETE FROM public.crawl_queue WHERE url = ANY($1)", &[&ours]).await.unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! URL canonicalization so one page maps to one `crawl_queue` /
+//! `ingested_documents` row regardless of case, ports, fragments, tracking
+//! parameters or trailing slashes.
+//!
+//! Stored URLs are not rewritten when these rules change (or when rows
+//! predate them): a page stored under an old form gets a second row under
+//! its canonical form once it is rediscovered, and both are recrawled until
+//! the stale one is deleted. Folding them automatically would mean merging
+//! `document_versions` histories, so that is left to an operator.
+
+use std::sync::OnceLock;
+use url::Url;
+
+/// Query parameters dropped by default. A trailing `*` matches by prefix.
+pub const DEFAULT_STRIP_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "mc_cid", "mc_eid",
+    "_ga", "_gl", "igshid", "ref_src", "cmpid", "ocid", "smid", "sr_share",
+];
+
+static STRIP_PARAMS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Add `extra` to the default tracking-parameter denylist. Call once at
+/// startup before any URL is canonicalized; the list is fixed on first use.
+pub fn init(extra: impl IntoIterator<Item = String>) {
+    let params = merged(extra);
+    if STRIP_PARAMS.set(params).is_err() {
+        panic!("canon::init called after the strip list was already fixed");
+    }
+}
+
+fn merged(extra: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut params: Vec<String> = DEFAULT_STRIP_PARAMS.iter().map(|s| s.to_string()).collect();
+    for p in extra {
+        let p = p.to_ascii_lowercase();
+        if !params.contains(&p) {
+            params.push(p);
+        }
+    }
+    params
+}
+
+fn strip_params() -> &'static [String] {
+    STRIP_PARAMS.get_or_init(|| merged([]))
+}
+
+fn is_tracking(key: &str) -> bool {
+    is_listed(strip_params(), key)
+}
+
+fn is_listed(params: &[String], key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    params.iter().any(|p| match p.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == *p,
+    })
+}
+
+/// Canonical form of `url`. Host lowercasing, IDN->punycode and default-port
+/// removal come from `Url` parsing itself; on top of that we drop the
+/// fragment and tracking params, sort the query, and collapse duplicate and
+/// trailing slashes.
+pub fn canonicalize(url: &Url) -> Url {
+    let mut u = url.clone();
+    u.set_fragment(None);
+
+    if let Some(host) = u.host_str() {
+        if host.ends_with('.') {
+            let trimmed = host.trim_end_matches('.').to_string();
+            let _ = u.set_host(Some(&trimmed));
+        }
+    }
+
+    let mut path = String::with_capacity(u.path().len());
+    for c in u.path().chars() {
+        if !(c == '/' && path.ends_with('/')) {
+            path.push(c);
+        }
+    }
+    while path.len() > 1 && path.ends_with('/') {
+        path.pop();
+    }
+    u.set_path(&path);
+
+    let mut pairs: Vec<(String, String)> = u
+        .query_pairs()
+        .filter(|(k, _)| !is_tracking(k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        u.set_query(None);
+    } else {
+        pairs.sort();
+        u.query_pairs_mut().clear().extend_pairs(&pairs);
+    }
+    u
+}
+
+/// String-in, string-out variant for DB keys; unparsable input passes through.
+pub fn canonical_str(raw: &str) -> String {
+    match Url::parse(raw.trim()) {
+        Ok(u) => canonicalize(&u).into(),
+        Err(_) => raw.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_host_port_path_and_fragment() {
+        assert_eq!(canonical_str("HTTPS://Example.ORG.:443//a//b/#top"), "https://example.org/a/b");
+        assert_eq!(canonical_str("http://example.org:8080/"), "http://example.org:8080/");
+        assert_eq!(canonical_str("https://bücher.example/"), "https://xn--bcher-kva.example/");
+    }
+
+    #[test]
+    fn drops_tracking_params_and_sorts_the_rest() {
+        assert_eq!(
+            canonical_str("https://example.org/p?utm_source=x&b=2&fbclid=y&a=1&UTM_Medium=z"),
+            "https://example.org/p?a=1&b=2"
+        );
+        assert_eq!(canonical_str("https://example.org/p?gclid=1"), "https://example.org/p");
+    }
+
+    #[test]
+    fn configured_params_extend_the_defaults() {
+        let params = merged(["Session_ID".to_string(), "ref_*".to_string(), "fbclid".to_string()]);
+        assert_eq!(params.len(), DEFAULT_STRIP_PARAMS.len() + 2);
+        for key in ["session_id", "REF_campaign", "utm_source", "fbclid", "gclid"] {
+            assert!(is_listed(&params, key), "{key}");
+        }
+        assert!(!is_listed(&params, "id"));
+    }
+
+    #[test]
+    #[should_panic(expected = "already fixed")]
+    fn init_after_first_use_panics() {
+        canonical_str("https://example.org/?utm_source=x");
+        init(["session_id".to_string()]);
+    }
+
+    #[test]
+    fn unparsable_input_passes_through() {
+        assert_eq!(canonical_str("not a url"), "not a url");
+    }
+}
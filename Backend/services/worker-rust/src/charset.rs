@@ -0,0 +1,106 @@
+//! Character-encoding detection for fetched HTML. Precedence follows the
+//! WHATWG sniffing order: BOM, HTTP `charset`, `<meta>` prescan, statistics.
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use url::Url;
+
+/// How far into the document we look for a `<meta charset>`.
+const META_PRESCAN_BYTES: usize = 4096;
+
+/// Decode `body` and report which encoding was used.
+pub fn decode_html(body: &[u8], content_type: &str, url: &Url) -> (String, &'static Encoding) {
+    let enc = Encoding::for_bom(body)
+        .map(|(e, _)| e)
+        .or_else(|| from_content_type(content_type))
+        .or_else(|| from_meta(body))
+        .unwrap_or_else(|| sniff(body, url));
+    // decode() strips a BOM and replaces malformed sequences
+    let (text, used, _had_errors) = enc.decode(body);
+    (text.into_owned(), used)
+}
+
+fn from_content_type(ct: &str) -> Option<&'static Encoding> {
+    ct.split(';')
+        .skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, v)| Encoding::for_label(v.trim().trim_matches(|c| c == '"' || c == '\'').as_bytes()))
+}
+
+/// Catches both `<meta charset="x">` and
+/// `<meta http-equiv="Content-Type" content="text/html; charset=x">`.
+fn from_meta(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(META_PRESCAN_BYTES)];
+    let head: String = head.iter().map(|&b| (b as char).to_ascii_lowercase()).collect();
+
+    let mut rest = head.as_str();
+    while let Some(i) = rest.find("<meta") {
+        let tag = &rest[i..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        if let Some(j) = tag.find("charset") {
+            let label: String = tag[j + "charset".len()..]
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '=' || c == '"' || c == '\'')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+                .collect();
+            if let Some(enc) = Encoding::for_label(label.as_bytes()) {
+                // a document that parsed as ASCII to get here can't really be UTF-16
+                return Some(if enc == UTF_16LE || enc == UTF_16BE { UTF_8 } else { enc });
+            }
+        }
+        rest = &rest[i + "<meta".len()..];
+    }
+    None
+}
+
+fn sniff(body: &[u8], url: &Url) -> &'static Encoding {
+    let mut det = EncodingDetector::new();
+    det.feed(body, true);
+    let tld = url
+        .host_str()
+        .and_then(|h| h.rsplit('.').next())
+        .map(|t| t.as_bytes());
+    det.guess(tld, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url() -> Url {
+        Url::parse("https://example.org/").unwrap()
+    }
+
+    #[test]
+    fn bom_beats_header_and_meta() {
+        let body = b"\xEF\xBB\xBF<meta charset=\"iso-8859-1\">caf\xC3\xA9";
+        let (text, enc) = decode_html(body, "text/html; charset=windows-1252", &url());
+        assert_eq!(enc, UTF_8);
+        assert!(text.ends_with("café"));
+    }
+
+    #[test]
+    fn header_charset_beats_meta() {
+        let (text, enc) = decode_html(b"<meta charset=utf-8>caf\xE9", "text/html; charset=\"ISO-8859-1\"", &url());
+        assert_eq!(enc.name(), "windows-1252");
+        assert!(text.ends_with("café"));
+    }
+
+    #[test]
+    fn meta_charset_and_http_equiv() {
+        let (_, enc) = decode_html(b"<html><head><META CHARSET='shift_jis'>", "text/html", &url());
+        assert_eq!(enc.name(), "Shift_JIS");
+        let body = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=koi8-r\">";
+        assert_eq!(decode_html(body, "text/html", &url()).1.name(), "KOI8-R");
+        // a page we could read as ASCII isn't UTF-16
+        assert_eq!(decode_html(b"<meta charset=utf-16le>", "text/html", &url()).1, UTF_8);
+    }
+
+    #[test]
+    fn sniffs_when_nothing_is_declared() {
+        let (text, enc) = decode_html("Grüße aus Köln".as_bytes(), "text/html", &url());
+        assert_eq!(enc, UTF_8);
+        assert_eq!(text, "Grüße aus Köln");
+    }
+}
@@ -1,30 +1,765 @@
-// Minimal stub; expand as needed. Not used by main currently.
-#![allow(dead_code)]
-use anyhow::Result;
-use crate::store::PgPool;
-use crate::scrape::ScrapeClient;
+//! The crawl loop: lease due queue items, scrape and store them, expand the
+//! frontier, and reschedule. Runs as a supervised background task and is
+//! also driven one batch at a time by `/crawl/tick`, optionally in the
+//! background with the result kept for polling.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::error::ScrapeError;
+use crate::feeds;
+use crate::links::{self, Scope};
+use crate::retry::{RetryDecision, RetryPolicy};
+use crate::robots::agent_token;
+use crate::scrape::{scrape_one, ScrapeClient, ScrapeOutcome};
+use crate::store::{self, DocumentRow, Enqueue, Lease, PgPool, QueueItem};
+use crate::types::Document;
+use crate::watch::Alerter;
 
 pub struct CrawlConfig {
     pub user_agent: String,
     pub per_domain_concurrency: usize,
     pub per_domain_delay_ms: u64,
+    /// Items processed concurrently by the background loop.
     pub max_tasks: usize,
     pub robots_ttl_secs: u64,
+    /// How long the loop sleeps when nothing is due.
+    pub idle_poll_secs: u64,
+    /// How often the loop polls feeds that are due; 0 turns this off.
+    pub feed_poll_secs: u64,
+    /// Start in `Running` rather than `Paused`. `/crawl/tick` is refused
+    /// unless running, like the loop.
+    pub autostart: bool,
+}
+
+impl CrawlConfig {
+    pub fn scrape_client(&self) -> ScrapeClient {
+        ScrapeClient::new(&self.user_agent, self.per_domain_concurrency, Duration::from_millis(self.per_domain_delay_ms))
+            .with_robots_ttl(Duration::from_secs(self.robots_ttl_secs))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrawlState {
+    Running,
+    /// No new items are leased; in-flight ones finish.
+    Paused,
+    /// Like paused, and turns into `Drained` once nothing is in flight.
+    Draining,
+    Drained,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Fetched,
+    NotModified,
+    Failed,
+    DeadLettered,
+    /// Not fetched because the host's circuit is open; put back without
+    /// counting a try.
+    Deferred,
+}
+
+/// What happened to one queue item.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ItemReport {
+    pub url: String,
+    pub outcome: Outcome,
+    pub http_status: Option<i32>,
+    pub error_code: Option<&'static str>,
+    pub discovered: usize,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct TickSummary {
+    pub processed_ok: usize,
+    pub not_modified: usize,
+    pub failed: usize,
+    pub dead_lettered: usize,
+    pub deferred: usize,
+    pub discovered: usize,
+    pub elapsed_ms: u64,
+    pub items: Vec<ItemReport>,
+}
+
+impl TickSummary {
+    fn add(&mut self, r: ItemReport) {
+        match r.outcome {
+            Outcome::Fetched => self.processed_ok += 1,
+            Outcome::NotModified => self.not_modified += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::DeadLettered => {
+                self.failed += 1;
+                self.dead_lettered += 1;
+            }
+            Outcome::Deferred => self.deferred += 1,
+        }
+        self.discovered += r.discovered;
+        self.items.push(r);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TickState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// A `/crawl/tick?async=true` batch, kept for polling after it finishes.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TickRecord {
+    pub id: String,
+    pub state: TickState,
+    pub batch: i64,
+    pub concurrency: usize,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub summary: Option<TickSummary>,
+    pub error: Option<String>,
+}
+
+/// How often the background loop writes changed host health to `domain_state`.
+const DOMAIN_FLUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Feeds claimed per round of the background loop's feed polling.
+const FEED_POLL_BATCH: i64 = 20;
+
+/// Finished async ticks remembered for status lookups; oldest go first.
+const MAX_TICK_RECORDS: usize = 100;
+
+#[derive(Default)]
+struct Counters {
+    fetched: AtomicU64,
+    not_modified: AtomicU64,
+    failed: AtomicU64,
+    dead_lettered: AtomicU64,
+    deferred: AtomicU64,
+    discovered: AtomicU64,
 }
 
 pub struct Crawler {
     sc: ScrapeClient,
     cfg: CrawlConfig,
     pool: PgPool,
+    scope: Scope,
+    retry: RetryPolicy,
+    lease: Lease,
+    alerter: Alerter,
+    state: watch::Sender<CrawlState>,
+    in_flight: AtomicUsize,
+    counters: Counters,
+    ticks: DashMap<String, TickRecord>,
+    // robots.txt fetch time last written to domain_state, per host
+    robots_saved: DashMap<String, DateTime<Utc>>,
+    // queue ids leased by this process and not yet released
+    held: DashSet<i64>,
+}
+
+/// Keeps an item's lease renewed by `keep_leases` until dropped, which
+/// happens when its task finishes, panics or is aborted.
+struct Held {
+    crawler: Arc<Crawler>,
+    id: i64,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.crawler.held.remove(&self.id);
+    }
+}
+
+/// One unit of `in_flight`, for a loop item, a tick item or a tick that is
+/// still leasing. Released on drop, so an aborted or panicked task can't
+/// leave a drain waiting.
+struct Busy {
+    crawler: Arc<Crawler>,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let c = &self.crawler;
+        if c.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            c.state.send_if_modified(|s| {
+                let drained = *s == CrawlState::Draining;
+                if drained {
+                    *s = CrawlState::Drained;
+                }
+                drained
+            });
+        }
+    }
 }
 
 impl Crawler {
-    pub fn new(sc: ScrapeClient, cfg: CrawlConfig, pool: PgPool) -> Self {
-        Self { sc, cfg, pool }
+    pub fn new(
+        sc: ScrapeClient,
+        cfg: CrawlConfig,
+        pool: PgPool,
+        scope: Scope,
+        retry: RetryPolicy,
+        lease: Lease,
+        alerter: Alerter,
+    ) -> Self {
+        let initial = if cfg.autostart { CrawlState::Running } else { CrawlState::Paused };
+        Self {
+            sc,
+            cfg,
+            pool,
+            scope,
+            retry,
+            lease,
+            alerter,
+            state: watch::Sender::new(initial),
+            in_flight: AtomicUsize::new(0),
+            counters: Counters::default(),
+            ticks: DashMap::new(),
+            robots_saved: DashMap::new(),
+            held: DashSet::new(),
+        }
+    }
+
+    /* ------------------------ control ------------------------ */
+
+    pub fn state(&self) -> CrawlState {
+        *self.state.borrow()
+    }
+
+    pub fn pause(&self) {
+        self.state.send_replace(CrawlState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.state.send_replace(CrawlState::Running);
     }
 
-    pub async fn run_once(&self) -> Result<usize> {
-        // Placeholder: dequeue URLs and scrape them, then upsert docs.
-        Ok(0)
+    pub fn drain(&self) {
+        self.state.send_if_modified(|s| match s {
+            CrawlState::Drained => false,
+            _ => {
+                // read under the state lock so a finishing item can't slip between
+                *s = if self.in_flight.load(Ordering::SeqCst) == 0 { CrawlState::Drained } else { CrawlState::Draining };
+                true
+            }
+        });
+    }
+
+    /// Wait until a drain completes, up to `timeout`. True if it did.
+    pub async fn wait_drained(&self, timeout: Duration) -> bool {
+        let mut rx = self.state.subscribe();
+        tokio::time::timeout(timeout, rx.wait_for(|s| *s == CrawlState::Drained)).await.is_ok_and(|r| r.is_ok())
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let c = &self.counters;
+        serde_json::json!({
+            "state": self.state(),
+            "worker_id": self.lease.owner,
+            "in_flight": self.in_flight.load(Ordering::SeqCst),
+            "max_tasks": self.cfg.max_tasks,
+            "totals": {
+                "processed_ok": c.fetched.load(Ordering::Relaxed),
+                "not_modified": c.not_modified.load(Ordering::Relaxed),
+                "failed": c.failed.load(Ordering::Relaxed),
+                "dead_lettered": c.dead_lettered.load(Ordering::Relaxed),
+                "deferred": c.deferred.load(Ordering::Relaxed),
+                "discovered": c.discovered.load(Ordering::Relaxed),
+            }
+        })
+    }
+
+    /* ------------------------ batch ------------------------ */
+
+    /// Run one batch as its own task, so it finishes even if the caller
+    /// goes away. Like the loop, ticks only lease while the crawler is
+    /// running and count towards `in_flight`, so a drain waits for them.
+    pub fn start_tick(self: &Arc<Self>, batch: i64, concurrency: usize) -> Result<JoinHandle<Result<TickSummary>>, CrawlState> {
+        // taken before the state check so a drain starting now sees us
+        let busy = self.busy();
+        let state = self.state();
+        if state != CrawlState::Running {
+            return Err(state);
+        }
+        let this = self.clone();
+        Ok(tokio::spawn(async move { this.run_once(batch, concurrency, busy).await }))
+    }
+
+    /// Lease up to `batch` due items and process them with at most
+    /// `concurrency` in flight. Per-host limits still apply underneath.
+    async fn run_once(self: &Arc<Self>, batch: i64, concurrency: usize, leasing: Busy) -> Result<TickSummary> {
+        let started = Instant::now();
+        let items = store::dequeue_due(&self.pool, batch, &self.lease, &self.sc.health().blocked_hosts()).await?;
+        let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for it in items {
+            let (this, permits) = (self.clone(), permits.clone());
+            let (held, busy) = (self.hold(it.id), self.busy());
+            tasks.spawn(async move {
+                let (_held, _busy) = (held, busy);
+                let _permit = permits.acquire_owned().await;
+                this.process(&it).await
+            });
+        }
+        drop(leasing);
+
+        let mut summary = TickSummary::default();
+        while let Some(res) = tasks.join_next().await {
+            match res {
+                Ok(report) => summary.add(report),
+                // its lease runs out and the item comes round again
+                Err(e) => error!(error=?e, "tick task panicked"),
+            }
+        }
+        self.flush_domains().await;
+        summary.elapsed_ms = elapsed_ms(started);
+        Ok(summary)
+    }
+
+    /// Start a tick and return its id for polling.
+    pub fn spawn_tick(self: &Arc<Self>, batch: i64, concurrency: usize) -> Result<String, CrawlState> {
+        let handle = self.start_tick(batch, concurrency)?;
+        let id = format!("{:016x}", rand::random::<u64>());
+        self.ticks.insert(id.clone(), TickRecord {
+            id: id.clone(),
+            state: TickState::Running,
+            batch,
+            concurrency,
+            started_at: Utc::now(),
+            finished_at: None,
+            summary: None,
+            error: None,
+        });
+        self.prune_ticks();
+
+        let (this, tick_id) = (self.clone(), id.clone());
+        tokio::spawn(async move {
+            let res = handle.await.unwrap_or_else(|e| Err(anyhow!("tick panicked: {e}")));
+            if let Some(mut rec) = this.ticks.get_mut(&tick_id) {
+                rec.finished_at = Some(Utc::now());
+                match res {
+                    Ok(summary) => {
+                        rec.state = TickState::Done;
+                        rec.summary = Some(summary);
+                    }
+                    Err(e) => {
+                        error!(error=?e, tick=%tick_id, "async tick failed");
+                        rec.state = TickState::Failed;
+                        rec.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        Ok(id)
     }
-}
\ No newline at end of file
+
+    pub fn tick(&self, id: &str) -> Option<TickRecord> {
+        self.ticks.get(id).map(|r| r.clone())
+    }
+
+    fn prune_ticks(&self) {
+        let excess = self.ticks.len().saturating_sub(MAX_TICK_RECORDS);
+        if excess == 0 {
+            return;
+        }
+        let mut finished: Vec<(DateTime<Utc>, String)> = self
+            .ticks
+            .iter()
+            .filter_map(|r| r.finished_at.map(|f| (f, r.id.clone())))
+            .collect();
+        finished.sort();
+        for (_, id) in finished.into_iter().take(excess) {
+            self.ticks.remove(&id);
+        }
+    }
+
+    fn busy(self: &Arc<Self>) -> Busy {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Busy { crawler: self.clone() }
+    }
+
+    /* ------------------------ leases ------------------------ */
+
+    fn hold(self: &Arc<Self>, id: i64) -> Held {
+        self.held.insert(id);
+        Held { crawler: self.clone(), id }
+    }
+
+    /// Renew the leases on every held item a few times per lease period, so
+    /// a long wait for a host slot can't outlast the lease.
+    pub async fn keep_leases(self: Arc<Self>) {
+        let mut every = tokio::time::interval((self.lease.ttl / 3).max(Duration::from_secs(1)));
+        every.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            every.tick().await;
+            let ids: Vec<i64> = self.held.iter().map(|id| *id).collect();
+            if ids.is_empty() {
+                continue;
+            }
+            if let Err(e) = store::renew_leases(&self.pool, &ids, &self.lease).await {
+                warn!(error=?e, held = ids.len(), "lease renewal failed");
+            }
+        }
+    }
+
+    /* ------------------------ host health ------------------------ */
+
+    /// Seed the circuit breaker with hosts that were unwell at last save.
+    pub async fn restore_domains(&self) -> Result<usize> {
+        let rows = store::load_domain_health(&self.pool).await?;
+        let n = rows.len();
+        self.sc.health().restore(rows);
+        Ok(n)
+    }
+
+    /// Write hosts whose health changed to `domain_state`, with their
+    /// robots.txt when it was refetched since the last write. Failures are
+    /// logged and the hosts kept for the next flush.
+    pub async fn flush_domains(&self) {
+        let mut states = self.sc.health().take_dirty();
+        if states.is_empty() {
+            return;
+        }
+        let agent = agent_token(&self.sc.user_agent);
+        for s in &mut states {
+            let Some((robots, fetched_at)) = self.sc.cached_robots(&s.origin) else { continue };
+            if self.robots_saved.get(&s.host).is_some_and(|at| *at == fetched_at) {
+                continue;
+            }
+            s.robots_txt = Some(robots.text().to_string());
+            s.robots_fetched_at = Some(fetched_at);
+            s.crawl_delay_ms = robots.crawl_delay(&agent).map(|d| d.as_millis().min(i32::MAX as u128) as i32);
+        }
+        match store::save_domain_states(&self.pool, &states).await {
+            Ok(()) => {
+                for s in &states {
+                    if let Some(at) = s.robots_fetched_at {
+                        self.robots_saved.insert(s.host.clone(), at);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error=?e, hosts = states.len(), "saving domain state failed");
+                self.sc.health().unsaved_again(&states);
+            }
+        }
+    }
+
+    /* ------------------------ background loop ------------------------ */
+
+    /// Restart the loop if it panics; returns only if it exits normally.
+    pub async fn supervise(self: Arc<Self>) {
+        let mut restarts = 0u32;
+        loop {
+            match tokio::spawn(self.clone().run()).await {
+                Ok(()) => return,
+                Err(e) => {
+                    restarts += 1;
+                    let wait = Duration::from_secs(2u64.pow(restarts.min(6)));
+                    error!(error=?e, restarts, "crawl loop died; restarting in {:?}", wait);
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    /// Keep up to `max_tasks` items in flight while running; a finished
+    /// item frees a slot for the next due one.
+    pub async fn run(self: Arc<Self>) {
+        let mut rx = self.state.subscribe();
+        let mut tasks: JoinSet<()> = JoinSet::new();
+        let idle = Duration::from_secs(self.cfg.idle_poll_secs.max(1));
+        let mut flushed = Instant::now();
+        let feed_every = Duration::from_secs(self.cfg.feed_poll_secs);
+        let mut feeds_polled: Option<Instant> = None;
+        let mut feed_round: Option<tokio::task::JoinHandle<()>> = None;
+        info!(max_tasks = self.cfg.max_tasks, state = ?self.state(), "crawl loop started");
+
+        loop {
+            let state = *rx.borrow_and_update();
+            if state == CrawlState::Draining && self.in_flight.load(Ordering::SeqCst) == 0 {
+                self.state.send_replace(CrawlState::Drained);
+                info!("crawl loop drained");
+                continue;
+            }
+
+            let room = self.cfg.max_tasks.max(1).saturating_sub(tasks.len());
+            let mut leased = 0;
+            if state == CrawlState::Running && room > 0 {
+                match store::dequeue_due(&self.pool, room as i64, &self.lease, &self.sc.health().blocked_hosts()).await {
+                    Ok(items) => {
+                        leased = items.len();
+                        for it in items {
+                            let (this, held, busy) = (self.clone(), self.hold(it.id), self.busy());
+                            tasks.spawn(async move {
+                                let (_held, _busy) = (held, busy);
+                                this.process(&it).await;
+                            });
+                        }
+                    }
+                    Err(e) => warn!(error=?e, "crawl loop dequeue failed"),
+                }
+            }
+
+            // feed polling runs beside the item tasks so a slow publisher
+            // doesn't hold up refilling slots; one round at a time
+            let feeds_due = !feed_every.is_zero() && feeds_polled.is_none_or(|t| t.elapsed() >= feed_every);
+            if state == CrawlState::Running && feeds_due && feed_round.as_ref().is_none_or(|h| h.is_finished()) {
+                feeds_polled = Some(Instant::now());
+                let this = self.clone();
+                feed_round = Some(tokio::spawn(async move { this.poll_feeds().await }));
+            }
+
+            if flushed.elapsed() >= DOMAIN_FLUSH_INTERVAL {
+                self.flush_domains().await;
+                flushed = Instant::now();
+            }
+
+            // more work is likely if we filled every slot we asked for
+            let mut nap = if leased > 0 && leased == room { Duration::ZERO } else { idle };
+            if !feed_every.is_zero() {
+                nap = nap.min(feed_every);
+            }
+            tokio::select! {
+                Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                    if let Err(e) = res {
+                        error!(error=?e, "crawl task panicked");
+                    }
+                }
+                _ = rx.changed() => {}
+                _ = tokio::time::sleep(nap) => {}
+            }
+        }
+    }
+
+    async fn poll_feeds(&self) {
+        match feeds::poll_due(&self.pool, &self.sc, &self.scope, FEED_POLL_BATCH).await {
+            Ok(report) if !report.is_empty() => {
+                let enqueued: usize = report.iter().map(|r| r.enqueued).sum();
+                info!(polled = report.len(), enqueued, "polled due feeds");
+            }
+            Ok(_) => {}
+            Err(e) => warn!(error=?e, "feed claim failed"),
+        }
+    }
+
+    /* ------------------------ one item ------------------------ */
+
+    pub async fn process(&self, it: &QueueItem) -> ItemReport {
+        let started = Instant::now();
+        let pg = &self.pool;
+        let prior = store::get_validators(pg, &it.url).await.unwrap_or_else(|e| {
+            error!(error=?e, url=%it.url, "validator lookup failed");
+            None
+        });
+        let mut report = ItemReport {
+            url: it.url.clone(),
+            outcome: Outcome::Fetched,
+            http_status: None,
+            error_code: None,
+            discovered: 0,
+            elapsed_ms: 0,
+        };
+        match scrape_one(&self.sc, &it.url, prior.as_ref()).await {
+            Ok(ScrapeOutcome::Fetched(doc)) => {
+                report.http_status = Some(doc.http_status);
+                let row = DocumentRow::from(&*doc);
+                match store::upsert_document(pg, &row).await {
+                    Err(e) => {
+                        error!(error=?e, url=%doc.url, "upsert failed");
+                        let e = ScrapeError::Internal(format!("store: {e}"));
+                        report.outcome = self.record_failure(it, &e).await;
+                        report.error_code = Some(e.code());
+                    }
+                    Ok(version) => {
+                        if let Err(e) = store::reschedule_success(pg, it.id, &self.lease.owner, doc.http_status).await {
+                            error!(error=?e, url=%it.url, "reschedule failed");
+                        }
+                        if let Some(v) = version {
+                            self.alerter.on_new_version(pg, &doc.url, v, &doc.body_text).await;
+                        }
+                        report.discovered = expand_frontier(pg, &self.scope, &doc, it.priority, it.depth).await;
+                    }
+                }
+            }
+            Ok(ScrapeOutcome::NotModified { url, fetched_at }) => {
+                report.outcome = Outcome::NotModified;
+                report.http_status = Some(304);
+                if let Err(e) = store::touch_document(pg, &url, fetched_at).await {
+                    error!(error=?e, url=%url, "touch failed");
+                }
+                if let Err(e) = store::reschedule_success(pg, it.id, &self.lease.owner, 304).await {
+                    error!(error=?e, url=%it.url, "reschedule failed");
+                }
+            }
+            Err(ScrapeError::CircuitOpen { host, retry_in }) => {
+                info!(url=%it.url, host=%host, "circuit open; deferring");
+                report.outcome = Outcome::Deferred;
+                report.error_code = Some("circuit_open");
+                if let Err(e) = store::defer(pg, it.id, &self.lease.owner, retry_in).await {
+                    error!(error=?e, url=%it.url, "defer failed");
+                }
+            }
+            Err(e) => {
+                error!(error=?e, url=%it.url, "scrape failed");
+                report.outcome = self.record_failure(it, &e).await;
+                report.http_status = e.http_status().map(i32::from);
+                report.error_code = Some(e.code());
+            }
+        }
+        report.elapsed_ms = elapsed_ms(started);
+        self.count(&report);
+        report
+    }
+
+    /// Reschedule a failed item per the retry policy, or dead-letter it.
+    async fn record_failure(&self, it: &QueueItem, e: &ScrapeError) -> Outcome {
+        let (pg, owner) = (&self.pool, self.lease.owner.as_str());
+        let status = e.http_status().map(i32::from);
+        let (res, outcome) = match self.retry.decide(e.class(), e.retry_after(), it.tries) {
+            RetryDecision::RetryIn(wait) => (
+                store::reschedule_failure(pg, it.id, owner, status, e.code(), &e.to_string(), wait).await,
+                Outcome::Failed,
+            ),
+            RetryDecision::DeadLetter => (
+                store::dead_letter(pg, it.id, owner, status, e.code(), &e.to_string()).await,
+                Outcome::DeadLettered,
+            ),
+        };
+        if let Err(db) = res {
+            error!(error=?db, url=%it.url, "recording failure failed");
+        }
+        outcome
+    }
+
+    fn count(&self, r: &ItemReport) {
+        let c = &self.counters;
+        let n = match r.outcome {
+            Outcome::Fetched => &c.fetched,
+            Outcome::NotModified => &c.not_modified,
+            Outcome::Failed => &c.failed,
+            Outcome::DeadLettered => {
+                c.failed.fetch_add(1, Ordering::Relaxed);
+                &c.dead_lettered
+            }
+            Outcome::Deferred => &c.deferred,
+        };
+        n.fetch_add(1, Ordering::Relaxed);
+        c.discovered.fetch_add(r.discovered as u64, Ordering::Relaxed);
+    }
+}
+
+fn elapsed_ms(since: Instant) -> u64 {
+    since.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Enqueue the in-scope links of a freshly fetched page. Failures are logged,
+/// not propagated: the page itself was stored fine.
+pub async fn expand_frontier(pg: &PgPool, scope: &Scope, doc: &Document, priority: i32, depth: i32) -> usize {
+    let Ok(parent) = Url::parse(&doc.url) else { return 0 };
+    let found = links::in_scope(scope, &parent, priority, depth, &doc.links);
+    let items: Vec<Enqueue> = found
+        .iter()
+        .map(|d| Enqueue {
+            url: &d.url,
+            priority: d.priority,
+            depth: d.depth,
+            discovered_from: Some(&doc.url),
+            next_fetch_at: None,
+        })
+        .collect();
+    match store::enqueue_many(pg, &items).await {
+        Ok(n) => n,
+        Err(e) => {
+            error!(error=?e, url=%doc.url, "frontier enqueue failed");
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch;
+    use crate::migrate;
+
+    const SEED: &str = "https://tick.example/";
+
+    /// A crawler on replayed fixtures. The pool connects on first use.
+    async fn crawler(pg_url: &str) -> Arc<Crawler> {
+        let pool = store::init_pool(pg_url).await.unwrap();
+        let cfg = CrawlConfig {
+            user_agent: "TestBot/1.0".into(),
+            per_domain_concurrency: 2,
+            per_domain_delay_ms: 0,
+            max_tasks: 2,
+            robots_ttl_secs: 60,
+            idle_poll_secs: 1,
+            feed_poll_secs: 0,
+            autostart: true,
+        };
+        let sc = cfg.scrape_client().with_fetcher(fetch::test_fixtures());
+        let alerter = Alerter::new(sc.http.clone(), None);
+        let lease = Lease { owner: "crawl-test".into(), ttl: Duration::from_secs(60) };
+        Arc::new(Crawler::new(sc, cfg, pool, Scope::default(), RetryPolicy::default(), lease, alerter))
+    }
+
+    async fn enqueue_seed(c: &Crawler) {
+        let seed = Enqueue { url: SEED, priority: i32::MAX, depth: 0, discovered_from: None, next_fetch_at: Some(Utc::now()) };
+        store::enqueue_many(&c.pool, &[seed]).await.unwrap();
+    }
+
+    /// Needs a database at `TEST_PG_URL`, which should be a throwaway one:
+    /// a tick leases whatever is due. Run with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn tick_fetches_stores_and_revalidates() {
+        let c = crawler(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await;
+        migrate::apply_pending(&c.pool).await.unwrap();
+        let client = c.pool.get().await.unwrap();
+        client.execute("DELETE FROM public.crawl_queue WHERE url LIKE 'https://tick.example/%'", &[]).await.unwrap();
+        client.execute("DELETE FROM public.ingested_documents WHERE url LIKE 'https://tick.example/%'", &[]).await.unwrap();
+
+        enqueue_seed(&c).await;
+        let first = c.start_tick(1, 1).unwrap().await.unwrap().unwrap();
+        assert_eq!((first.processed_ok, first.failed), (1, 0), "{:?}", first.items);
+        assert_eq!(first.items[0].url, SEED);
+        // the same-site link only
+        assert_eq!(first.discovered, 1);
+        let stored = store::get_validators(&c.pool, SEED).await.unwrap().unwrap();
+        assert_eq!(stored.etag.as_deref(), Some("\"t1\""));
+        let next = client
+            .query_one("SELECT depth, discovered_from FROM public.crawl_queue WHERE url = 'https://tick.example/next'", &[])
+            .await
+            .unwrap();
+        assert_eq!((next.get::<_, i32>(0), next.get::<_, Option<String>>(1).as_deref()), (1, Some(SEED)));
+
+        // due again: the stored ETag goes out and the replay answers 304
+        enqueue_seed(&c).await;
+        let second = c.start_tick(1, 1).unwrap().await.unwrap().unwrap();
+        assert_eq!(second.not_modified, 1, "{:?}", second.items);
+        assert_eq!(second.items[0].http_status, Some(304));
+        assert_eq!(c.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn tick_is_refused_unless_running() {
+        let c = crawler("host=/nonexistent dbname=unused").await;
+        c.pause();
+        assert_eq!(c.start_tick(1, 1).err(), Some(CrawlState::Paused));
+        c.drain();
+        assert_eq!(c.state(), CrawlState::Drained);
+        assert_eq!(c.in_flight.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,342 @@
+//! Egress policy: which hosts and addresses the worker may connect to.
+//! URLs come from API callers, feeds and other people's pages, so by default
+//! nothing that resolves to loopback, private, link-local, CGNAT or other
+//! non-public ranges is fetched. The check happens in our own DNS resolver,
+//! so the address vetted is the address connected to (no DNS rebinding),
+//! and again on every redirect hop for IP-literal targets, which skip DNS.
+
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use url::{Host, Url};
+
+/// Ranges that are never publicly routable, blocked unless allow-listed.
+const BLOCKED_RANGES: &[&str] = &[
+    "0.0.0.0/8",          // "this" network
+    "10.0.0.0/8",         // RFC 1918
+    "100.64.0.0/10",      // CGNAT
+    "127.0.0.0/8",        // loopback
+    "169.254.0.0/16",     // link-local, cloud metadata
+    "172.16.0.0/12",      // RFC 1918
+    "192.0.0.0/24",       // IETF protocol assignments
+    "192.0.2.0/24",       // TEST-NET-1
+    "192.168.0.0/16",     // RFC 1918
+    "198.18.0.0/15",      // benchmarking
+    "198.51.100.0/24",    // TEST-NET-2
+    "203.0.113.0/24",     // TEST-NET-3
+    "224.0.0.0/4",        // multicast
+    "240.0.0.0/4",        // reserved, broadcast
+    "64:ff9b:1::/48",     // local-use NAT64, any embedding
+    "100::/64",           // discard-only
+    "::/128",             // unspecified
+    "::1/128",            // loopback
+    "fc00::/7",           // unique local
+    "fe80::/10",          // link-local
+    "ff00::/8",           // multicast
+    "2001:db8::/32",      // documentation
+];
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{target}: {reason}")]
+pub struct EgressBlocked {
+    pub target: String,
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+    blocked: Vec<IpNet>,
+    /// Exempt from the built-in blocks (e.g. an intranet you do mean to crawl).
+    pub allow_cidrs: Vec<IpNet>,
+    /// Always refused, checked before anything else.
+    pub deny_cidrs: Vec<IpNet>,
+    /// Hosts (and their subdomains) allowed to resolve to blocked ranges.
+    pub allow_hosts: Vec<String>,
+    /// Hosts (and their subdomains) never contacted.
+    pub deny_hosts: Vec<String>,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            blocked: BLOCKED_RANGES.iter().map(|r| r.parse().expect("valid built-in range")).collect(),
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+        }
+    }
+}
+
+impl EgressPolicy {
+    /// `EGRESS_ALLOW_CIDRS`, `EGRESS_DENY_CIDRS`, `EGRESS_ALLOW_HOSTS` and
+    /// `EGRESS_DENY_HOSTS`, each comma-separated.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let list = |key: &str| -> Vec<String> {
+            std::env::var(key)
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        let cidrs = |key: &str| -> anyhow::Result<Vec<IpNet>> {
+            list(key)
+                .iter()
+                .map(|s| {
+                    // a bare address means just that address
+                    s.parse::<IpNet>()
+                        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                        .map_err(|_| anyhow::anyhow!("{key}: bad CIDR {s:?}"))
+                })
+                .collect()
+        };
+        let hosts = |key: &str| list(key).into_iter().map(|h| normalize_host(&h)).collect();
+        Ok(Self {
+            allow_cidrs: cidrs("EGRESS_ALLOW_CIDRS")?,
+            deny_cidrs: cidrs("EGRESS_DENY_CIDRS")?,
+            allow_hosts: hosts("EGRESS_ALLOW_HOSTS"),
+            deny_hosts: hosts("EGRESS_DENY_HOSTS"),
+            ..Self::default()
+        })
+    }
+
+    fn host_listed(list: &[String], host: &str) -> bool {
+        list.iter().any(|h| host == h || host.strip_suffix(h.as_str()).is_some_and(|p| p.ends_with('.')))
+    }
+
+    pub fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), EgressBlocked> {
+        let target = if host == ip.to_string() { host.to_string() } else { format!("{host} ({ip})") };
+        let blocked = |reason| Err(EgressBlocked { target, reason });
+        // judge v4-in-v6 forms by the IPv4 address they carry
+        let ip = embedded_v4(ip).unwrap_or(ip);
+        if self.deny_cidrs.iter().any(|n| n.contains(&ip)) {
+            return blocked("address is denied by EGRESS_DENY_CIDRS");
+        }
+        if self.allow_cidrs.iter().any(|n| n.contains(&ip)) || Self::host_listed(&self.allow_hosts, host) {
+            return Ok(());
+        }
+        if self.blocked.iter().any(|n| n.contains(&ip)) {
+            return blocked("address is not publicly routable");
+        }
+        Ok(())
+    }
+
+    pub fn check_host(&self, host: &str) -> Result<(), EgressBlocked> {
+        if Self::host_listed(&self.deny_hosts, host) {
+            return Err(EgressBlocked { target: host.to_string(), reason: "host is denied by EGRESS_DENY_HOSTS" });
+        }
+        Ok(())
+    }
+
+    /// Everything that can be decided without DNS: scheme, host lists and
+    /// IP-literal hosts. Hostnames are checked once resolved.
+    pub fn check_url(&self, url: &Url) -> Result<(), EgressBlocked> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(EgressBlocked { target: url.to_string(), reason: "only http and https are allowed" });
+        }
+        match url.host() {
+            Some(Host::Domain(d)) => self.check_host(&normalize_host(d)),
+            Some(Host::Ipv4(ip)) => self.check_ip(&ip.to_string(), ip.into()),
+            Some(Host::Ipv6(ip)) => self.check_ip(&ip.to_string(), ip.into()),
+            None => Err(EgressBlocked { target: url.to_string(), reason: "url has no host" }),
+        }
+    }
+
+    /// `check_url` plus resolving the host, so callers can refuse a URL up
+    /// front with a clear error rather than a failed connection.
+    pub async fn vet(&self, url: &Url) -> Result<(), EgressBlocked> {
+        self.check_url(url)?;
+        if let Some(Host::Domain(d)) = url.host() {
+            let host = normalize_host(d);
+            // a name that doesn't resolve is a DNS failure, reported by the fetch itself
+            let resolved = tokio::net::lookup_host((host.as_str(), 0)).await;
+            if let Ok(addrs) = resolved {
+                self.filter_addrs(&host, addrs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The addresses `host` may be reached on; an error if none are left.
+    fn filter_addrs(&self, host: &str, addrs: impl Iterator<Item = SocketAddr>) -> Result<Vec<SocketAddr>, EgressBlocked> {
+        let mut refused = None;
+        let allowed: Vec<SocketAddr> = addrs
+            .filter(|a| match self.check_ip(host, a.ip()) {
+                Ok(()) => true,
+                Err(e) => {
+                    refused = Some(e);
+                    false
+                }
+            })
+            .collect();
+        match refused {
+            Some(e) if allowed.is_empty() => Err(e),
+            _ => Ok(allowed),
+        }
+    }
+}
+
+fn normalize_host(h: &str) -> String {
+    h.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// IPv4-mapped (`::ffff:a.b.c.d`), IPv4-compatible (`::a.b.c.d`), NAT64
+/// (`64:ff9b::a.b.c.d`), 6to4 (`2002:aabb:ccdd::/48`) and Teredo
+/// (`2001:0::/32`, client address inverted in the last 32 bits) addresses.
+fn embedded_v4(ip: IpAddr) -> Option<IpAddr> {
+    let IpAddr::V6(v6) = ip else { return None };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return Some(v4.into());
+    }
+    let s = v6.segments();
+    // `::` and `::1` stay themselves, so allow/deny lists naming them still match
+    let compatible = s[..6] == [0; 6] && !matches!(s[6..], [0, 0 | 1]);
+    if compatible || s[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = v6.octets();
+        return Some(IpAddr::from([a, b, c, d]));
+    }
+    if s[..2] == [0x2001, 0] {
+        let [.., a, b, c, d] = v6.octets();
+        return Some(IpAddr::from([!a, !b, !c, !d]));
+    }
+    if s[0] == 0x2002 {
+        let [_, _, a, b, c, d, ..] = v6.octets();
+        return Some(IpAddr::from([a, b, c, d]));
+    }
+    None
+}
+
+/// reqwest resolver that drops addresses the policy refuses, so connections
+/// only ever go to vetted IPs. Fails the lookup if none are left.
+pub struct PolicyResolver {
+    policy: Arc<EgressPolicy>,
+}
+
+impl PolicyResolver {
+    pub fn new(policy: Arc<EgressPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = normalize_host(name.as_str());
+            policy.check_host(&host)?;
+            let allowed = policy.filter_addrs(&host, tokio::net::lookup_host((host.as_str(), 0)).await?)?;
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Follow up to `max` redirects, refusing hops the policy rejects outright.
+/// Hops to hostnames are vetted again by `PolicyResolver` on connect.
+pub fn redirect_policy(policy: Arc<EgressPolicy>, max: usize) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > max {
+            return attempt.error("too many redirects");
+        }
+        match policy.check_url(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: &EgressPolicy, ip: &str) -> Result<(), EgressBlocked> {
+        policy.check_ip("example.org", ip.parse().unwrap())
+    }
+
+    #[test]
+    fn blocks_non_public_ranges() {
+        let p = EgressPolicy::default();
+        for ip in ["127.0.0.1", "10.1.2.3", "169.254.169.254", "100.64.0.1", "::1", "fd00::1", "fe80::1"] {
+            assert!(check(&p, ip).is_err(), "{ip} should be blocked");
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(check(&p, ip).is_ok(), "{ip} should be allowed");
+        }
+    }
+
+    #[test]
+    fn judges_embedded_v4_by_the_v4_address() {
+        let p = EgressPolicy::default();
+        let cases = [
+            // mapped
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:93.184.216.34", true),
+            // IPv4-compatible
+            ("::127.0.0.1", false),
+            ("::a00:1", false),
+            ("::93.184.216.34", true),
+            // NAT64
+            ("64:ff9b::7f00:1", false),
+            ("64:ff9b::5db8:d822", true),
+            // 6to4
+            ("2002:7f00:1::", false),
+            ("2002:a00:1:5::1", false),
+            ("2002:5db8:d822::1", true),
+            // Teredo, judged by the inverted client address: 127.0.0.1, 10.0.0.1, 93.184.216.34
+            ("2001:0:4136:e378:8000:63bf:80ff:fffe", false),
+            ("2001:0:4136:e378:8000:63bf:f5ff:fffe", false),
+            ("2001:0:4136:e378:8000:63bf:a247:27dd", true),
+        ];
+        for (ip, allowed) in cases {
+            assert_eq!(check(&p, ip).is_ok(), allowed, "{ip}");
+        }
+        // documentation space right next to Teredo is still blocked on its own
+        assert!(check(&p, "2001:db8::5db8:d822").is_err());
+        // unspecified and loopback are matched as IPv6, not as 0.0.0.0 and 0.0.0.1
+        let loopback = EgressPolicy { allow_cidrs: vec!["::1/128".parse().unwrap()], ..EgressPolicy::default() };
+        assert!(check(&loopback, "::1").is_ok());
+        assert!(check(&loopback, "::").is_err());
+    }
+
+    #[test]
+    fn blocks_the_discard_prefix() {
+        let p = EgressPolicy::default();
+        for ip in ["100::1", "100::ffff:ffff:ffff:ffff", "100::5db8:d822"] {
+            assert!(check(&p, ip).is_err(), "{ip} should be blocked");
+        }
+    }
+
+    #[test]
+    fn blocks_local_use_nat64() {
+        let p = EgressPolicy::default();
+        assert!(check(&p, "64:ff9b:1::5db8:d822").is_err());
+        assert!(check(&p, "64:ff9b:1:ffff::1").is_err());
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let p = EgressPolicy {
+            allow_cidrs: vec!["10.0.0.0/24".parse().unwrap()],
+            deny_cidrs: vec!["93.184.216.0/24".parse().unwrap()],
+            allow_hosts: vec!["intranet.test".into()],
+            ..EgressPolicy::default()
+        };
+        assert!(check(&p, "10.0.0.5").is_ok());
+        assert!(check(&p, "10.0.1.5").is_err());
+        assert!(check(&p, "93.184.216.34").is_err());
+        assert!(p.check_ip("wiki.intranet.test", "192.168.1.1".parse().unwrap()).is_ok());
+        assert!(p.check_ip("notintranet.test", "192.168.1.1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn check_url_covers_scheme_and_ip_literals() {
+        let p = EgressPolicy::default();
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(p.check_url(&url("ftp://example.org/")).is_err());
+        assert!(p.check_url(&url("http://127.0.0.1:8080/")).is_err());
+        assert!(p.check_url(&url("http://[::ffff:10.0.0.1]/")).is_err());
+        assert!(p.check_url(&url("https://example.org/")).is_ok());
+    }
+}
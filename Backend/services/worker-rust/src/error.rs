@@ -0,0 +1,296 @@
+//! Typed scrape failures. Every variant maps to a stable `code()` stored in
+//! `crawl_queue.error_code` and a `class()` that drives rescheduling.
+
+use std::error::Error as _;
+use std::time::Duration;
+
+use crate::egress::EgressBlocked;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScrapeError {
+    #[error("bad url: {0}")]
+    BadUrl(String),
+    #[error("unsupported scheme: {0}")]
+    UnsupportedScheme(String),
+    #[error("blocked by robots.txt")]
+    RobotsBlocked,
+    #[error("robots.txt unreachable")]
+    RobotsUnavailable,
+    #[error("blocked by egress policy: {0}")]
+    EgressBlocked(String),
+    #[error("dns lookup failed: {0}")]
+    Dns(String),
+    #[error("tls error: {0}")]
+    Tls(String),
+    #[error("connection failed: {0}")]
+    Connect(String),
+    #[error("request timed out")]
+    Timeout,
+    #[error("redirect error: {0}")]
+    Redirect(String),
+    #[error("http status {status}")]
+    HttpStatus { status: u16, retry_after: Option<Duration> },
+    #[error("unsupported content-type: {0}")]
+    UnsupportedContentType(String),
+    #[error("body too large (limit {limit} bytes)")]
+    TooLarge { limit: usize },
+    #[error("decode error: {0}")]
+    Decode(String),
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("circuit open for {host}")]
+    CircuitOpen { host: String, retry_in: Duration },
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Retrying won't help until the page or our config changes.
+    Permanent,
+    /// Likely to succeed on a later attempt.
+    Transient,
+    /// The server asked us to slow down.
+    RateLimited,
+}
+
+impl ScrapeError {
+    pub fn class(&self) -> ErrorClass {
+        use ScrapeError::*;
+        match self {
+            BadUrl(_) | UnsupportedScheme(_) | RobotsBlocked | EgressBlocked(_) | UnsupportedContentType(_) | TooLarge { .. }
+            | Decode(_) | Redirect(_) => ErrorClass::Permanent,
+            RobotsUnavailable | Dns(_) | Tls(_) | Connect(_) | Timeout | Network(_) | CircuitOpen { .. } | Internal(_) => {
+                ErrorClass::Transient
+            }
+            HttpStatus { status, retry_after } => match status {
+                429 => ErrorClass::RateLimited,
+                503 if retry_after.is_some() => ErrorClass::RateLimited,
+                408 | 425 | 500..=599 => ErrorClass::Transient,
+                _ => ErrorClass::Permanent,
+            },
+        }
+    }
+
+    /// Stable identifier for dashboards; never reword an existing one.
+    pub fn code(&self) -> &'static str {
+        use ScrapeError::*;
+        match self {
+            BadUrl(_) => "bad_url",
+            UnsupportedScheme(_) => "unsupported_scheme",
+            RobotsBlocked => "robots_blocked",
+            RobotsUnavailable => "robots_unavailable",
+            EgressBlocked(_) => "egress_blocked",
+            Dns(_) => "dns",
+            Tls(_) => "tls",
+            Connect(_) => "connect",
+            Timeout => "timeout",
+            Redirect(_) => "redirect",
+            HttpStatus { status, .. } => match status {
+                404 | 410 => "http_not_found",
+                401 | 403 => "http_forbidden",
+                429 => "http_rate_limited",
+                400..=499 => "http_4xx",
+                _ => "http_5xx",
+            },
+            UnsupportedContentType(_) => "unsupported_content_type",
+            TooLarge { .. } => "too_large",
+            Decode(_) => "decode",
+            Network(_) => "network",
+            CircuitOpen { .. } => "circuit_open",
+            Internal(_) => "internal",
+        }
+    }
+
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            ScrapeError::HttpStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ScrapeError::HttpStatus { retry_after, .. } => *retry_after,
+            ScrapeError::CircuitOpen { retry_in, .. } => Some(*retry_in),
+            _ => None,
+        }
+    }
+}
+
+impl From<EgressBlocked> for ScrapeError {
+    fn from(e: EgressBlocked) -> Self {
+        ScrapeError::EgressBlocked(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for ScrapeError {
+    fn from(e: reqwest::Error) -> Self {
+        // refused by our resolver or redirect policy, wherever reqwest wrapped it
+        let mut cur = e.source();
+        while let Some(s) = cur {
+            if let Some(blocked) = s.downcast_ref::<EgressBlocked>() {
+                return blocked.clone().into();
+            }
+            cur = s.source();
+        }
+        if e.is_timeout() {
+            return ScrapeError::Timeout;
+        }
+        if e.is_redirect() {
+            return ScrapeError::Redirect(e.to_string());
+        }
+        if e.is_decode() || e.is_body() {
+            return ScrapeError::Network(e.to_string());
+        }
+        if e.is_connect() {
+            // reqwest doesn't type these; hyper-util and rustls errors are in the source chain
+            let chain = source_chain(&e);
+            let lower = chain.to_lowercase();
+            if lower.contains("dns error") || lower.contains("failed to lookup address") {
+                return ScrapeError::Dns(chain);
+            }
+            if lower.contains("certificate") || lower.contains("tls") || lower.contains("handshake") {
+                return ScrapeError::Tls(chain);
+            }
+            return ScrapeError::Connect(chain);
+        }
+        ScrapeError::Network(source_chain(&e))
+    }
+}
+
+fn source_chain(e: &reqwest::Error) -> String {
+    let mut out = e.to_string();
+    let mut cur = e.source();
+    while let Some(s) = cur {
+        out.push_str(": ");
+        out.push_str(&s.to_string());
+        cur = s.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::egress::{redirect_policy, EgressPolicy, PolicyResolver};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn codes_and_classes_are_stable() {
+        let status = |status, retry_after| ScrapeError::HttpStatus { status, retry_after };
+        let cases = [
+            (ScrapeError::BadUrl("x".into()), "bad_url", ErrorClass::Permanent),
+            (ScrapeError::UnsupportedScheme("ftp".into()), "unsupported_scheme", ErrorClass::Permanent),
+            (ScrapeError::RobotsBlocked, "robots_blocked", ErrorClass::Permanent),
+            (ScrapeError::RobotsUnavailable, "robots_unavailable", ErrorClass::Transient),
+            (ScrapeError::EgressBlocked("10.0.0.1".into()), "egress_blocked", ErrorClass::Permanent),
+            (ScrapeError::Dns("x".into()), "dns", ErrorClass::Transient),
+            (ScrapeError::Tls("x".into()), "tls", ErrorClass::Transient),
+            (ScrapeError::Connect("x".into()), "connect", ErrorClass::Transient),
+            (ScrapeError::Timeout, "timeout", ErrorClass::Transient),
+            (ScrapeError::Redirect("x".into()), "redirect", ErrorClass::Permanent),
+            (ScrapeError::UnsupportedContentType("image/png".into()), "unsupported_content_type", ErrorClass::Permanent),
+            (ScrapeError::TooLarge { limit: 1 }, "too_large", ErrorClass::Permanent),
+            (ScrapeError::Decode("x".into()), "decode", ErrorClass::Permanent),
+            (ScrapeError::Network("x".into()), "network", ErrorClass::Transient),
+            (ScrapeError::CircuitOpen { host: "a.example".into(), retry_in: Duration::ZERO }, "circuit_open", ErrorClass::Transient),
+            (ScrapeError::Internal("x".into()), "internal", ErrorClass::Transient),
+            (status(400, None), "http_4xx", ErrorClass::Permanent),
+            (status(401, None), "http_forbidden", ErrorClass::Permanent),
+            (status(403, None), "http_forbidden", ErrorClass::Permanent),
+            (status(404, None), "http_not_found", ErrorClass::Permanent),
+            (status(408, None), "http_4xx", ErrorClass::Transient),
+            (status(410, None), "http_not_found", ErrorClass::Permanent),
+            (status(425, None), "http_4xx", ErrorClass::Transient),
+            (status(429, None), "http_rate_limited", ErrorClass::RateLimited),
+            (status(500, None), "http_5xx", ErrorClass::Transient),
+            (status(503, None), "http_5xx", ErrorClass::Transient),
+            (status(503, Some(Duration::from_secs(30))), "http_5xx", ErrorClass::RateLimited),
+            (status(599, None), "http_5xx", ErrorClass::Transient),
+        ];
+        for (err, code, class) in cases {
+            assert_eq!((err.code(), err.class()), (code, class), "{err:?}");
+        }
+    }
+
+    /// Answers every connection on a loopback port with `response`, then hangs up.
+    async fn serve(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = sock.read(&mut buf).await;
+                let _ = sock.write_all(response).await;
+            }
+        });
+        addr
+    }
+
+    async fn get(client: &reqwest::Client, url: &str) -> ScrapeError {
+        let res = client.get(url).send().await;
+        let err = match res {
+            Ok(r) => r.bytes().await.expect_err("body should fail"),
+            Err(e) => e,
+        };
+        err.into()
+    }
+
+    #[tokio::test]
+    async fn reqwest_errors_map_to_typed_variants() {
+        let plain = reqwest::Client::builder().no_proxy().timeout(Duration::from_millis(300)).build().unwrap();
+
+        // nothing listens on a port we just released
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let err = get(&plain, &format!("http://{closed}/")).await;
+        assert!(matches!(err, ScrapeError::Connect(_)), "{err:?}");
+
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _held = silent.accept().await;
+            std::future::pending::<()>().await;
+        });
+        let err = get(&plain, &format!("http://{addr}/")).await;
+        assert!(matches!(err, ScrapeError::Timeout), "{err:?}");
+        assert_eq!(err.class(), ErrorClass::Transient);
+
+        // a body cut short by the peer is a transient network error
+        let addr = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\nshort").await;
+        let err = get(&plain, &format!("http://{addr}/")).await;
+        assert!(matches!(err, ScrapeError::Network(_)), "{err:?}");
+        assert_eq!(err.class(), ErrorClass::Transient);
+
+        // .invalid never resolves (RFC 6761)
+        let err = get(&plain, "http://no-such-host.invalid/").await;
+        assert!(matches!(err, ScrapeError::Dns(_)), "{err:?}");
+
+        let looping = reqwest::Client::builder().no_proxy().redirect(reqwest::redirect::Policy::limited(2)).build().unwrap();
+        let addr = serve(b"HTTP/1.1 302 Found\r\nLocation: /again\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        let err = get(&looping, &format!("http://{addr}/")).await;
+        assert!(matches!(err, ScrapeError::Redirect(_)), "{err:?}");
+        assert_eq!(err.class(), ErrorClass::Permanent);
+    }
+
+    #[tokio::test]
+    async fn egress_refusals_are_found_in_the_source_chain() {
+        let policy = Arc::new(EgressPolicy::default());
+
+        // from the resolver
+        let client = reqwest::Client::builder().no_proxy().dns_resolver(Arc::new(PolicyResolver::new(policy.clone()))).build().unwrap();
+        let err = get(&client, "http://localhost/").await;
+        assert!(matches!(err, ScrapeError::EgressBlocked(_)), "{err:?}");
+
+        // from the redirect policy
+        let client = reqwest::Client::builder().no_proxy().redirect(redirect_policy(policy, 8)).build().unwrap();
+        let addr = serve(b"HTTP/1.1 301 Moved\r\nLocation: http://169.254.169.254/latest\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        let err = get(&client, &format!("http://{addr}/")).await;
+        assert!(matches!(err, ScrapeError::EgressBlocked(_)), "{err:?}");
+        assert_eq!((err.code(), err.class()), ("egress_blocked", ErrorClass::Permanent));
+    }
+}
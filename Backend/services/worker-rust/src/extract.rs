@@ -0,0 +1,408 @@
+//! Main-content extraction for HTML pages (readability-style). Paragraph-ish
+//! blocks push score up to their ancestors, candidates are damped by link
+//! density, and the best container plus its strong siblings is kept.
+
+use ego_tree::{NodeId, NodeRef};
+use scraper::{node::Element, ElementRef, Html, Node};
+use std::collections::HashMap;
+
+/// Elements whose subtree never contributes text.
+const SKIP_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "canvas", "iframe", "object", "embed",
+    "nav", "footer", "aside", "form", "button", "select", "input", "textarea", "dialog", "menu",
+];
+
+/// Elements that start a new paragraph in the output.
+const BLOCK_TAGS: &[&str] = &[
+    "p", "div", "section", "article", "main", "header", "h1", "h2", "h3", "h4", "h5", "h6",
+    "li", "ul", "ol", "dl", "dt", "dd", "blockquote", "pre", "table", "tr", "td", "th",
+    "figure", "figcaption", "address", "hr",
+];
+
+const NEGATIVE: &[&str] = &[
+    "ad", "ads", "advert", "advertisement", "banner", "breadcrumb", "breadcrumbs", "comment",
+    "comments", "consent", "cookie", "cookies", "footer", "masthead", "menu", "modal", "nav",
+    "navbar", "navigation", "newsletter", "outbrain", "popup", "promo", "related", "share",
+    "sharing", "sidebar", "skip", "social", "sponsored", "subscribe", "taboola", "widget",
+];
+
+const POSITIVE: &[&str] = &[
+    "article", "blog", "body", "content", "entry", "main", "page", "post", "story", "text",
+];
+
+const SKIP_ROLES: &[&str] = &["navigation", "contentinfo", "complementary", "dialog", "menu", "menubar", "banner"];
+
+/// Paragraphs shorter than this don't vote for their ancestors.
+const MIN_PARAGRAPH_CHARS: usize = 25;
+/// Below this much extracted text we distrust the candidate and keep the whole body.
+const MIN_ARTICLE_CHARS: usize = 250;
+
+pub fn main_text(doc: &Html) -> String {
+    let root = *doc.root_element();
+    let Some(body) = root.children().find(|n| tag(n) == Some("body")) else {
+        return blocks_to_text(root);
+    };
+
+    let mut scores: HashMap<NodeId, f64> = HashMap::new();
+    score_paragraphs(body, &mut scores);
+    let mut lens = HashMap::new();
+    measure(doc.tree.root(), &mut lens);
+
+    let best = scores
+        .iter()
+        .filter_map(|(id, s)| doc.tree.get(*id).map(|n| (n, *s * (1.0 - link_density(&lens, n)))))
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+
+    if let Some((top, top_score)) = best {
+        let text = sibling_text(top, top_score, &scores, &lens);
+        if text.chars().count() >= MIN_ARTICLE_CHARS {
+            return text;
+        }
+    }
+    blocks_to_text(body)
+}
+
+/* ------------------------ scoring ------------------------ */
+
+fn score_paragraphs(node: NodeRef<Node>, scores: &mut HashMap<NodeId, f64>) {
+    for child in node.children() {
+        if skipped(child) {
+            continue;
+        }
+        if matches!(tag(&child), Some("p" | "pre" | "td" | "blockquote")) {
+            let text = inner_text(child);
+            let len = text.chars().count();
+            if len < MIN_PARAGRAPH_CHARS {
+                continue;
+            }
+            let contribution = 1.0 + text.matches(',').count() as f64 + (len as f64 / 100.0).min(3.0);
+            let mut ancestors = child.ancestors().filter(|a| a.value().is_element());
+            if let Some(parent) = ancestors.next() {
+                *scores.entry(parent.id()).or_insert_with(|| base_score(parent)) += contribution;
+            }
+            if let Some(grand) = ancestors.next() {
+                *scores.entry(grand.id()).or_insert_with(|| base_score(grand)) += contribution / 2.0;
+            }
+        } else {
+            score_paragraphs(child, scores);
+        }
+    }
+}
+
+fn base_score(node: NodeRef<Node>) -> f64 {
+    let by_tag = match tag(&node) {
+        Some("article" | "main") => 10.0,
+        Some("div") => 5.0,
+        Some("pre" | "td" | "blockquote") => 3.0,
+        Some("address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li") => -3.0,
+        Some("h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th") => -5.0,
+        _ => 0.0,
+    };
+    by_tag + class_weight(node)
+}
+
+fn class_weight(node: NodeRef<Node>) -> f64 {
+    let Some(el) = node.value().as_element() else { return 0.0 };
+    let toks = tokens(el);
+    let mut w = 0.0;
+    if toks.iter().any(|t| NEGATIVE.contains(&t.as_str())) {
+        w -= 25.0;
+    }
+    if toks.iter().any(|t| POSITIVE.contains(&t.as_str())) {
+        w += 25.0;
+    }
+    w
+}
+
+fn link_density(lens: &HashMap<NodeId, Measure>, node: NodeRef<Node>) -> f64 {
+    let Some(m) = lens.get(&node.id()) else { return 0.0 };
+    let total = m.chars();
+    if total == 0 {
+        return 0.0;
+    }
+    (m.linked as f64 / total as f64).min(1.0)
+}
+
+/// Length of a subtree's `inner_text`, and how much of it sits inside links.
+/// Measured for every node in one bottom-up pass so scoring candidates is a
+/// lookup rather than a walk of each candidate's subtree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Measure {
+    /// Non-whitespace chars.
+    solid: usize,
+    words: usize,
+    /// Whether the raw text is empty, starts or ends mid-word; enough to join
+    /// two measures the way `split_whitespace().join(" ")` joins their text.
+    empty: bool,
+    open_start: bool,
+    open_end: bool,
+    /// Chars of `chars()` that fall inside `<a>`.
+    linked: usize,
+}
+
+impl Measure {
+    const EMPTY: Measure = Measure { solid: 0, words: 0, empty: true, open_start: false, open_end: false, linked: 0 };
+
+    fn of_text(t: &str) -> Self {
+        Measure {
+            solid: t.chars().filter(|c| !c.is_whitespace()).count(),
+            words: t.split_whitespace().count(),
+            empty: t.is_empty(),
+            open_start: t.starts_with(|c: char| !c.is_whitespace()),
+            open_end: t.ends_with(|c: char| !c.is_whitespace()),
+            linked: 0,
+        }
+    }
+
+    /// The measure of this text followed directly by `next`.
+    fn then(self, next: Measure) -> Measure {
+        let glued = self.open_end && next.open_start;
+        Measure {
+            solid: self.solid + next.solid,
+            words: self.words + next.words - glued as usize,
+            empty: self.empty && next.empty,
+            open_start: if self.empty { next.open_start } else { self.open_start },
+            open_end: if next.empty { self.open_end } else { next.open_end },
+            linked: self.linked + next.linked,
+        }
+    }
+
+    fn chars(&self) -> usize {
+        if self.words == 0 { 0 } else { self.solid + self.words - 1 }
+    }
+}
+
+/// Record the measure of `node` and every non-skipped node below it.
+fn measure(node: NodeRef<Node>, lens: &mut HashMap<NodeId, Measure>) -> Measure {
+    let mut m = Measure::EMPTY;
+    for c in node.children() {
+        match c.value() {
+            Node::Text(t) => m = m.then(Measure::of_text(t)),
+            _ if skipped_self(c) => {}
+            _ => m = m.then(measure(c, lens)),
+        }
+    }
+    if tag(&node) == Some("a") {
+        m.linked = m.chars();
+    }
+    lens.insert(node.id(), m);
+    m
+}
+
+/// The top candidate plus siblings that look like they belong to the same
+/// article (split bodies, lead paragraphs outside the main wrapper).
+fn sibling_text(
+    top: NodeRef<Node>,
+    top_score: f64,
+    scores: &HashMap<NodeId, f64>,
+    lens: &HashMap<NodeId, Measure>,
+) -> String {
+    let threshold = (top_score * 0.2).max(10.0);
+    let Some(parent) = top.parent() else { return blocks_to_text(top) };
+
+    let mut parts = Vec::new();
+    for sib in parent.children() {
+        if skipped(sib) {
+            continue;
+        }
+        let keep = if sib.id() == top.id()
+            || scores.get(&sib.id()).is_some_and(|s| s * (1.0 - link_density(lens, sib)) >= threshold)
+        {
+            true
+        } else if tag(&sib) == Some("p") {
+            let len = lens.get(&sib.id()).map_or(0, Measure::chars);
+            let ld = link_density(lens, sib);
+            (len > 80 && ld < 0.25) || (len > 0 && ld == 0.0 && inner_text(sib).contains(". "))
+        } else {
+            false
+        };
+        if keep {
+            let t = blocks_to_text(sib);
+            if !t.is_empty() {
+                parts.push(t);
+            }
+        }
+    }
+    parts.join("\n\n")
+}
+
+/* ------------------------ text output ------------------------ */
+
+/// Text of `node` with one paragraph per block element, blank-line separated.
+fn blocks_to_text(node: NodeRef<Node>) -> String {
+    let mut blocks = Vec::new();
+    let mut cur = String::new();
+    collect(node, &mut blocks, &mut cur);
+    flush(&mut blocks, &mut cur);
+    blocks.join("\n\n")
+}
+
+fn collect(node: NodeRef<Node>, blocks: &mut Vec<String>, cur: &mut String) {
+    match node.value() {
+        Node::Text(t) => push_inline(cur, t),
+        Node::Element(_) => {
+            if skipped(node) {
+                return;
+            }
+            if tag(&node) == Some("br") {
+                cur.push('\n');
+                return;
+            }
+            let block = tag(&node).is_some_and(|t| BLOCK_TAGS.contains(&t));
+            if block {
+                flush(blocks, cur);
+            }
+            for c in node.children() {
+                collect(c, blocks, cur);
+            }
+            if block {
+                flush(blocks, cur);
+            }
+        }
+        _ => {
+            for c in node.children() {
+                collect(c, blocks, cur);
+            }
+        }
+    }
+}
+
+fn push_inline(cur: &mut String, text: &str) {
+    if text.starts_with(char::is_whitespace) && !cur.ends_with([' ', '\n']) && !cur.is_empty() {
+        cur.push(' ');
+    }
+    let words: Vec<&str> = text.split_whitespace().collect();
+    cur.push_str(&words.join(" "));
+    if text.ends_with(char::is_whitespace) && !words.is_empty() {
+        cur.push(' ');
+    }
+}
+
+fn flush(blocks: &mut Vec<String>, cur: &mut String) {
+    let para = cur
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !para.is_empty() {
+        blocks.push(para);
+    }
+    cur.clear();
+}
+
+fn inner_text(node: NodeRef<Node>) -> String {
+    let mut out = String::new();
+    raw_text(node, &mut out);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn raw_text(node: NodeRef<Node>, out: &mut String) {
+    for c in node.children() {
+        match c.value() {
+            Node::Text(t) => out.push_str(t),
+            _ if skipped_self(c) => {}
+            _ => raw_text(c, out),
+        }
+    }
+}
+
+/* ------------------------ element tests ------------------------ */
+
+fn tag<'a>(node: &NodeRef<'a, Node>) -> Option<&'a str> {
+    node.value().as_element().map(|e| e.name())
+}
+
+fn skipped(node: NodeRef<Node>) -> bool {
+    node.value().is_element() && skipped_self(node)
+}
+
+fn skipped_self(node: NodeRef<Node>) -> bool {
+    let Some(el) = ElementRef::wrap(node) else { return false };
+    let el = el.value();
+    if SKIP_TAGS.contains(&el.name()) {
+        return true;
+    }
+    if el.attr("hidden").is_some() || el.attr("aria-hidden") == Some("true") {
+        return true;
+    }
+    if el.attr("role").is_some_and(|r| SKIP_ROLES.contains(&r.to_ascii_lowercase().as_str())) {
+        return true;
+    }
+    if matches!(el.name(), "body" | "main" | "article") {
+        return false;
+    }
+    let toks = tokens(el);
+    toks.iter().any(|t| NEGATIVE.contains(&t.as_str()))
+        && !toks.iter().any(|t| POSITIVE.contains(&t.as_str()))
+}
+
+/// Lowercased words of `class` and `id`, split on whitespace, `-` and `_`.
+fn tokens(el: &Element) -> Vec<String> {
+    let mut out = Vec::new();
+    for v in [el.attr("class"), el.attr("id")].into_iter().flatten() {
+        out.extend(
+            v.split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_ascii_lowercase()),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boilerplate_is_dropped_and_article_kept() {
+        let doc = Html::parse_document(include_str!("../tests/fixtures/extract/news.html"));
+        let text = main_text(&doc);
+        assert!(text.starts_with("Alpine glacier retreat has doubled since 2000"), "{text}");
+        assert!(text.contains("4,000 glaciers"));
+        assert!(text.ends_with("by the end of the century."), "{text}");
+        for boilerplate in [
+            "The Daily Planet", // masthead, footer
+            "Climate",          // nav
+            "Share on",         // share widget
+            "Ski resorts",      // class="related-posts"
+            "Heatwave",         // unmarked but link-heavy
+            "Reader comment",   // id="comments"
+        ] {
+            assert!(!text.contains(boilerplate), "{boilerplate} kept in {text}");
+        }
+    }
+
+    #[test]
+    fn short_pages_fall_back_to_the_whole_body() {
+        let doc = Html::parse_document(
+            "<html><body><nav>Menu</nav><h1>Notice</h1><div><p>Closed on Monday.</p></div></body></html>",
+        );
+        assert_eq!(main_text(&doc), "Notice\n\nClosed on Monday.");
+    }
+
+    #[test]
+    fn measure_agrees_with_inner_text() {
+        let doc = Html::parse_document(concat!(
+            "<div id=x> Lead <b>in</b>line<i></i>  text <a href=/1>one <em>link</em></a>",
+            "<span> </span><a href=/2>two</a>end <nav><a href=/3>skipped</a></nav>",
+            "<p>para<a href=/4>glued</a>\n</p><p>\t</p></div>",
+        ));
+        let mut lens = HashMap::new();
+        measure(doc.tree.root(), &mut lens);
+        let visible = |n: &NodeRef<Node>| !skipped(*n) && !n.ancestors().any(skipped);
+        for node in doc.tree.root().descendants().filter(|n| n.value().is_element() && visible(n)) {
+            let m = lens[&node.id()];
+            assert_eq!(m.chars(), inner_text(node).chars().count(), "{:?}", node.value());
+            let linked: usize = node
+                .descendants()
+                .filter(|n| tag(n) == Some("a") && visible(n))
+                .map(|a| inner_text(a).chars().count())
+                .sum();
+            assert_eq!(m.linked, linked, "{:?}", node.value());
+        }
+        let x = doc.tree.root().descendants().find(|n| n.value().as_element().and_then(|e| e.attr("id")) == Some("x")).unwrap();
+        assert_eq!(inner_text(x), "Lead inline text one link twoend paraglued");
+        assert_eq!(lens[&x.id()].linked, "one link".len() + "two".len() + "glued".len());
+    }
+}
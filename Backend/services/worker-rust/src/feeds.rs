@@ -0,0 +1,301 @@
+//! RSS/Atom/JSON Feed polling. Subscribed feeds are fetched with conditional
+//! GETs; entries not seen before (by GUID or link) go to the crawl queue at
+//! the feed's priority, and their date and author are kept in `feed_items`
+//! for the document upsert to pick up.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use feed_rs::model::Entry;
+use tracing::error;
+use url::Url;
+
+use crate::links::Scope;
+use crate::robots::agent_token;
+use crate::scrape::{FetchedBody, ScrapeClient};
+use crate::store::{self, Enqueue, Feed, FeedItemRow, PgPool};
+use crate::types::Validators;
+
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub guid: String,
+    pub url: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct PollReport {
+    pub feed: String,
+    pub status: Option<u16>,
+    pub not_modified: bool,
+    pub entries: usize,
+    pub new_items: usize,
+    pub enqueued: usize,
+    pub error: Option<String>,
+}
+
+/// Claim up to `limit` feeds whose interval has elapsed and poll them in turn.
+pub async fn poll_due(pg: &PgPool, sc: &ScrapeClient, scope: &Scope, limit: i64) -> Result<Vec<PollReport>> {
+    let due = store::claim_due_feeds(pg, limit).await?;
+    let mut report = Vec::with_capacity(due.len());
+    for feed in &due {
+        report.push(poll(pg, sc, scope, feed).await);
+    }
+    Ok(report)
+}
+
+/// Poll one feed and record the result on its row. Errors end up in the
+/// report and in `feeds.last_error`; they never fail the caller.
+pub async fn poll(pg: &PgPool, sc: &ScrapeClient, scope: &Scope, feed: &Feed) -> PollReport {
+    let mut report = PollReport { feed: feed.url.clone(), ..Default::default() };
+    if let Err(e) = poll_inner(pg, sc, scope, feed, &mut report).await {
+        let msg = e.to_string();
+        if let Err(db) = store::feed_failed(pg, feed.id, report.status.map(i32::from), &msg).await {
+            error!(error=?db, feed=%feed.url, "recording feed failure failed");
+        }
+        report.error = Some(msg);
+    }
+    report
+}
+
+async fn poll_inner(pg: &PgPool, sc: &ScrapeClient, scope: &Scope, feed: &Feed, report: &mut PollReport) -> Result<()> {
+    let url = Url::parse(&feed.url)?;
+    sc.vet(&url).await?;
+    let agent = agent_token(&sc.user_agent);
+    let robots = sc.robots(&url).await;
+    if !robots.is_allowed(&agent, &url) {
+        bail!("disallowed by robots.txt");
+    }
+
+    let prior = Validators { etag: feed.etag.clone(), last_modified: feed.last_modified.clone() };
+    let FetchedBody { status, etag, last_modified, body, truncated, .. } = sc.fetch_bytes(&url, robots.crawl_delay(&agent), Some(&prior)).await?;
+    report.status = Some(status.as_u16());
+
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        report.not_modified = true;
+        store::feed_polled(pg, feed.id, 304, None, None).await?;
+        return Ok(());
+    }
+    if !status.is_success() {
+        bail!("http status {}", status.as_u16());
+    }
+    // a cut-off XML document won't parse; say why instead
+    if truncated {
+        bail!("feed larger than the body size limit");
+    }
+
+    let base = feed.url.clone();
+    let (title, entries) = tokio::task::spawn_blocking(move || parse(&body, &base)).await??;
+    report.entries = entries.len();
+
+    let rows: Vec<FeedItemRow> = entries.iter().map(|e| FeedItemRow {
+        guid: &e.guid,
+        url: &e.url,
+        title: e.title.as_deref(),
+        author: e.author.as_deref(),
+        published_at: e.published_at,
+    }).collect();
+    let new_urls = store::insert_feed_items(pg, feed.id, &rows).await?;
+    report.new_items = new_urls.len();
+
+    // feed entries are articles, not hubs: enqueue at max depth like sitemap URLs
+    let now = Utc::now();
+    let items: Vec<Enqueue> = new_urls.iter().map(|u| Enqueue {
+        url: u,
+        priority: feed.priority,
+        depth: scope.max_depth,
+        discovered_from: Some(&feed.url),
+        next_fetch_at: Some(now),
+    }).collect();
+    report.enqueued = store::enqueue_many(pg, &items).await?;
+
+    let validators = Validators { etag, last_modified };
+    store::feed_polled(pg, feed.id, i32::from(status.as_u16()), title.as_deref(), Some(&validators)).await?;
+    Ok(())
+}
+
+/// Parse a feed document into its title and http(s) entries. Entries without
+/// an id are keyed by their link instead of feed-rs's generated hash.
+pub fn parse(body: &[u8], feed_url: &str) -> Result<(Option<String>, Vec<FeedEntry>)> {
+    let parser = feed_rs::parser::Builder::new()
+        .base_uri(Some(feed_url))
+        .id_generator(|links, title, _| {
+            links.first().map(|l| l.href.clone())
+                .or_else(|| title.as_ref().map(|t| t.content.clone()))
+                .unwrap_or_default()
+        })
+        .build();
+    let feed = parser.parse(body)?;
+    let title = feed.title.map(|t| t.content.trim().to_string()).filter(|t| !t.is_empty());
+    let entries = feed.entries.iter().filter_map(entry).collect();
+    Ok((title, entries))
+}
+
+fn entry(e: &Entry) -> Option<FeedEntry> {
+    // Atom entries may list several links; rel="alternate" (or none) is the article
+    let link = e.links.iter()
+        .find(|l| l.rel.as_deref().is_none_or(|r| r == "alternate"))
+        .or_else(|| e.links.first())?;
+    let url = Url::parse(link.href.trim()).ok().filter(|u| matches!(u.scheme(), "http" | "https"))?;
+
+    let guid = match e.id.trim() {
+        "" => url.to_string(),
+        id => id.to_string(),
+    };
+    let authors: Vec<&str> = e.authors.iter().map(|p| p.name.trim()).filter(|n| !n.is_empty()).collect();
+    Some(FeedEntry {
+        guid,
+        url: url.to_string(),
+        title: e.title.as_ref().map(|t| t.content.trim().to_string()).filter(|t| !t.is_empty()),
+        author: (!authors.is_empty()).then(|| authors.join(", ")),
+        published_at: e.published.or(e.updated),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch;
+    use crate::migrate;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_rss_items_keyed_by_guid_or_link() {
+        let body = std::fs::read("tests/fixtures/replay/feeds.example_rss.body").unwrap();
+        let (title, entries) = parse(&body, "https://feeds.example/rss.xml").unwrap();
+        assert_eq!(title.as_deref(), Some("Feeds Example Newsroom"));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].guid, "news-1001");
+        assert_eq!(entries[0].url, "https://feeds.example/news/river-levels?utm_source=rss");
+        assert_eq!(entries[0].published_at.unwrap().to_rfc3339(), "2024-05-02T08:30:00+00:00");
+        // no <guid>: the (resolved) link stands in
+        assert_eq!(entries[1].guid, "https://feeds.example/news/flood-barrier");
+        assert_eq!(entries[1].url, "https://feeds.example/news/flood-barrier");
+    }
+
+    #[test]
+    fn atom_entries_use_the_alternate_link_and_join_authors() {
+        let body = br#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+              <title> Lab notes </title>
+              <entry>
+                <id>tag:lab.example,2024:1</id>
+                <title>Core samples</title>
+                <link rel="replies" href="https://lab.example/1/comments"/>
+                <link rel="alternate" href="https://lab.example/1"/>
+                <author><name>A. Researcher</name></author>
+                <author><name>B. Analyst</name></author>
+                <updated>2024-05-01T12:00:00Z</updated>
+              </entry>
+              <entry>
+                <id>tag:lab.example,2024:2</id>
+                <link href="ftp://lab.example/2.tar"/>
+              </entry>
+            </feed>"#;
+        let (title, entries) = parse(body, "https://lab.example/atom.xml").unwrap();
+        assert_eq!(title.as_deref(), Some("Lab notes"));
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!((e.guid.as_str(), e.url.as_str()), ("tag:lab.example,2024:1", "https://lab.example/1"));
+        assert_eq!(e.author.as_deref(), Some("A. Researcher, B. Analyst"));
+        assert_eq!(e.published_at.unwrap().to_rfc3339(), "2024-05-01T12:00:00+00:00");
+    }
+
+    async fn pool() -> PgPool {
+        let pool = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pool).await.unwrap();
+        pool
+    }
+
+    /// A subscription with no history: earlier runs' items and queued entries are dropped.
+    async fn fresh_feed(pg: &PgPool, url: &str) -> Feed {
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.crawl_queue WHERE discovered_from = $1", &[&url]).await.unwrap();
+        client.execute("DELETE FROM public.feeds WHERE url = $1", &[&url]).await.unwrap();
+        store::upsert_feed(pg, url, 70, 900).await.unwrap()
+    }
+
+    fn client() -> ScrapeClient {
+        ScrapeClient::new("TestBot/1.0", 2, Duration::ZERO).with_fetcher(fetch::test_fixtures())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn poll_enqueues_new_items_and_keeps_validators() {
+        let pg = pool().await;
+        let sc = client();
+        let feed = fresh_feed(&pg, "https://feeds.example/rss.xml").await;
+
+        let first = poll(&pg, &sc, &Scope::default(), &feed).await;
+        assert_eq!(first.error, None);
+        assert_eq!((first.status, first.entries, first.new_items, first.enqueued), (Some(200), 2, 2, 2));
+        let feed = store::list_feeds(&pg).await.unwrap().into_iter().find(|f| f.id == feed.id).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("Feeds Example Newsroom"));
+        assert_eq!(feed.etag.as_deref(), Some("\"f1\""));
+        assert_eq!(feed.last_modified.as_deref(), Some("Thu, 02 May 2024 09:00:00 GMT"));
+        assert_eq!(feed.last_status, Some(200));
+
+        let client = pg.get().await.unwrap();
+        let queued: Vec<(String, i32)> = client
+            .query("SELECT url, priority FROM public.crawl_queue WHERE discovered_from = $1 ORDER BY url", &[&feed.url])
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get(0), r.get(1)))
+            .collect();
+        assert_eq!(
+            queued,
+            [("https://feeds.example/news/flood-barrier".to_string(), 70), ("https://feeds.example/news/river-levels".to_string(), 70)]
+        );
+
+        // the stored ETag goes out and the replay answers 304; validators survive it
+        let second = poll(&pg, &sc, &Scope::default(), &feed).await;
+        assert!(second.not_modified, "{second:?}");
+        assert_eq!((second.status, second.new_items), (Some(304), 0));
+        let feed = store::list_feeds(&pg).await.unwrap().into_iter().find(|f| f.id == feed.id).unwrap();
+        assert_eq!(feed.etag.as_deref(), Some("\"f1\""));
+        assert_eq!(feed.last_status, Some(304));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn items_are_new_only_if_neither_guid_nor_url_is_known() {
+        let pg = pool().await;
+        let feed = fresh_feed(&pg, "https://feeds.example/items.xml").await;
+        let item = |guid, url| FeedItemRow { guid, url, title: None, author: None, published_at: None };
+
+        let new = store::insert_feed_items(&pg, feed.id, &[item("g1", "https://feeds.example/a"), item("g2", "https://feeds.example/b")])
+            .await
+            .unwrap();
+        assert_eq!(new, ["https://feeds.example/a", "https://feeds.example/b"]);
+
+        let new = store::insert_feed_items(
+            &pg,
+            feed.id,
+            &[
+                item("g1", "https://feeds.example/a"),
+                // regenerated GUID for a known link
+                item("g1-rebuilt", "https://feeds.example/b?utm_medium=rss"),
+                // known GUID, new link
+                item("g2", "https://feeds.example/b2"),
+                item("g3", "https://feeds.example/c"),
+                item("g3", "https://feeds.example/c-dup"),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(new, ["https://feeds.example/c"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn failures_are_recorded_on_the_feed() {
+        let pg = pool().await;
+        let feed = fresh_feed(&pg, "https://closed.example/feed.xml").await;
+        let report = poll(&pg, &client(), &Scope::default(), &feed).await;
+        assert_eq!(report.error.as_deref(), Some("disallowed by robots.txt"));
+        let feed = store::list_feeds(&pg).await.unwrap().into_iter().find(|f| f.id == feed.id).unwrap();
+        assert_eq!(feed.last_error.as_deref(), Some("disallowed by robots.txt"));
+    }
+}
@@ -0,0 +1,467 @@
+//! Where response bytes come from. `LiveFetcher` goes to the network,
+//! `ReplayFetcher` answers from recorded fixtures (a WARC, or a directory of
+//! fixture files and WARCs), and `RecordFetcher` fetches live and saves what
+//! it got as fixtures. Everything above the fetcher — robots, politeness,
+//! body caps, extraction — is the same in all three, so a crawl can be run
+//! deterministically with no network.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::egress::EgressPolicy;
+use crate::error::ScrapeError;
+use crate::warc::{self, WarcReader};
+
+/// Largest body `RecordFetcher` keeps; anything beyond is cut off.
+const RECORD_MAX_BYTES: usize = 64 * 1024 * 1024;
+/// Describe the transfer, not the (already decoded) body we hand out.
+const TRANSFER_HEADERS: &[&str] = &["content-encoding", "transfer-encoding", "content-length"];
+
+/// A GET request's response head plus its body, read incrementally so
+/// callers can stop at a byte cap.
+pub struct FetchResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    body: Body,
+}
+
+enum Body {
+    Live(reqwest::Response),
+    Buffered(Option<Bytes>),
+}
+
+impl FetchResponse {
+    pub fn buffered(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        Self { status, headers, body: Body::Buffered(Some(body)) }
+    }
+
+    /// Body size if known up front.
+    pub fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            Body::Live(res) => res.content_length(),
+            Body::Buffered(b) => Some(b.as_ref().map_or(0, |b| b.len() as u64)),
+        }
+    }
+
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, ScrapeError> {
+        match &mut self.body {
+            Body::Live(res) => Ok(res.chunk().await?),
+            Body::Buffered(b) => Ok(b.take()),
+        }
+    }
+
+    /// Read at most `cap` bytes of the body; `true` if there was more.
+    pub async fn read_capped(&mut self, cap: usize) -> Result<(Bytes, bool), ScrapeError> {
+        let mut buf = BytesMut::with_capacity(self.content_length().map_or(64 * 1024, |l| l as usize).min(cap));
+        while let Some(chunk) = self.chunk().await? {
+            let room = cap - buf.len();
+            if chunk.len() > room {
+                buf.extend_from_slice(&chunk[..room]);
+                return Ok((buf.freeze(), true));
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok((buf.freeze(), false))
+    }
+}
+
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    /// GET `url` with `headers` added to the request (validators for
+    /// conditional requests). Redirects are followed; the response is that of
+    /// the final hop.
+    async fn get(&self, url: &Url, headers: HeaderMap) -> Result<FetchResponse, ScrapeError>;
+
+    /// Refuse a URL the egress policy won't let us reach, before any request
+    /// (robots.txt included) is made. Only fetchers that touch the network
+    /// have anything to check.
+    async fn vet(&self, _url: &Url) -> Result<(), ScrapeError> {
+        Ok(())
+    }
+}
+
+/// `FETCH_MODE=live|replay|record` (default live) with `FETCH_FIXTURES` as
+/// the WARC or directory replayed from, or the directory recorded into.
+pub fn from_env(http: &Client, egress: Arc<EgressPolicy>) -> Result<Arc<dyn Fetcher>> {
+    let fixtures = || -> Result<PathBuf> {
+        match std::env::var("FETCH_FIXTURES") {
+            Ok(p) if !p.is_empty() => Ok(PathBuf::from(p)),
+            _ => bail!("FETCH_FIXTURES must be set for FETCH_MODE=replay or record"),
+        }
+    };
+    match std::env::var("FETCH_MODE").unwrap_or_default().to_ascii_lowercase().as_str() {
+        "" | "live" => Ok(Arc::new(LiveFetcher::new(http.clone(), egress))),
+        "replay" => {
+            let path = fixtures()?;
+            let replay = ReplayFetcher::open(&path)?;
+            info!(path=%path.display(), responses=replay.len(), "replaying recorded responses");
+            Ok(Arc::new(replay))
+        }
+        "record" => {
+            let path = fixtures()?;
+            info!(path=%path.display(), "recording responses as fixtures");
+            Ok(Arc::new(RecordFetcher::new(LiveFetcher::new(http.clone(), egress), path)?))
+        }
+        other => bail!("unknown FETCH_MODE {other:?}; expected live, replay or record"),
+    }
+}
+
+/* ------------------------ live ------------------------ */
+
+/// `http` must be built with the same `egress` policy's resolver and
+/// redirect policy; see `ScrapeClient::with_egress`.
+pub struct LiveFetcher {
+    http: Client,
+    egress: Arc<EgressPolicy>,
+}
+
+impl LiveFetcher {
+    pub fn new(http: Client, egress: Arc<EgressPolicy>) -> Self {
+        Self { http, egress }
+    }
+}
+
+#[async_trait]
+impl Fetcher for LiveFetcher {
+    async fn get(&self, url: &Url, headers: HeaderMap) -> Result<FetchResponse, ScrapeError> {
+        let res = self.http.get(url.clone()).headers(headers).send().await?;
+        Ok(FetchResponse { status: res.status(), headers: res.headers().clone(), body: Body::Live(res) })
+    }
+
+    async fn vet(&self, url: &Url) -> Result<(), ScrapeError> {
+        Ok(self.egress.vet(url).await?)
+    }
+}
+
+/* ------------------------ fixtures ------------------------ */
+
+/// `{name}.json` next to `{name}.body` in a fixture directory. The body is
+/// stored as served after content decoding, so fixtures can be written or
+/// edited by hand.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct FixtureMeta {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+struct Recorded {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Recorded {
+    fn new(status: u16, headers: Vec<(String, String)>, body: Bytes) -> Self {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+        Self { status, headers: to_header_map(&headers), body }
+    }
+}
+
+fn to_header_map(headers: &[(String, String)]) -> HeaderMap {
+    headers
+        .iter()
+        .filter(|(k, _)| !TRANSFER_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+        .filter_map(|(k, v)| Some((HeaderName::from_bytes(k.as_bytes()).ok()?, HeaderValue::from_str(v).ok()?)))
+        .collect()
+}
+
+fn from_header_map(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(k, _)| !TRANSFER_HEADERS.contains(&k.as_str()))
+        .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+        .collect()
+}
+
+/// File stem for a URL's fixture: readable host prefix, unique hash suffix.
+fn fixture_name(url: &Url) -> String {
+    let host: String = url
+        .host_str()
+        .unwrap_or("nohost")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    let hash = format!("{:x}", Sha256::digest(url.as_str().as_bytes()));
+    format!("{host}_{}", &hash[..16])
+}
+
+/* ------------------------ replay ------------------------ */
+
+/// Serves recorded responses by exact URL. A URL with no recording gets an
+/// empty 404, which for robots.txt means "no restrictions". When a URL was
+/// recorded more than once the last capture wins.
+pub struct ReplayFetcher {
+    responses: HashMap<String, Recorded>,
+}
+
+impl ReplayFetcher {
+    /// Load a `.warc`/`.warc.gz` file, or a directory of fixtures and WARCs.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut responses = HashMap::new();
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)
+                .with_context(|| format!("read {}", path.display()))?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?;
+            entries.sort();
+            for p in entries {
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                if name.ends_with(".warc") || name.ends_with(".warc.gz") {
+                    load_warc(&p, &mut responses)?;
+                } else if name.ends_with(".json") {
+                    load_fixture(&p, &mut responses)?;
+                }
+            }
+        } else {
+            load_warc(path, &mut responses)?;
+        }
+        Ok(Self { responses })
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+}
+
+fn load_fixture(meta_path: &Path, out: &mut HashMap<String, Recorded>) -> Result<()> {
+    let meta: FixtureMeta = serde_json::from_slice(&fs::read(meta_path)?)
+        .with_context(|| format!("parse {}", meta_path.display()))?;
+    let body_path = meta_path.with_extension("body");
+    let body = fs::read(&body_path).with_context(|| format!("read {}", body_path.display()))?;
+    out.insert(meta.url, Recorded::new(meta.status, meta.headers, body.into()));
+    Ok(())
+}
+
+fn load_warc(path: &Path, out: &mut HashMap<String, Recorded>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = WarcReader::new(BufReader::new(file))?;
+    while let Some(rec) = reader.next_record()? {
+        let (Some(kind), Some(uri)) = (rec.header("WARC-Type"), rec.header("WARC-Target-URI")) else { continue };
+        let Some(msg) = warc::parse_http_response(&rec.block) else { continue };
+        let headers = warc::original_headers(msg.headers);
+        let body: Bytes = match kind {
+            "response" => msg.body.into(),
+            // same payload as an earlier response record
+            "revisit" => match rec.header("WARC-Refers-To-Target-URI").and_then(|u| out.get(u)) {
+                Some(r) => r.body.clone(),
+                None => continue,
+            },
+            _ => continue,
+        };
+        out.insert(uri.to_string(), Recorded::new(msg.status, headers, body));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Fetcher for ReplayFetcher {
+    async fn get(&self, url: &Url, headers: HeaderMap) -> Result<FetchResponse, ScrapeError> {
+        let Some(r) = self.responses.get(url.as_str()) else {
+            warn!(url=%url, "no recorded response; replaying 404");
+            return Ok(FetchResponse::buffered(StatusCode::NOT_FOUND, HeaderMap::new(), Bytes::new()));
+        };
+        Ok(answer(r, &headers))
+    }
+}
+
+/// Respond to a request carrying `headers` from a recording, honouring
+/// validators the way the origin would have.
+fn answer(r: &Recorded, headers: &HeaderMap) -> FetchResponse {
+    let etag_match = match (headers.get(header::IF_NONE_MATCH), r.headers.get(header::ETAG)) {
+        (Some(sent), Some(etag)) => sent == etag,
+        _ => false,
+    };
+    let lm_match = match (headers.get(header::IF_MODIFIED_SINCE), r.headers.get(header::LAST_MODIFIED)) {
+        (Some(sent), Some(lm)) => sent == lm,
+        _ => false,
+    };
+    if r.status.is_success() && (etag_match || lm_match) {
+        return FetchResponse::buffered(StatusCode::NOT_MODIFIED, r.headers.clone(), Bytes::new());
+    }
+    FetchResponse::buffered(r.status, r.headers.clone(), r.body.clone())
+}
+
+/* ------------------------ record ------------------------ */
+
+/// Fetches through `live` and writes each response into `dir` as a fixture
+/// `ReplayFetcher` can serve. Failed requests are not recorded. Requests go
+/// out without validators, so a recrawl records the full page rather than an
+/// empty 304; the caller's validators are then answered from the recording.
+pub struct RecordFetcher {
+    live: LiveFetcher,
+    dir: PathBuf,
+}
+
+impl RecordFetcher {
+    pub fn new(live: LiveFetcher, dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        Ok(Self { live, dir })
+    }
+}
+
+#[async_trait]
+impl Fetcher for RecordFetcher {
+    async fn get(&self, url: &Url, headers: HeaderMap) -> Result<FetchResponse, ScrapeError> {
+        let mut unconditional = headers.clone();
+        unconditional.remove(header::IF_NONE_MATCH);
+        unconditional.remove(header::IF_MODIFIED_SINCE);
+        let mut res = self.live.get(url, unconditional).await?;
+        let (body, truncated) = res.read_capped(RECORD_MAX_BYTES).await?;
+        if truncated {
+            warn!(url=%url, limit=RECORD_MAX_BYTES, "recorded body truncated");
+        }
+        let meta = FixtureMeta { url: url.to_string(), status: res.status.as_u16(), headers: from_header_map(&res.headers) };
+        let (dir, name, data) = (self.dir.clone(), fixture_name(url), body.clone());
+        let saved = tokio::task::spawn_blocking(move || write_fixture(&dir, &name, &meta, &data)).await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(error=?e, url=%url, "fixture write failed"),
+            Err(e) => warn!(error=?e, url=%url, "fixture write failed"),
+        }
+        let recorded = Recorded { status: res.status, headers: to_header_map(&from_header_map(&res.headers)), body };
+        Ok(answer(&recorded, &headers))
+    }
+
+    async fn vet(&self, url: &Url) -> Result<(), ScrapeError> {
+        self.live.vet(url).await
+    }
+}
+
+/// Body first, then metadata: `ReplayFetcher` only looks at `.json` files.
+fn write_fixture(dir: &Path, name: &str, meta: &FixtureMeta, body: &[u8]) -> Result<()> {
+    fs::write(dir.join(format!("{name}.body")), body)?;
+    fs::write(dir.join(format!("{name}.json")), serde_json::to_vec_pretty(meta)?)?;
+    Ok(())
+}
+
+/// The recorded responses under `tests/fixtures/replay`.
+#[cfg(test)]
+pub(crate) fn test_fixtures() -> Arc<dyn Fetcher> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay");
+    Arc::new(ReplayFetcher::open(&dir).expect("replay fixtures"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape::ScrapeClient;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    async fn get(f: &dyn Fetcher, url: &str, headers: HeaderMap) -> (StatusCode, HeaderMap, Bytes) {
+        let mut res = f.get(&Url::parse(url).unwrap(), headers).await.unwrap();
+        let (body, _) = res.read_capped(usize::MAX).await.unwrap();
+        (res.status, res.headers, body)
+    }
+
+    fn validators(etag: Option<&str>, last_modified: Option<&str>) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        if let Some(e) = etag {
+            h.insert(header::IF_NONE_MATCH, HeaderValue::from_str(e).unwrap());
+        }
+        if let Some(lm) = last_modified {
+            h.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_str(lm).unwrap());
+        }
+        h
+    }
+
+    #[tokio::test]
+    async fn replays_fixtures_by_url() {
+        let f = test_fixtures();
+        let (status, headers, body) = get(&*f, "https://allowed.example/robots.txt", HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        assert!(body.starts_with(b"User-agent: *"));
+
+        let (status, _, body) = get(&*f, "https://down.example/robots.txt", HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!body.is_empty());
+
+        let (status, _, body) = get(&*f, "https://unrecorded.example/", HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_matching_validators_with_304() {
+        let f = test_fixtures();
+        let url = "https://allowed.example/article";
+        let (status, _, body) = get(&*f, url, validators(Some("\"a1\""), None)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        let (status, _, _) = get(&*f, url, validators(None, Some("Tue, 06 Oct 2026 08:00:00 GMT"))).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let (status, _, body) = get(&*f, url, validators(Some("\"stale\""), None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.is_empty());
+    }
+
+    /// Serves `requests` connections on loopback: 304 to a conditional
+    /// request, the page otherwise.
+    fn origin(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let conditional = String::from_utf8_lossy(&req).to_ascii_lowercase().contains("if-none-match");
+                let res: &[u8] = if conditional {
+                    b"HTTP/1.1 304 Not Modified\r\nETag: \"r1\"\r\nConnection: close\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nETag: \"r1\"\r\nContent-Length: 13\r\nConnection: close\r\n\r\n<p>page</p>\r\n"
+                };
+                stream.write_all(res).unwrap();
+            }
+        });
+        format!("http://{addr}/page")
+    }
+
+    #[tokio::test]
+    async fn records_full_responses_for_conditional_requests() {
+        let dir = std::env::temp_dir().join(format!("record-{:016x}", rand::random::<u64>()));
+        let mut egress = EgressPolicy::default();
+        egress.allow_cidrs.push("127.0.0.1/32".parse().unwrap());
+        let sc = ScrapeClient::new("TestBot/1.0", 1, std::time::Duration::ZERO).with_egress(egress);
+        let record = RecordFetcher::new(LiveFetcher::new(sc.http.clone(), sc.egress()), dir.clone()).unwrap();
+        let url = origin(2);
+
+        // a recrawl with validators: the caller still sees its 304 ...
+        let (status, _, body) = get(&record, &url, validators(Some("\"r1\""), None)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        let (status, _, body) = get(&record, &url, validators(Some("\"old\""), None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"<p>page</p>\r\n");
+
+        // ... but the fixture holds the page, which replays without validators
+        let replay = ReplayFetcher::open(&dir).unwrap();
+        let (status, headers, body) = get(&replay, &url, HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ETAG], "\"r1\"");
+        assert_eq!(&body[..], b"<p>page</p>\r\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Outbound link discovery and the scope rules deciding which links join the
+//! crawl frontier.
+
+use scraper::{Html, Selector};
+use std::collections::HashSet;
+use url::Url;
+
+use crate::canon;
+use crate::politeness::registrable_domain;
+
+/// Paths we never want to fetch as documents.
+const SKIP_EXTENSIONS: &[&str] = &[
+    ".jpg", ".jpeg", ".png", ".gif", ".webp", ".svg", ".ico", ".css", ".js", ".json", ".zip",
+    ".gz", ".tar", ".mp3", ".mp4", ".mov", ".avi", ".webm", ".woff", ".woff2", ".ttf", ".exe",
+    ".dmg", ".apk", ".xls", ".xlsx", ".doc", ".docx", ".ppt", ".pptx",
+];
+
+/// `<a href>` targets of a page, resolved against `<base href>` (or the page
+/// URL), canonicalized and de-duplicated in document order. Honors
+/// `rel="nofollow"` and `<meta name="robots" content="nofollow">`.
+pub fn extract_links(doc: &Html, page_url: &Url) -> Vec<Url> {
+    let meta_robots = Selector::parse(r#"meta[name="robots" i]"#).unwrap();
+    let nofollow_page = doc
+        .select(&meta_robots)
+        .filter_map(|m| m.value().attr("content"))
+        .any(|c| c.to_ascii_lowercase().split(',').any(|d| matches!(d.trim(), "nofollow" | "none")));
+    if nofollow_page {
+        return Vec::new();
+    }
+
+    let base_sel = Selector::parse("base[href]").unwrap();
+    let base = doc
+        .select(&base_sel)
+        .next()
+        .and_then(|b| b.value().attr("href"))
+        .and_then(|h| page_url.join(h.trim()).ok())
+        .unwrap_or_else(|| page_url.clone());
+
+    let a_sel = Selector::parse("a[href]").unwrap();
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+    for a in doc.select(&a_sel) {
+        let el = a.value();
+        if el.attr("rel").is_some_and(|r| r.split_whitespace().any(|t| t.eq_ignore_ascii_case("nofollow"))) {
+            continue;
+        }
+        let Some(href) = el.attr("href") else { continue };
+        let Ok(link) = base.join(href.trim()) else { continue };
+        if !matches!(link.scheme(), "http" | "https") {
+            continue;
+        }
+        let link = canon::canonicalize(&link);
+        if seen.insert(link.as_str().to_string()) {
+            out.push(link);
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct Scope {
+    /// Links found at this depth are not followed further (seeds are depth 0).
+    pub max_depth: i32,
+    /// Only follow links within the discovering page's registrable domain.
+    pub same_site: bool,
+    /// Cap on links enqueued from a single page.
+    pub max_links_per_page: usize,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self { max_depth: 2, same_site: true, max_links_per_page: 100 }
+    }
+}
+
+/// A link that passed scope rules, ready for `store::enqueue_many`.
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub url: String,
+    pub priority: i32,
+    pub depth: i32,
+}
+
+/// Apply scope rules to the links of a page fetched at `parent_depth`.
+/// Children inherit the parent's priority minus 10 per hop (floor 0), so the
+/// frontier drains breadth-first within a seed.
+pub fn in_scope(scope: &Scope, parent: &Url, parent_priority: i32, parent_depth: i32, links: &[String]) -> Vec<Discovered> {
+    let depth = parent_depth + 1;
+    if depth > scope.max_depth {
+        return Vec::new();
+    }
+    let parent_site = registrable_domain(parent.host_str().unwrap_or(""));
+    let priority = (parent_priority - 10).max(0);
+
+    links
+        .iter()
+        .filter_map(|l| Url::parse(l).ok())
+        .filter(|l| l.as_str() != parent.as_str())
+        .filter(|l| !scope.same_site || registrable_domain(l.host_str().unwrap_or("")) == parent_site)
+        .filter(|l| {
+            let path = l.path().to_ascii_lowercase();
+            !SKIP_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+        })
+        .take(scope.max_links_per_page)
+        .map(|l| Discovered { url: l.to_string(), priority, depth })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(html: &str, page: &str) -> Vec<String> {
+        extract_links(&Html::parse_document(html), &Url::parse(page).unwrap()).into_iter().map(String::from).collect()
+    }
+
+    fn scoped(scope: &Scope, parent_depth: i32, links: &[&str]) -> Vec<String> {
+        let parent = Url::parse("https://www.example.org/news").unwrap();
+        let links: Vec<String> = links.iter().map(|l| l.to_string()).collect();
+        in_scope(scope, &parent, 50, parent_depth, &links).into_iter().map(|d| d.url).collect()
+    }
+
+    #[test]
+    fn resolves_against_base_href() {
+        let html = r#"<head><base href="/docs/v2/"></head><body>
+            <a href="intro">rel</a> <a href="/abs">abs</a> <a href="https://other.example/x">ext</a></body>"#;
+        assert_eq!(
+            links(html, "https://example.org/page/index.html"),
+            ["https://example.org/docs/v2/intro", "https://example.org/abs", "https://other.example/x"]
+        );
+        assert_eq!(links(r#"<a href="b">b</a>"#, "https://example.org/a/page"), ["https://example.org/a/b"]);
+    }
+
+    #[test]
+    fn honors_rel_and_meta_nofollow() {
+        let html = r#"<a href="/a">a</a> <a rel="external NoFollow" href="/b">b</a> <a rel="noopener" href="/c">c</a>"#;
+        assert_eq!(links(html, "https://example.org/"), ["https://example.org/a", "https://example.org/c"]);
+
+        for content in ["nofollow", "noindex, NOFOLLOW", "none"] {
+            let html = format!(r#"<head><meta name="Robots" content="{content}"></head><body><a href="/a">a</a></body>"#);
+            assert!(links(&html, "https://example.org/").is_empty(), "{content}");
+        }
+        let html = r#"<head><meta name="robots" content="noindex"></head><body><a href="/a">a</a></body>"#;
+        assert_eq!(links(html, "https://example.org/"), ["https://example.org/a"]);
+    }
+
+    #[test]
+    fn dedups_after_canonicalization_and_drops_other_schemes() {
+        let html = r#"
+            <a href="/story?b=2&a=1">1</a>
+            <a href="/story/?a=1&b=2&utm_source=feed#comments">2</a>
+            <a href="HTTPS://EXAMPLE.ORG//story?a=1&b=2">3</a>
+            <a href="mailto:desk@example.org">mail</a> <a href="javascript:void(0)">js</a> <a href="ftp://example.org/f">ftp</a>
+            <a href="/other">4</a>"#;
+        assert_eq!(links(html, "https://example.org/"), ["https://example.org/story?a=1&b=2", "https://example.org/other"]);
+    }
+
+    #[test]
+    fn stops_at_max_depth() {
+        let scope = Scope { max_depth: 2, ..Default::default() };
+        assert_eq!(scoped(&scope, 1, &["https://www.example.org/a"]), ["https://www.example.org/a"]);
+        assert!(scoped(&scope, 2, &["https://www.example.org/a"]).is_empty());
+
+        let parent = Url::parse("https://www.example.org/").unwrap();
+        let found = in_scope(&scope, &parent, 15, 0, &["https://www.example.org/a".into()]);
+        assert_eq!((found[0].depth, found[0].priority), (1, 5));
+        let found = in_scope(&scope, &parent, 5, 1, &["https://www.example.org/a".into()]);
+        assert_eq!((found[0].depth, found[0].priority), (2, 0));
+    }
+
+    #[test]
+    fn same_site_spans_subdomains_of_the_registrable_domain() {
+        let all = [
+            "https://example.org/a",
+            "https://blog.example.org/b",
+            "https://example.org.evil.test/c",
+            "https://other.example/d",
+        ];
+        let scope = Scope::default();
+        assert_eq!(scoped(&scope, 0, &all), ["https://example.org/a", "https://blog.example.org/b"]);
+        let scope = Scope { same_site: false, ..Default::default() };
+        assert_eq!(scoped(&scope, 0, &all), all);
+    }
+
+    #[test]
+    fn skips_assets_and_the_parent_itself() {
+        let found = scoped(
+            &Scope::default(),
+            0,
+            &[
+                "https://www.example.org/news",
+                "https://www.example.org/logo.PNG",
+                "https://www.example.org/data.xlsx",
+                "https://www.example.org/app.js?v=3",
+                "https://www.example.org/report.pdf",
+                "https://www.example.org/json-guide",
+                "not a url",
+            ],
+        );
+        assert_eq!(found, ["https://www.example.org/report.pdf", "https://www.example.org/json-guide"]);
+    }
+
+    #[test]
+    fn per_page_cap_counts_only_in_scope_links() {
+        let scope = Scope { max_links_per_page: 2, ..Default::default() };
+        let found = scoped(
+            &scope,
+            0,
+            &[
+                "https://other.example/1",
+                "https://www.example.org/news",
+                "https://www.example.org/style.css",
+                "https://www.example.org/a",
+                "https://other.example/2",
+                "https://www.example.org/b",
+                "https://www.example.org/c",
+            ],
+        );
+        assert_eq!(found, ["https://www.example.org/a", "https://www.example.org/b"]);
+    }
+}
@@ -1,20 +1,52 @@
-use actix_web::{middleware, post, get, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{middleware, post, get, delete, web, App, HttpResponse, HttpServer, Responder};
 use actix_web::web::Query;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, EnvFilter};
 use tracing_subscriber::util::SubscriberInitExt; // <- needed for .try_init()
 
+mod archive;
+mod breaker;
+mod canon;
+mod charset;
+mod crawl;
+mod egress;
+mod error;
+mod extract;
+mod feeds;
+mod fetch;
+mod links;
+mod meta;
+mod migrate;
+mod pdf;
+mod politeness;
+mod reprocess;
+mod retry;
+mod robots;
 mod scrape;
+mod sitemaps;
 mod store;
 mod types;
+mod warc;
+mod watch;
 
-use crate::scrape::{ScrapeClient, scrape_one};
-use crate::store::{PgPool, init_pool};
-use crate::types::{IngestRequest};
+use crate::archive::Archive;
+use crate::breaker::BreakerConfig;
+use crate::crawl::{CrawlConfig, CrawlState, Crawler};
+use crate::error::{ErrorClass, ScrapeError};
+use crate::links::Scope;
+use crate::pdf::PdfLimits;
+use crate::reprocess::Reprocessor;
+use crate::retry::RetryPolicy;
+use crate::scrape::{BodyLimits, ScrapeClient, ScrapeOutcome, scrape_one};
+use crate::sitemaps::SitemapLimits;
+use crate::store::{DocumentRow, Enqueue, Lease, PgPool, init_pool};
+use crate::types::{Health, IngestRequest};
+use crate::watch::Alerter;
+use url::Url;
 
 #[get("/health")]
 async fn health() -> impl Responder {
-    web::Json(serde_json::json!({ "status": "ok" }))
+    web::Json(Health { status: "ok".into() })
 }
 
 /* ------------------------ /ingest/url ------------------------ */
@@ -24,137 +56,745 @@ async fn ingest_url(
     payload: web::Json<IngestRequest>,
     pg: web::Data<PgPool>,
     sc: web::Data<ScrapeClient>,
+    scope: web::Data<Scope>,
+    alerter: web::Data<Alerter>,
 ) -> actix_web::Result<impl Responder> {
     let req = payload.into_inner();
-    match scrape_one(&sc, &req.url).await {
-        Ok(doc) => {
-            use crate::store::DocumentRow;
-            let row = DocumentRow {
-                url: &doc.url,
-                fetched_at: doc.fetched_at,
-                title: doc.title.as_deref(),
-                description: doc.description.as_deref(),
-                body_text: &doc.body_text,
-                content_type: doc.content_type.as_deref(),
-                http_status: doc.http_status,
-                content_hash: doc.content_hash.as_deref(),
-                lang: doc.lang.as_deref(),
-                etag: doc.etag.as_deref(),
+    match scrape_one(&sc, &req.url, None).await {
+        Ok(ScrapeOutcome::Fetched(doc)) => {
+            let row = DocumentRow::from(&*doc);
+            let version = match store::upsert_document(&pg, &row).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(error=?e, "failed to store document");
+                    return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                        "ok": false, "error": "store_failed"
+                    })));
+                }
             };
-            if let Err(e) = store::upsert_document(&pg, &row).await {
-                error!(error=?e, "failed to store document");
-                return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "ok": false, "error": "store_failed"
-                })));
-            }
+            let alerts = match version {
+                Some(v) => alerter.on_new_version(&pg, &doc.url, v, &doc.body_text).await,
+                None => 0,
+            };
+            // a directly ingested page acts like a seed for link discovery
+            let discovered = crawl::expand_frontier(&pg, &scope, &doc, INGEST_PRIORITY, 0).await;
             Ok(HttpResponse::Ok().json(serde_json::json!({
                 "ok": true,
                 "url": row.url,
                 "title": row.title,
-                "bytes": row.body_text.len()
+                "bytes": row.body_text.len(),
+                "new_version": version,
+                "alerts": alerts,
+                "discovered": discovered
+            })))
+        }
+        // no validators were sent, so scrape_one never reports this here
+        Ok(ScrapeOutcome::NotModified { url, .. }) => {
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "ok": true, "url": url, "not_modified": true
             })))
         }
         Err(e) => {
             error!(error=?e, url=%req.url, "scrape failed");
-            Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "ok": false, "error": e.to_string()
+            let mut res = match (&e, e.class()) {
+                (ScrapeError::BadUrl(_) | ScrapeError::UnsupportedScheme(_), _) => HttpResponse::BadRequest(),
+                (_, ErrorClass::Permanent) => HttpResponse::UnprocessableEntity(),
+                (_, ErrorClass::Transient) => HttpResponse::BadGateway(),
+                (_, ErrorClass::RateLimited) => HttpResponse::ServiceUnavailable(),
+            };
+            if let Some(after) = e.retry_after() {
+                res.insert_header(("Retry-After", after.as_secs().to_string()));
+            }
+            Ok(res.json(serde_json::json!({
+                "ok": false,
+                "error": e.to_string(),
+                "error_code": e.code(),
+                "error_class": e.class(),
+                "http_status": e.http_status(),
             })))
         }
     }
 }
 
+/// Priority given to links found on pages submitted through /ingest/url.
+const INGEST_PRIORITY: i32 = 50;
+
+/* ------------------------ /documents/versions ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct VersionsQ { url: String }
+
+#[get("/documents/versions")]
+async fn list_versions(q: Query<VersionsQ>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match store::list_versions(&pg, &q.url).await {
+        Ok(v) if v.is_empty() => Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown url" }))),
+        Ok(v) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "url": q.url, "versions": v }))),
+        Err(e) => {
+            error!(error=?e, url=%q.url, "version list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DiffQ {
+    url: String,
+    /// Defaults to the version before `to`.
+    from: Option<i32>,
+    /// Defaults to the latest; negative values count back from it.
+    to: Option<i32>,
+    context: Option<usize>,
+}
+
+/// Unified line diff of the extracted text of two versions of a page.
+#[get("/documents/diff")]
+async fn diff_versions(q: Query<DiffQ>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    let to = match store::get_version(&pg, &q.url, q.to.unwrap_or(-1)).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown version" }))),
+        Err(e) => {
+            error!(error=?e, url=%q.url, "version lookup failed");
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })));
+        }
+    };
+    let from = match q.from.unwrap_or(to.version - 1) {
+        0 => None,
+        n => match store::get_version(&pg, &q.url, n).await {
+            Ok(Some(v)) => Some(v),
+            Ok(None) => return Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown version" }))),
+            Err(e) => {
+                error!(error=?e, url=%q.url, "version lookup failed");
+                return Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })));
+            }
+        },
+    };
+
+    // diffing against "version 0" shows the first version as all additions
+    let old_text = from.as_ref().map(|v| v.body_text.as_str()).unwrap_or("");
+    let diff = similar::TextDiff::from_lines(old_text, to.body_text.as_str());
+    let (mut added, mut removed) = (0usize, 0usize);
+    for c in diff.iter_all_changes() {
+        match c.tag() {
+            similar::ChangeTag::Insert => added += 1,
+            similar::ChangeTag::Delete => removed += 1,
+            similar::ChangeTag::Equal => {}
+        }
+    }
+    let label = |v: Option<&store::VersionText>| match v {
+        Some(v) => format!("v{} ({})", v.version, v.fetched_at.to_rfc3339()),
+        None => "v0".to_string(),
+    };
+    let unified = diff
+        .unified_diff()
+        .context_radius(q.context.unwrap_or(3).min(50))
+        .header(&label(from.as_ref()), &label(Some(&to)))
+        .to_string();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "ok": true,
+        "url": q.url,
+        "from": from.as_ref().map(|v| v.version).unwrap_or(0),
+        "to": to.version,
+        "title_from": from.as_ref().and_then(|v| v.title.clone()),
+        "title_to": to.title,
+        "lines_added": added,
+        "lines_removed": removed,
+        "diff": unified,
+    })))
+}
+
+/* ------------------------ /archive ------------------------ */
+
+/// The archived raw body for a content hash, with its original content type.
+#[get("/archive/{content_hash}")]
+async fn archived_body(hash: web::Path<String>, archive: web::Data<Option<Archive>>) -> actix_web::Result<impl Responder> {
+    let Some(archive) = archive.as_ref() else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "archive disabled" })));
+    };
+    match archive.get(&hash).await {
+        Ok(Some(raw)) => {
+            let ct = raw.meta.headers.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+                .map_or("application/octet-stream", |(_, v)| v.as_str());
+            Ok(HttpResponse::Ok()
+                .content_type(ct)
+                .insert_header(("X-Archive-Url", raw.meta.url.clone()))
+                .insert_header(("X-Archive-Fetched-At", raw.meta.fetched_at.to_rfc3339()))
+                .body(raw.body))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "not archived" }))),
+        Err(e) => {
+            error!(error=?e, hash=%hash, "archive read failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+/* ------------------------ /watches ------------------------ */
+
+/// Default share of the extracted text that must change to raise an alert.
+const DEFAULT_MIN_CHANGE: f64 = 0.02;
+/// Queue priority for watched URLs, above every seed.
+const WATCH_PRIORITY: i32 = 100;
+
+#[derive(Debug, serde::Deserialize)]
+struct WatchRequest {
+    /// A URL, or a bare domain that also covers its subdomains.
+    target: String,
+    label: Option<String>,
+    min_change: Option<f64>,
+    webhook_url: Option<String>,
+}
+
+#[post("/watches")]
+async fn add_watch(
+    payload: web::Json<WatchRequest>,
+    pg: web::Data<PgPool>,
+    sc: web::Data<ScrapeClient>,
+) -> actix_web::Result<impl Responder> {
+    let req = payload.into_inner();
+    let bad = |msg: &str| Ok(HttpResponse::BadRequest().json(serde_json::json!({ "ok": false, "error": msg })));
+    let target = req.target.trim();
+    let (kind, target) = match Url::parse(target) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") => ("url", canon::canonical_str(target)),
+        Ok(_) => return bad("bad url"),
+        // not a URL: take it as a domain
+        Err(_) => match Url::parse(&format!("http://{target}/")).ok().and_then(|u| u.host_str().map(str::to_string)) {
+            Some(host) if host.contains('.') => ("domain", host.trim_start_matches("www.").to_string()),
+            _ => return bad("target must be an http(s) URL or a domain"),
+        },
+    };
+    let webhook_url = match &req.webhook_url {
+        Some(hook) => match watch::check_webhook(&sc.egress(), hook).await {
+            Ok(u) => Some(u.to_string()),
+            Err(e) => return bad(&e),
+        },
+        None => None,
+    };
+    let min_change = req.min_change.unwrap_or(DEFAULT_MIN_CHANGE).clamp(0.0, 1.0);
+    let watch = match store::upsert_watch(&pg, kind, &target, req.label.as_deref(), min_change, webhook_url.as_deref()).await {
+        Ok(w) => w,
+        Err(e) => {
+            error!(error=?e, target=%target, "watch insert failed");
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })));
+        }
+    };
+    // make sure a watched page is actually being crawled
+    if kind == "url" {
+        let item = Enqueue { url: &target, priority: WATCH_PRIORITY, depth: 0, discovered_from: None, next_fetch_at: None };
+        if let Err(e) = store::enqueue_many(&pg, &[item]).await {
+            error!(error=?e, url=%target, "watch enqueue failed");
+        }
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "watch": watch })))
+}
+
+#[get("/watches")]
+async fn list_watches(pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match store::list_watches(&pg).await {
+        Ok(w) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "watches": w }))),
+        Err(e) => {
+            error!(error=?e, "watch list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+#[delete("/watches/{id}")]
+async fn delete_watch(id: web::Path<i64>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match store::delete_watch(&pg, *id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true }))),
+        Ok(false) => Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown watch" }))),
+        Err(e) => {
+            error!(error=?e, "watch delete failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AlertsQ { watch_id: Option<i64>, limit: Option<i64> }
+
+#[get("/alerts")]
+async fn list_alerts(q: Query<AlertsQ>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 500);
+    match store::list_alerts(&pg, q.watch_id, limit).await {
+        Ok(a) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "alerts": a }))),
+        Err(e) => {
+            error!(error=?e, "alert list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
 /* ------------------------ /crawl/seed ------------------------ */
 
+const SEEDS: &[(&str, i32)] = &[
+    ("https://www.ipcc.ch/", 100),
+    ("https://www.noaa.gov/climate", 90),
+    ("https://www.climate.gov/news-features", 90),
+    ("https://www.nature.com/subjects/climate-change", 80),
+    ("https://www.nytimes.com/section/climate", 70),
+    ("https://www.theguardian.com/environment/climate-crisis", 70),
+    ("https://www.unep.org/resources", 70),
+    ("https://www.iea.org/topics/climate-change", 70),
+    ("https://www.epa.gov/climate-change", 60),
+    ("https://www.carbonbrief.org/", 90),
+    ("https://www.wri.org/insights", 70),
+    ("https://www.edf.org/climate", 60),
+    ("https://www.bbc.com/news/science_and_environment", 60),
+    ("https://www.nasa.gov/climate/", 80),
+];
+
+/// Feeds of the news seeds above; polled for same-day coverage.
+const FEED_SEEDS: &[(&str, i32)] = &[
+    ("https://www.theguardian.com/environment/climate-crisis/rss", 90),
+    ("https://rss.nytimes.com/services/xml/rss/nyt/Climate.xml", 90),
+    ("https://feeds.bbci.co.uk/news/science_and_environment/rss.xml", 80),
+    ("https://www.carbonbrief.org/feed/", 95),
+];
+
 #[post("/crawl/seed")]
 async fn crawl_seed(pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
-    let seeds: &[(&str, i32)] = &[
-        ("https://www.ipcc.ch/", 100),
-        ("https://www.noaa.gov/climate", 90),
-        ("https://www.climate.gov/news-features", 90),
-        ("https://www.nature.com/subjects/climate-change", 80),
-        ("https://www.nytimes.com/section/climate", 70),
-        ("https://www.theguardian.com/environment/climate-crisis", 70),
-        ("https://www.unep.org/resources", 70),
-        ("https://www.iea.org/topics/climate-change", 70),
-        ("https://www.epa.gov/climate-change", 60),
-        ("https://www.carbonbrief.org/", 90),
-        ("https://www.wri.org/insights", 70),
-        ("https://www.edf.org/climate", 60),
-        ("https://www.bbc.com/news/science_and_environment", 60),
-        ("https://www.nasa.gov/climate/", 80),
-    ];
-    match store::enqueue_many(&pg, seeds).await {
-        Ok(n) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "enqueued": n }))),
+    let items: Vec<Enqueue> = SEEDS
+        .iter()
+        .map(|&(url, priority)| Enqueue { url, priority, depth: 0, discovered_from: None, next_fetch_at: None })
+        .collect();
+    let enqueued = match store::enqueue_many(&pg, &items).await {
+        Ok(n) => n,
         Err(e) => {
             error!(error=?e, "seed enqueue failed");
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })));
+        }
+    };
+    let mut feeds = 0usize;
+    for &(url, priority) in FEED_SEEDS {
+        match store::upsert_feed(&pg, url, priority, DEFAULT_FEED_INTERVAL_SECS).await {
+            Ok(_) => feeds += 1,
+            Err(e) => error!(error=?e, feed=%url, "feed subscribe failed"),
+        }
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "enqueued": enqueued, "feeds": feeds })))
+}
+
+/* ------------------------ /feeds ------------------------ */
+
+const DEFAULT_FEED_PRIORITY: i32 = 90;
+const DEFAULT_FEED_INTERVAL_SECS: i32 = 900;
+
+#[derive(Debug, serde::Deserialize)]
+struct SubscribeRequest {
+    url: String,
+    priority: Option<i32>,
+    poll_interval_secs: Option<i32>,
+}
+
+#[post("/feeds")]
+async fn subscribe_feed(payload: web::Json<SubscribeRequest>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    let req = payload.into_inner();
+    if !Url::parse(&req.url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "ok": false, "error": "bad url" })));
+    }
+    let priority = req.priority.unwrap_or(DEFAULT_FEED_PRIORITY);
+    // don't let a subscription hammer a publisher
+    let interval = req.poll_interval_secs.unwrap_or(DEFAULT_FEED_INTERVAL_SECS).max(60);
+    match store::upsert_feed(&pg, &req.url, priority, interval).await {
+        Ok(feed) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "feed": feed }))),
+        Err(e) => {
+            error!(error=?e, feed=%req.url, "feed subscribe failed");
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
         }
     }
 }
 
-/* ------------------------ /crawl/tick ------------------------ */
+#[get("/feeds")]
+async fn list_feeds(pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match store::list_feeds(&pg).await {
+        Ok(feeds) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "feeds": feeds }))),
+        Err(e) => {
+            error!(error=?e, "feed list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
 
 #[derive(Debug, serde::Deserialize)]
-struct TickQ { batch: Option<i64> }
+struct FeedPollQ { limit: Option<i64> }
 
-#[post("/crawl/tick")]
-async fn crawl_tick(
-    q: Query<TickQ>,
+/// Poll every feed whose interval has elapsed now, without waiting for the
+/// crawl loop's next feed round.
+#[post("/feeds/poll")]
+async fn poll_feeds(
+    q: Query<FeedPollQ>,
     pg: web::Data<PgPool>,
     sc: web::Data<ScrapeClient>,
+    scope: web::Data<Scope>,
 ) -> actix_web::Result<impl Responder> {
-    let batch = q.batch.unwrap_or(25).clamp(1, 200);
-    let mut ok = 0usize;
-    let mut failed = 0usize;
-
-    let items = match store::dequeue_due(&pg, batch).await {
+    let limit = q.limit.unwrap_or(20).clamp(1, 100);
+    let report = match feeds::poll_due(&pg, &sc, &scope, limit).await {
         Ok(v) => v,
         Err(e) => {
-            error!(error=?e, "dequeue failed");
+            error!(error=?e, "feed claim failed");
             return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "ok": false, "error": "dequeue_failed"
+                "ok": false, "error": "claim_failed"
             })));
         }
     };
+    let enqueued: usize = report.iter().map(|r| r.enqueued).sum();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "polled": report.len(), "enqueued": enqueued, "feeds": report })))
+}
 
-    for it in items {
-        match scrape_one(&sc, &it.url).await {
-            Ok(doc) => {
-                use crate::store::DocumentRow;
-                let row = DocumentRow {
-                    url: &doc.url,
-                    fetched_at: doc.fetched_at,
-                    title: doc.title.as_deref(),
-                    description: doc.description.as_deref(),
-                    body_text: &doc.body_text,
-                    content_type: doc.content_type.as_deref(),
-                    http_status: doc.http_status,
-                    content_hash: doc.content_hash.as_deref(),
-                    lang: doc.lang.as_deref(),
-                    etag: doc.etag.as_deref(),
-                };
-                if let Err(e) = store::upsert_document(&pg, &row).await {
-                    error!(error=?e, url=%doc.url, "upsert failed");
-                    let _ = store::reschedule_failure(&pg, it.id, "upsert_failed", 30).await;
-                    failed += 1;
-                    continue;
-                }
-                let _ = store::reschedule_success(&pg, it.id, doc.http_status).await;
-                ok += 1;
-            }
+/* ------------------------ /crawl/sitemaps ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct SitemapQ { origin: Option<String> }
+
+/// Enqueue every URL listed in the sitemaps of one origin (`?origin=`) or,
+/// by default, of all seed origins. Sitemap URLs enter at the scope's max
+/// depth: the sitemap already lists the site, so we don't link-follow them.
+#[post("/crawl/sitemaps")]
+async fn crawl_sitemaps(
+    q: Query<SitemapQ>,
+    pg: web::Data<PgPool>,
+    sc: web::Data<ScrapeClient>,
+    scope: web::Data<Scope>,
+    limits: web::Data<SitemapLimits>,
+) -> actix_web::Result<impl Responder> {
+    let targets: Vec<(String, i32)> = match &q.origin {
+        Some(o) => vec![(o.clone(), INGEST_PRIORITY)],
+        None => SEEDS.iter().map(|&(u, p)| (u.to_string(), p)).collect(),
+    };
+
+    let mut seen = std::collections::HashSet::new();
+    let mut report = Vec::new();
+    for (target, base_priority) in targets {
+        let Ok(url) = Url::parse(&target) else {
+            report.push(serde_json::json!({ "origin": target, "error": "bad url" }));
+            continue;
+        };
+        let origin = url.origin().ascii_serialization();
+        if !seen.insert(origin.clone()) {
+            continue;
+        }
+        let Ok(origin_url) = Url::parse(&origin) else { continue };
+
+        let found = sitemaps::discover(&sc, &origin_url, **limits).await;
+        let now = chrono::Utc::now();
+        let items: Vec<Enqueue> = found.urls.iter().map(|u| Enqueue {
+            url: &u.url,
+            priority: sitemaps::queue_priority(u, base_priority, now),
+            depth: scope.max_depth,
+            discovered_from: Some(&u.sitemap),
+            next_fetch_at: Some(sitemaps::next_fetch_at(u, now)),
+        }).collect();
+        let enqueued = match store::enqueue_many(&pg, &items).await {
+            Ok(n) => n,
             Err(e) => {
-                error!(error=?e, url=%it.url, "scrape failed");
-                let _ = store::reschedule_failure(&pg, it.id, &format!("{e}"), 30).await;
-                failed += 1;
+                error!(error=?e, origin=%origin, "sitemap enqueue failed");
+                0
+            }
+        };
+        report.push(serde_json::json!({
+            "origin": origin,
+            "sitemaps_read": found.sitemaps_read,
+            "urls": found.urls.len(),
+            "enqueued": enqueued,
+            "errors": found.errors,
+        }));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "origins": report })))
+}
+
+/* ------------------------ /crawl/tick ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct TickQ {
+    batch: Option<i64>,
+    concurrency: Option<usize>,
+    /// Return a tick id straight away; poll `GET /crawl/tick/{id}`.
+    #[serde(rename = "async")]
+    run_async: Option<bool>,
+}
+
+#[post("/crawl/tick")]
+async fn crawl_tick(q: Query<TickQ>, crawler: web::Data<Crawler>) -> actix_web::Result<impl Responder> {
+    let batch = q.batch.unwrap_or(25).clamp(1, 200);
+    let concurrency = q.concurrency.unwrap_or(DEFAULT_TICK_CONCURRENCY).clamp(1, 64);
+    let crawler = crawler.into_inner();
+    let refused = |state: CrawlState| {
+        HttpResponse::Conflict().json(serde_json::json!({ "ok": false, "error": "crawler_not_running", "state": state }))
+    };
+    if q.run_async.unwrap_or(false) {
+        return Ok(match crawler.spawn_tick(batch, concurrency) {
+            Ok(id) => HttpResponse::Accepted().json(serde_json::json!({ "ok": true, "tick_id": id })),
+            Err(state) => refused(state),
+        });
+    }
+    // the tick runs detached: if the caller times out and this future is
+    // dropped, leased items still get finished and released
+    let handle = match crawler.start_tick(batch, concurrency) {
+        Ok(h) => h,
+        Err(state) => return Ok(refused(state)),
+    };
+    match handle.await {
+        Ok(Ok(summary)) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "ok": true,
+            "processed_ok": summary.processed_ok,
+            "not_modified": summary.not_modified,
+            "failed": summary.failed,
+            "dead_lettered": summary.dead_lettered,
+            "deferred": summary.deferred,
+            "discovered": summary.discovered,
+            "elapsed_ms": summary.elapsed_ms,
+            "items": summary.items
+        }))),
+        Ok(Err(e)) => {
+            error!(error=?e, "dequeue failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "ok": false, "error": "dequeue_failed"
+            })))
+        }
+        Err(e) => {
+            error!(error=?e, "tick panicked");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "ok": false, "error": "tick_failed"
+            })))
+        }
+    }
+}
+
+/// Items scraped at once within one tick; per-host limits still apply.
+const DEFAULT_TICK_CONCURRENCY: usize = 8;
+
+#[get("/crawl/tick/{id}")]
+async fn tick_status(id: web::Path<String>, crawler: web::Data<Crawler>) -> impl Responder {
+    match crawler.tick(&id) {
+        Some(rec) => HttpResponse::Ok().json(serde_json::json!({ "ok": true, "tick": rec })),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown tick" })),
+    }
+}
+
+/* ------------------------ /admin/crawler ------------------------ */
+
+#[get("/admin/crawler")]
+async fn crawler_status(crawler: web::Data<Crawler>) -> impl Responder {
+    HttpResponse::Ok().json(crawler.status())
+}
+
+#[post("/admin/crawler/pause")]
+async fn crawler_pause(crawler: web::Data<Crawler>) -> impl Responder {
+    crawler.pause();
+    HttpResponse::Ok().json(crawler.status())
+}
+
+#[post("/admin/crawler/resume")]
+async fn crawler_resume(crawler: web::Data<Crawler>) -> impl Responder {
+    crawler.resume();
+    HttpResponse::Ok().json(crawler.status())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DrainQ { wait_secs: Option<u64> }
+
+/// Stop leasing and let in-flight items finish; `?wait_secs=` blocks until
+/// that happens (or the wait runs out) before answering.
+#[post("/admin/crawler/drain")]
+async fn crawler_drain(q: Query<DrainQ>, crawler: web::Data<Crawler>) -> impl Responder {
+    crawler.drain();
+    if let Some(secs) = q.wait_secs {
+        crawler.wait_drained(std::time::Duration::from_secs(secs.min(300))).await;
+    }
+    HttpResponse::Ok().json(crawler.status())
+}
+
+/* ------------------------ /admin/domains ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct DomainsQ { circuit: Option<String>, limit: Option<i64> }
+
+/// Per-host health, least healthy first; `?circuit=open|half_open|closed`.
+#[get("/admin/domains")]
+async fn list_domains(q: Query<DomainsQ>, crawler: web::Data<Crawler>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    crawler.flush_domains().await;
+    let limit = q.limit.unwrap_or(100).clamp(1, 1000);
+    match store::list_domain_states(&pg, q.circuit.as_deref(), limit).await {
+        Ok(domains) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "domains": domains }))),
+        Err(e) => {
+            error!(error=?e, "domain state list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+/// One host's health, including its cached robots.txt.
+#[get("/admin/domains/{host}")]
+async fn get_domain(path: web::Path<String>, crawler: web::Data<Crawler>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    crawler.flush_domains().await;
+    match store::get_domain_state(&pg, &path.into_inner().to_ascii_lowercase()).await {
+        Ok(Some(d)) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "domain": d }))),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown host" }))),
+        Err(e) => {
+            error!(error=?e, "domain state lookup failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+/// Close a host's circuit now instead of waiting out the cooldown.
+#[post("/admin/domains/{host}/reset")]
+async fn reset_domain(path: web::Path<String>, sc: web::Data<ScrapeClient>, crawler: web::Data<Crawler>) -> impl Responder {
+    let host = path.into_inner().to_ascii_lowercase();
+    if !sc.health().reset(&host) {
+        return HttpResponse::NotFound().json(serde_json::json!({ "ok": false, "error": "unknown host" }));
+    }
+    crawler.flush_domains().await;
+    HttpResponse::Ok().json(serde_json::json!({ "ok": true, "host": host }))
+}
+
+/* ------------------------ /crawl/dead ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct DeadQ { error_code: Option<String>, limit: Option<i64> }
+
+#[get("/crawl/dead")]
+async fn list_dead(q: Query<DeadQ>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    let limit = q.limit.unwrap_or(100).clamp(1, 1000);
+    match store::list_dead(&pg, q.error_code.as_deref(), limit).await {
+        Ok(items) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "items": items }))),
+        Err(e) => {
+            error!(error=?e, "dead-letter list failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+/// Give dead-lettered items (optionally only one `?error_code=`) another go.
+#[post("/crawl/dead/requeue")]
+async fn requeue_dead(q: Query<DeadQ>, pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match store::requeue_dead(&pg, q.error_code.as_deref()).await {
+        Ok(n) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "requeued": n }))),
+        Err(e) => {
+            error!(error=?e, "dead-letter requeue failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false })))
+        }
+    }
+}
+
+/* ------------------------ /admin/migrations ------------------------ */
+
+#[get("/admin/migrations")]
+async fn migrations_status(pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match migrate::status(&pg).await {
+        Ok(list) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "migrations": list }))),
+        Err(e) => {
+            error!(error=?e, "migration status failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false, "error": e.to_string() })))
+        }
+    }
+}
+
+#[post("/admin/migrations/apply")]
+async fn migrations_apply(pg: web::Data<PgPool>) -> actix_web::Result<impl Responder> {
+    match migrate::apply_pending(&pg).await {
+        Ok(applied) => Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true, "applied": applied }))),
+        Err(e) => {
+            error!(error=?e, "migration failed");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({ "ok": false, "error": format!("{e:#}") })))
+        }
+    }
+}
+
+/* ------------------------ /admin/reprocess ------------------------ */
+
+#[derive(Debug, serde::Deserialize)]
+struct ReprocessQ { batch: Option<i64>, limit: Option<usize> }
+
+#[get("/admin/reprocess")]
+async fn reprocess_status(rp: web::Data<Reprocessor>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "ok": true, "job": rp.status() }))
+}
+
+/// Start re-extracting outdated documents from the raw archive in the
+/// background; poll `GET /admin/reprocess` for progress.
+#[post("/admin/reprocess")]
+async fn reprocess_start(q: Query<ReprocessQ>, rp: web::Data<Reprocessor>) -> impl Responder {
+    let batch = q.batch.unwrap_or(DEFAULT_REPROCESS_BATCH).clamp(1, 1000);
+    match rp.into_inner().start(batch, q.limit) {
+        Ok(()) => HttpResponse::Accepted().json(serde_json::json!({ "ok": true })),
+        Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "ok": false, "error": e.to_string() })),
+    }
+}
+
+const DEFAULT_REPROCESS_BATCH: i64 = 100;
+
+/// `worker-rust migrate [status|up]`: run migrations without starting the server.
+async fn migrate_cli(pool: &PgPool, cmd: Option<&str>) -> anyhow::Result<()> {
+    match cmd.unwrap_or("status") {
+        "status" => {
+            for m in migrate::status(pool).await? {
+                let at = m.applied_at.map(|t| t.to_rfc3339()).unwrap_or_default();
+                println!("{:>4}  {:<32} {:<18} {}", m.version, m.name, format!("{:?}", m.state), at);
             }
         }
+        "up" => {
+            let applied = migrate::apply_pending(pool).await?;
+            println!("applied {} migration(s): {:?}", applied.len(), applied);
+        }
+        other => anyhow::bail!("unknown migrate command {other:?}; expected status or up"),
     }
+    Ok(())
+}
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "ok": true,
-        "processed_ok": ok,
-        "failed": failed
-    })))
+/// `worker-rust archive export <file.warc.gz> [url-prefix] [since]` and
+/// `worker-rust archive import <file.warc[.gz]>`.
+async fn archive_cli(pool: &PgPool, pdf_limits: PdfLimits, args: &[String]) -> anyhow::Result<()> {
+    let archive = Archive::from_env(pool)?
+        .ok_or_else(|| anyhow::anyhow!("set ARCHIVE=disk|postgres (and ARCHIVE_DIR) to pick the archive"))?;
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("export"), Some(path)) => {
+            let since = match args.get(3) {
+                Some(s) => Some(meta::parse_date(s).ok_or_else(|| anyhow::anyhow!("bad date {s:?}"))?),
+                None => None,
+            };
+            let report = archive::export_warc(pool, &archive, path.as_ref(), args.get(2).map(String::as_str), since).await?;
+            println!("{}", serde_json::to_string(&report)?);
+        }
+        (Some("import"), Some(path)) => {
+            let report = archive::import_warc(pool, &archive, path.as_ref(), pdf_limits).await?;
+            println!("{}", serde_json::to_string(&report)?);
+        }
+        _ => anyhow::bail!("usage: archive export <file> [url-prefix] [since] | archive import <file>"),
+    }
+    Ok(())
+}
+
+/// `worker-rust reprocess [batch] [limit]`: re-extract outdated documents
+/// from the raw archive, printing progress after every batch.
+async fn reprocess_cli(pool: &PgPool, pdf_limits: PdfLimits, args: &[String]) -> anyhow::Result<()> {
+    let batch = match args.first() {
+        Some(b) => b.parse().map_err(|_| anyhow::anyhow!("bad batch size {b:?}"))?,
+        None => DEFAULT_REPROCESS_BATCH,
+    };
+    let limit = match args.get(1) {
+        Some(l) => Some(l.parse().map_err(|_| anyhow::anyhow!("bad limit {l:?}"))?),
+        None => None,
+    };
+    let rp = Reprocessor::new(pool.clone(), Archive::from_env(pool)?, pdf_limits);
+    let report = rp
+        .run(batch.max(1), limit, &|p| {
+            eprintln!(
+                "{}/{} scanned, {} updated, {} versions updated, {} missing raw, {} failed",
+                p.scanned, p.total, p.updated, p.versions_updated, p.missing_raw, p.failed
+            );
+        })
+        .await?;
+    println!("{}", serde_json::to_string(&report)?);
+    Ok(())
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
 }
 
 #[actix_web::main]
@@ -166,6 +806,9 @@ async fn main() -> std::io::Result<()> {
         .try_init();
 
     // Config
+    // before anything canonicalizes a URL, CLI subcommands included
+    let strip_params = std::env::var("CANON_STRIP_PARAMS").unwrap_or_default();
+    canon::init(strip_params.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()));
     let addr  = std::env::var("WORKER_BIND").unwrap_or_else(|_| "127.0.0.1:5002".into());
     let pg_url = std::env::var("PG_URL").expect("PG_URL not set");
 
@@ -173,25 +816,170 @@ async fn main() -> std::io::Result<()> {
     let pool = init_pool(&pg_url).await.expect("pg pool init failed");
     info!("✅ connected to Postgres");
 
-    let sc = ScrapeClient::new(
-        "ClimateImpactBot/1.0 (+https://codered.plobethus.com)",
-        2,
-        std::time::Duration::from_millis(400),
-    );
+    let pdf_limits = PdfLimits {
+        max_bytes: env_or("PDF_MAX_BYTES", PdfLimits::default().max_bytes),
+        max_pages: env_or("PDF_MAX_PAGES", PdfLimits::default().max_pages),
+    };
+
+    let args: Vec<String> = std::env::args().collect();
+    let cli = match args.get(1).map(String::as_str) {
+        Some("migrate") => Some(migrate_cli(&pool, args.get(2).map(String::as_str)).await),
+        Some("archive") => Some(archive_cli(&pool, pdf_limits, &args[2..]).await),
+        Some("reprocess") => Some(reprocess_cli(&pool, pdf_limits, &args[2..]).await),
+        _ => None,
+    };
+    if let Some(res) = cli {
+        if let Err(e) = res {
+            eprintln!("{}: {e:#}", args[1]);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // On by default so a fresh database works without a separate step, as it
+    // did when startup created the tables itself; concurrent starts serialise
+    // on the runner's advisory lock. Set MIGRATE_ON_START=false where schema
+    // changes are rolled out separately (docs/setup.md).
+    if env_or("MIGRATE_ON_START", true) {
+        migrate::apply_pending(&pool).await.expect("schema migration failed");
+    } else {
+        let pending = migrate::status(&pool).await.expect("migration status failed")
+            .into_iter()
+            .filter(|m| matches!(m.state, migrate::MigrationState::Pending | migrate::MigrationState::ChecksumMismatch))
+            .count();
+        if pending > 0 {
+            tracing::warn!(pending, "schema is not up to date; run `worker-rust migrate up`");
+        }
+    }
+
+    let crawl_cfg = CrawlConfig {
+        user_agent: "ClimateImpactBot/1.0 (+https://codered.plobethus.com)".into(),
+        per_domain_concurrency: env_or("CRAWL_PER_DOMAIN", 2),
+        per_domain_delay_ms: env_or("CRAWL_DELAY_MS", 400),
+        max_tasks: env_or("CRAWL_MAX_TASKS", 8),
+        robots_ttl_secs: env_or("ROBOTS_TTL_SECS", 24 * 3600),
+        idle_poll_secs: env_or("CRAWL_IDLE_SECS", 30),
+        feed_poll_secs: env_or("FEED_POLL_SECS", 60),
+        autostart: env_or("CRAWLER_AUTOSTART", true),
+    };
+
+    let archive = Archive::from_env(&pool).expect("archive config");
+    if archive.is_some() {
+        info!("raw response archive enabled");
+    }
+
+    let sc = crawl_cfg.scrape_client()
+    .with_archive(archive.clone())
+    .with_pdf_limits(pdf_limits)
+    .with_body_limits(BodyLimits {
+        html: env_or("HTML_MAX_BYTES", BodyLimits::default().html),
+        xml: env_or("XML_MAX_BYTES", BodyLimits::default().xml),
+        other: env_or("FETCH_MAX_BYTES", BodyLimits::default().other),
+    })
+    .with_breaker(BreakerConfig {
+        failures: env_or("BREAKER_FAILURES", BreakerConfig::default().failures).max(1),
+        cooldown: std::time::Duration::from_secs(env_or("BREAKER_COOLDOWN_SECS", BreakerConfig::default().cooldown.as_secs())),
+    })
+    .with_egress(egress::EgressPolicy::from_env().expect("egress policy config"));
+    // FETCH_MODE=replay serves recorded fixtures instead of the network
+    let fetcher = fetch::from_env(&sc.http, sc.egress()).expect("fetch mode config");
+    let sc = sc.with_fetcher(fetcher);
+
+    let scope = Scope {
+        max_depth: env_or("CRAWL_MAX_DEPTH", Scope::default().max_depth),
+        same_site: env_or("CRAWL_SAME_SITE", Scope::default().same_site),
+        max_links_per_page: env_or("CRAWL_MAX_LINKS_PER_PAGE", Scope::default().max_links_per_page),
+    };
+
+    let sitemap_limits = SitemapLimits {
+        max_depth: env_or("SITEMAP_MAX_DEPTH", SitemapLimits::default().max_depth),
+        max_sitemaps: env_or("SITEMAP_MAX_FILES", SitemapLimits::default().max_sitemaps),
+        max_urls: env_or("SITEMAP_MAX_URLS", SitemapLimits::default().max_urls),
+        max_bytes: SitemapLimits::default().max_bytes,
+    };
+
+    let retry = RetryPolicy {
+        base: std::time::Duration::from_secs(env_or("RETRY_BASE_SECS", RetryPolicy::default().base.as_secs())),
+        max_backoff: std::time::Duration::from_secs(env_or("RETRY_MAX_BACKOFF_SECS", RetryPolicy::default().max_backoff.as_secs())),
+        max_attempts: env_or("RETRY_MAX_ATTEMPTS", RetryPolicy::default().max_attempts),
+        jitter: RetryPolicy::default().jitter,
+    };
 
+    // default is unique per process; set WORKER_ID to something stable per deployment
+    let lease = Lease {
+        owner: std::env::var("WORKER_ID").unwrap_or_else(|_| {
+            let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".into());
+            format!("{host}:{}", std::process::id())
+        }),
+        ttl: std::time::Duration::from_secs(env_or("LEASE_SECS", 15 * 60)),
+    };
+    info!(worker_id=%lease.owner, "queue lease owner");
+
+    let alerter = Alerter::new(sc.http.clone(), std::env::var("ALERT_WEBHOOK_URL").ok().filter(|s| !s.is_empty()));
+
+    let crawler = web::Data::new(Crawler::new(sc.clone(), crawl_cfg, pool.clone(), scope.clone(), retry, lease, alerter.clone()));
+    match crawler.restore_domains().await {
+        Ok(n) if n > 0 => info!(hosts = n, "restored unhealthy hosts from domain_state"),
+        Ok(_) => {}
+        Err(e) => warn!(error=?e, "loading domain_state failed"),
+    }
+    tokio::spawn(crawler.clone().into_inner().supervise());
+    tokio::spawn(crawler.clone().into_inner().keep_leases());
+
+    let reprocessor = web::Data::new(Reprocessor::new(pool.clone(), archive.clone(), pdf_limits));
+
+    let app_crawler = crawler.clone();
     info!("🌐 worker listening on {}", addr);
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(sc.clone()))
+            .app_data(web::Data::new(scope.clone()))
+            .app_data(web::Data::new(sitemap_limits))
+            .app_data(web::Data::new(alerter.clone()))
+            .app_data(web::Data::new(archive.clone()))
+            .app_data(app_crawler.clone())
+            .app_data(reprocessor.clone())
             .wrap(middleware::Logger::default())
             .service(health)
             .service(ingest_url)   // <- now in scope
+            .service(list_versions)
+            .service(diff_versions)
+            .service(archived_body)
+            .service(add_watch)
+            .service(list_watches)
+            .service(delete_watch)
+            .service(list_alerts)
             .service(crawl_seed)
+            .service(crawl_sitemaps)
             .service(crawl_tick)
+            .service(tick_status)
+            .service(list_dead)
+            .service(requeue_dead)
+            .service(crawler_status)
+            .service(crawler_pause)
+            .service(crawler_resume)
+            .service(crawler_drain)
+            .service(list_domains)
+            .service(get_domain)
+            .service(reset_domain)
+            .service(migrations_status)
+            .service(migrations_apply)
+            .service(reprocess_status)
+            .service(reprocess_start)
+            .service(subscribe_feed)
+            .service(list_feeds)
+            .service(poll_feeds)
     })
     .bind(addr)?
     .workers(2)
     .run()
-    .await
+    .await?;
+
+    // let leased items finish instead of waiting out their lease on restart
+    crawler.drain();
+    if !crawler.wait_drained(std::time::Duration::from_secs(env_or("CRAWL_DRAIN_SECS", 30))).await {
+        info!("crawler still busy at shutdown; remaining leases will expire");
+    }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,415 @@
+//! Structured page metadata: OpenGraph/Twitter/`article:*` meta tags,
+//! `application/ld+json` blocks and schema.org microdata. Typed fields are
+//! resolved in that order of trust (JSON-LD first); everything found is also
+//! kept as a raw JSON blob.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use scraper::{ElementRef, Html, Selector};
+use serde_json::{json, Map, Value};
+use url::Url;
+
+/// schema.org types we read article metadata from.
+const ARTICLE_TYPES: &[&str] = &[
+    "Article", "NewsArticle", "ReportageNewsArticle", "AnalysisNewsArticle", "BlogPosting",
+    "ScholarlyArticle", "TechArticle", "Report", "WebPage", "Dataset",
+];
+
+const ORG_TYPES: &[&str] = &["Organization", "NewsMediaOrganization", "GovernmentOrganization", "NGO"];
+
+/// Non-standard `<meta name>`s publishers use for the publish date.
+const DATE_META_NAMES: &[&str] = &[
+    "date", "pubdate", "publishdate", "publish-date", "dc.date", "dc.date.issued", "dcterms.created",
+    "dcterms.date", "citation_publication_date", "citation_date", "parsely-pub-date", "sailthru.date",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct PageMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub publisher: Option<String>,
+    pub section: Option<String>,
+    pub image_url: Option<String>,
+    /// `{ "meta": {..}, "json_ld": [..], "microdata": [..] }`
+    pub raw: Value,
+}
+
+pub fn extract(doc: &Html, page_url: &Url) -> PageMeta {
+    let meta = meta_tags(doc);
+    let ld = json_ld(doc);
+    let micro = microdata(doc);
+
+    let ld_article = ld.iter().flat_map(flatten).find(|v| has_type(v, ARTICLE_TYPES));
+    let ld_org = ld.iter().flat_map(flatten).find(|v| has_type(v, ORG_TYPES));
+    let micro_article = micro.iter().find(|v| has_type(v, ARTICLE_TYPES));
+
+    let m = |k: &str| meta.get(k).and_then(Value::as_str).map(str::to_string);
+    let ld_str = |k: &str| ld_article.and_then(|a| a.get(k)).and_then(first_text);
+    let md_str = |k: &str| micro_article.and_then(|a| a.get(k)).and_then(first_text);
+
+    let published_at = ld_str("datePublished").as_deref().and_then(parse_date)
+        .or_else(|| m("article:published_time").as_deref().and_then(parse_date))
+        .or_else(|| md_str("datePublished").as_deref().and_then(parse_date))
+        .or_else(|| DATE_META_NAMES.iter().find_map(|n| m(n).as_deref().and_then(parse_date)))
+        .or_else(|| ld_str("dateCreated").as_deref().and_then(parse_date));
+
+    // article:author is usually a profile URL, not a name
+    let author = ld_article.and_then(|a| a.get("author")).and_then(names)
+        .or_else(|| m("author"))
+        .or_else(|| m("article:author").filter(|a| !a.starts_with("http")))
+        .or_else(|| micro_article.and_then(|a| a.get("author")).and_then(names));
+
+    let publisher = ld_article.and_then(|a| a.get("publisher")).and_then(names)
+        .or_else(|| m("og:site_name"))
+        .or_else(|| micro_article.and_then(|a| a.get("publisher")).and_then(names))
+        .or_else(|| ld_org.and_then(|o| o.get("name")).and_then(first_text));
+
+    let section = ld_str("articleSection")
+        .or_else(|| m("article:section"))
+        .or_else(|| md_str("articleSection"));
+
+    let image_url = m("og:image:secure_url")
+        .or_else(|| m("og:image"))
+        .or_else(|| m("twitter:image"))
+        .or_else(|| ld_article.and_then(|a| a.get("image")).and_then(image))
+        .or_else(|| micro_article.and_then(|a| a.get("image")).and_then(image))
+        .and_then(|i| page_url.join(&i).ok())
+        .filter(|u| matches!(u.scheme(), "http" | "https"))
+        .map(String::from);
+
+    PageMeta {
+        title: m("og:title").or_else(|| m("twitter:title")).or_else(|| ld_str("headline")),
+        description: m("og:description").or_else(|| m("twitter:description")).or_else(|| ld_str("description")),
+        published_at,
+        author,
+        publisher,
+        section,
+        image_url,
+        raw: json!({ "meta": meta, "json_ld": ld, "microdata": micro }),
+    }
+}
+
+/* ------------------------ sources ------------------------ */
+
+/// `og:*`, `twitter:*`, `article:*` plus named metas, keyed lowercase. First
+/// value wins except for a few repeatable keys, which are joined.
+fn meta_tags(doc: &Html) -> Map<String, Value> {
+    let sel = Selector::parse("meta[content]").unwrap();
+    let mut out = Map::new();
+    for el in doc.select(&sel) {
+        let e = el.value();
+        let Some(key) = e.attr("property").or(e.attr("name")).or(e.attr("itemprop")) else { continue };
+        let key = key.trim().to_ascii_lowercase();
+        let content = e.attr("content").unwrap_or("").trim();
+        if content.is_empty() {
+            continue;
+        }
+        let keep = key.starts_with("og:") || key.starts_with("twitter:") || key.starts_with("article:")
+            || key == "author" || key == "keywords" || key == "news_keywords" || DATE_META_NAMES.contains(&key.as_str());
+        if !keep {
+            continue;
+        }
+        match out.get_mut(&key) {
+            None => {
+                out.insert(key, Value::String(content.to_string()));
+            }
+            Some(Value::String(prev)) if matches!(key.as_str(), "author" | "article:tag") => {
+                prev.push_str(", ");
+                prev.push_str(content);
+            }
+            Some(_) => {}
+        }
+    }
+    out
+}
+
+/// Every parseable `<script type="application/ld+json">` block, as-is.
+fn json_ld(doc: &Html) -> Vec<Value> {
+    let sel = Selector::parse(r#"script[type="application/ld+json" i]"#).unwrap();
+    doc.select(&sel)
+        .filter_map(|s| {
+            let text: String = s.text().collect();
+            // CMSes sometimes leave raw newlines inside strings, which JSON forbids
+            serde_json::from_str(text.trim()).ok()
+                .or_else(|| serde_json::from_str(&text.replace(['\n', '\r', '\t'], " ")).ok())
+        })
+        .collect()
+}
+
+/// Top-level schema.org microdata items as JSON-LD-shaped objects.
+fn microdata(doc: &Html) -> Vec<Value> {
+    let sel = Selector::parse("[itemscope][itemtype]").unwrap();
+    doc.select(&sel)
+        .filter(|el| el.value().attr("itemprop").is_none())
+        .map(item)
+        .collect()
+}
+
+fn item(scope: ElementRef) -> Value {
+    let mut obj = Map::new();
+    if let Some(t) = scope.value().attr("itemtype") {
+        let types: Vec<Value> = t.split_whitespace()
+            .map(|u| Value::String(u.rsplit('/').next().unwrap_or(u).to_string()))
+            .collect();
+        obj.insert("@type".into(), Value::Array(types));
+    }
+    collect_props(scope, &mut obj);
+    Value::Object(obj)
+}
+
+/// Properties belonging to `node`'s item; nested items become nested objects
+/// and are not searched for this item's properties.
+fn collect_props(node: ElementRef, obj: &mut Map<String, Value>) {
+    for child in node.children().filter_map(ElementRef::wrap) {
+        let e = child.value();
+        let nested = e.attr("itemscope").is_some();
+        if let Some(props) = e.attr("itemprop") {
+            let value = if nested { item(child) } else { prop_value(child) };
+            for p in props.split_whitespace() {
+                obj.entry(p.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+        if !nested {
+            collect_props(child, obj);
+        }
+    }
+}
+
+fn prop_value(el: ElementRef) -> Value {
+    let e = el.value();
+    let attr = match e.name() {
+        "meta" => e.attr("content"),
+        "time" => e.attr("datetime"),
+        "a" | "link" | "area" => e.attr("href"),
+        "img" | "audio" | "video" | "source" | "iframe" | "embed" => e.attr("src"),
+        "data" | "meter" => e.attr("value"),
+        _ => e.attr("content"),
+    };
+    let text = match attr {
+        Some(a) => a.trim().to_string(),
+        None => el.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" "),
+    };
+    Value::String(text)
+}
+
+/* ------------------------ JSON helpers ------------------------ */
+
+/// A JSON-LD value and every object nested under `@graph` or in arrays.
+fn flatten(v: &Value) -> Vec<&Value> {
+    match v {
+        Value::Array(items) => items.iter().flat_map(flatten).collect(),
+        Value::Object(o) => {
+            let mut out = vec![v];
+            if let Some(g) = o.get("@graph") {
+                out.extend(flatten(g));
+            }
+            out
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn has_type(v: &Value, wanted: &[&str]) -> bool {
+    let matches = |t: &Value| t.as_str().is_some_and(|s| wanted.contains(&s.rsplit('/').next().unwrap_or(s)));
+    match v.get("@type") {
+        Some(Value::Array(ts)) => ts.iter().any(matches),
+        Some(t) => matches(t),
+        None => false,
+    }
+}
+
+fn first_text(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.trim().to_string()).filter(|s| !s.is_empty()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Array(items) => items.iter().find_map(first_text),
+        _ => None,
+    }
+}
+
+/// Person/Organization values: a name string, an object with `name`, or a
+/// list of either. Multiple names are comma-joined.
+fn names(v: &Value) -> Option<String> {
+    let list: Vec<String> = match v {
+        Value::Array(items) => items.iter().filter_map(names).collect(),
+        Value::Object(o) => o.get("name").and_then(first_text).into_iter().collect(),
+        other => first_text(other).into_iter().collect(),
+    };
+    (!list.is_empty()).then(|| list.join(", "))
+}
+
+fn image(v: &Value) -> Option<String> {
+    match v {
+        Value::Array(items) => items.iter().find_map(image),
+        Value::Object(o) => o.get("url").or_else(|| o.get("contentUrl")).and_then(first_text),
+        other => first_text(other),
+    }
+}
+
+/* ------------------------ dates ------------------------ */
+
+/// ISO 8601 / RFC 3339 with or without offset, bare dates, and RFC 2822.
+/// Offset-less values are taken as UTC.
+pub fn parse_date(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        return Some(d.with_timezone(&Utc));
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%dT%H:%M%z", "%Y-%m-%d %H:%M:%S%.f%z"] {
+        if let Ok(d) = DateTime::parse_from_str(s, fmt) {
+            return Some(d.with_timezone(&Utc));
+        }
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M"] {
+        if let Ok(d) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Some(d.and_utc());
+        }
+    }
+    // the date part alone, so a time in a shape none of the above knows doesn't lose the day
+    for (fmt, width) in [("%Y-%m-%d", 10), ("%Y%m%d", 8), ("%Y/%m/%d", 10)] {
+        if let Ok(d) = NaiveDate::parse_from_str(s.get(..width.min(s.len())).unwrap_or(s), fmt) {
+            return d.and_hms_opt(0, 0, 0).map(|d| d.and_utc());
+        }
+    }
+    DateTime::parse_from_rfc2822(s).ok().map(|d| d.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(html: &str) -> PageMeta {
+        extract(&Html::parse_document(html), &Url::parse("https://news.example/2024/story").unwrap())
+    }
+
+    #[test]
+    fn json_ld_graph_with_author_objects() {
+        let m = meta(r#"<head><script type="application/ld+json">
+            {"@context": "https://schema.org", "@graph": [
+              {"@type": "WebSite", "name": "News Example"},
+              {"@type": ["NewsArticle"], "headline": "Dam report published",
+               "datePublished": "2024-03-05T09:30:00+01:00",
+               "author": [{"@type": "Person", "name": "Ana Lima"}, {"@type": "Person", "name": " Bo Chen "}, {"@type": "Person"}],
+               "publisher": {"@type": "NewsMediaOrganization", "name": "News Example Ltd"},
+               "articleSection": ["Environment", "Water"],
+               "image": [{"@type": "ImageObject", "url": "/img/dam.jpg"}]}
+            ]}
+            </script></head><body></body>"#);
+        assert_eq!(m.title.as_deref(), Some("Dam report published"));
+        assert_eq!(m.published_at.unwrap().to_rfc3339(), "2024-03-05T08:30:00+00:00");
+        assert_eq!(m.author.as_deref(), Some("Ana Lima, Bo Chen"));
+        assert_eq!(m.publisher.as_deref(), Some("News Example Ltd"));
+        assert_eq!(m.section.as_deref(), Some("Environment"));
+        assert_eq!(m.image_url.as_deref(), Some("https://news.example/img/dam.jpg"));
+        assert_eq!(m.raw["json_ld"][0]["@graph"][1]["headline"], "Dam report published");
+    }
+
+    #[test]
+    fn opengraph_and_named_metas_fill_in_without_json_ld() {
+        let m = meta(r#"<head>
+            <meta property="og:title" content="Heat records">
+            <meta property="og:description" content=" Hottest May on record ">
+            <meta property="og:site_name" content="News Example">
+            <meta property="og:image" content="/img/heat.png">
+            <meta property="article:published_time" content="2024-06-01T06:00:00Z">
+            <meta property="article:author" content="https://news.example/staff/ana">
+            <meta property="article:section" content="Climate">
+            <meta name="author" content="Ana Lima">
+            <meta name="author" content="Bo Chen">
+            <meta name="viewport" content="width=device-width">
+            </head><body></body>"#);
+        assert_eq!(m.title.as_deref(), Some("Heat records"));
+        assert_eq!(m.description.as_deref(), Some("Hottest May on record"));
+        assert_eq!(m.published_at.unwrap().to_rfc3339(), "2024-06-01T06:00:00+00:00");
+        assert_eq!(m.author.as_deref(), Some("Ana Lima, Bo Chen"));
+        assert_eq!(m.publisher.as_deref(), Some("News Example"));
+        assert_eq!(m.section.as_deref(), Some("Climate"));
+        assert_eq!(m.image_url.as_deref(), Some("https://news.example/img/heat.png"));
+        assert!(m.raw["meta"].get("viewport").is_none());
+
+        // a profile URL is not a name
+        let m = meta(r#"<meta property="article:author" content="https://news.example/staff/ana">"#);
+        assert_eq!(m.author, None);
+        let m = meta(r#"<meta name="DC.date.issued" content="2024-02-29">"#);
+        assert_eq!(m.published_at.unwrap().to_rfc3339(), "2024-02-29T00:00:00+00:00");
+    }
+
+    #[test]
+    fn json_ld_wins_over_meta_tags() {
+        let m = meta(r#"<head>
+            <meta property="article:published_time" content="2024-06-02T00:00:00Z">
+            <meta name="author" content="Meta Author">
+            <meta property="og:site_name" content="Meta Publisher">
+            <meta property="article:section" content="Meta Section">
+            <script type="application/ld+json">{"@type": "Article", "datePublished": "2024-06-01",
+              "author": "LD Author", "publisher": {"name": "LD Publisher"}, "articleSection": "LD Section"}</script>
+            </head><body></body>"#);
+        assert_eq!(m.published_at.unwrap().to_rfc3339(), "2024-06-01T00:00:00+00:00");
+        assert_eq!(m.author.as_deref(), Some("LD Author"));
+        assert_eq!(m.publisher.as_deref(), Some("LD Publisher"));
+        assert_eq!(m.section.as_deref(), Some("LD Section"));
+    }
+
+    #[test]
+    fn microdata_nested_items_keep_their_own_props() {
+        let m = meta(r#"<body>
+            <article itemscope itemtype="https://schema.org/NewsArticle">
+              <h1 itemprop="headline">Flood defences</h1>
+              <time itemprop="datePublished" datetime="2024-04-10T12:00:00Z">10 April</time>
+              <div itemprop="author" itemscope itemtype="https://schema.org/Person">
+                <span itemprop="name">Ana   Lima</span>
+                <a itemprop="url" href="/staff/ana">profile</a>
+              </div>
+              <div itemprop="publisher" itemscope itemtype="https://schema.org/Organization">
+                <meta itemprop="name" content="News Example">
+              </div>
+              <img itemprop="image" src="/img/flood.jpg">
+            </article></body>"#);
+        assert_eq!(m.published_at.unwrap().to_rfc3339(), "2024-04-10T12:00:00+00:00");
+        assert_eq!(m.author.as_deref(), Some("Ana Lima"));
+        assert_eq!(m.publisher.as_deref(), Some("News Example"));
+        assert_eq!(m.image_url.as_deref(), Some("https://news.example/img/flood.jpg"));
+
+        let article = &m.raw["microdata"][0];
+        assert_eq!(article["@type"], json!(["NewsArticle"]));
+        assert_eq!(article["headline"], "Flood defences");
+        assert_eq!(article["author"]["url"], "/staff/ana");
+        // the Person's props don't leak into the article
+        assert!(article.get("name").is_none() && article.get("url").is_none());
+        assert_eq!(m.raw["microdata"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn date_formats() {
+        let cases = [
+            // RFC 3339
+            ("2024-03-05T09:30:00+01:00", Some("2024-03-05T08:30:00+00:00")),
+            ("2024-03-05T09:30:00.123Z", Some("2024-03-05T09:30:00.123+00:00")),
+            // offset without a colon, minute precision, space separator
+            ("2024-03-05T09:30:00+0100", Some("2024-03-05T08:30:00+00:00")),
+            ("2024-03-05T09:30+0100", Some("2024-03-05T08:30:00+00:00")),
+            ("2024-03-05 09:30:00+0100", Some("2024-03-05T08:30:00+00:00")),
+            // no offset: UTC
+            ("2024-03-05T09:30:00", Some("2024-03-05T09:30:00+00:00")),
+            ("2024-03-05T09:30", Some("2024-03-05T09:30:00+00:00")),
+            ("2024-03-05 09:30:00.5", Some("2024-03-05T09:30:00.500+00:00")),
+            ("2024-03-05 09:30", Some("2024-03-05T09:30:00+00:00")),
+            // bare dates, with anything after the date part ignored
+            (" 2024-03-05 ", Some("2024-03-05T00:00:00+00:00")),
+            ("2024-03-05 at noon", Some("2024-03-05T00:00:00+00:00")),
+            ("20240305", Some("2024-03-05T00:00:00+00:00")),
+            ("20240305T093000", Some("2024-03-05T00:00:00+00:00")),
+            ("2024/03/05 09:30", Some("2024-03-05T00:00:00+00:00")),
+            // RFC 2822
+            ("Tue, 05 Mar 2024 09:30:00 GMT", Some("2024-03-05T09:30:00+00:00")),
+            ("Tue, 05 Mar 2024 09:30:00 -0500", Some("2024-03-05T14:30:00+00:00")),
+            ("2024-02-30", None),
+            ("March 5, 2024", None),
+            ("", None),
+        ];
+        for (input, want) in cases {
+            assert_eq!(parse_date(input).map(|d| d.to_rfc3339()).as_deref(), want, "{input:?}");
+        }
+    }
+}
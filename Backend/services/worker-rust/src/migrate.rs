@@ -0,0 +1,254 @@
+//! Embedded, ordered schema migrations for the tables the worker owns.
+//! Applied versions are recorded in `schema_migrations` with a checksum of
+//! their SQL; each migration runs in its own transaction, and concurrent
+//! runners serialise on an advisory lock.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::info;
+
+use crate::store::PgPool;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../migrations/", $name, ".sql")),
+        }
+    };
+}
+
+/// In version order. Never edit an applied migration; add a new one.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_ingested_documents"),
+    migration!(2, "0002_crawl_queue"),
+    migration!(3, "0003_feeds"),
+    migration!(4, "0004_backfill_columns"),
+    migration!(5, "0005_document_versions"),
+    migration!(6, "0006_watches_alerts"),
+    migration!(7, "0007_raw_responses"),
+    migration!(8, "0008_extractor_version"),
+    migration!(9, "0009_domain_state"),
+    migration!(10, "0010_watch_baselines"),
+    migration!(11, "0011_version_extractor"),
+];
+
+/// `pg_advisory_lock` key; arbitrary but fixed ("mig8" in ASCII).
+const LOCK_KEY: i64 = 0x6d69_6738;
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the embedded SQL has changed since.
+    ChecksumMismatch,
+    /// Recorded in the database but unknown to this build (a newer one ran).
+    Unknown,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: String,
+    pub state: MigrationState,
+    pub checksum: String,
+    pub applied_at: Option<DateTime<Utc>>,
+    pub execution_ms: Option<i64>,
+}
+
+struct AppliedRow {
+    name: String,
+    checksum: String,
+    applied_at: DateTime<Utc>,
+    execution_ms: i64,
+}
+
+async fn ensure_table(client: &tokio_postgres::Client) -> Result<()> {
+    client.batch_execute(r#"
+    CREATE TABLE IF NOT EXISTS public.schema_migrations (
+      version      int PRIMARY KEY,
+      name         text NOT NULL,
+      checksum     text NOT NULL,
+      applied_at   timestamptz NOT NULL DEFAULT now(),
+      execution_ms bigint NOT NULL
+    );
+    "#).await.context("create schema_migrations")?;
+    Ok(())
+}
+
+async fn applied(client: &tokio_postgres::Client) -> Result<HashMap<i32, AppliedRow>> {
+    let rows = client
+        .query("SELECT version, name, checksum, applied_at, execution_ms FROM public.schema_migrations", &[])
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|r| {
+            (r.get(0), AppliedRow { name: r.get(1), checksum: r.get(2), applied_at: r.get(3), execution_ms: r.get(4) })
+        })
+        .collect())
+}
+
+/// Every embedded migration plus any unknown versions, in version order.
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>> {
+    let client = pool.get().await?;
+    ensure_table(&client).await?;
+    Ok(statuses(MIGRATIONS, applied(&client).await?))
+}
+
+fn statuses(migrations: &[Migration], mut done: HashMap<i32, AppliedRow>) -> Vec<MigrationStatus> {
+    let mut out: Vec<MigrationStatus> = migrations
+        .iter()
+        .map(|m| {
+            let checksum = m.checksum();
+            match done.remove(&m.version) {
+                Some(row) => MigrationStatus {
+                    version: m.version,
+                    name: m.name.to_string(),
+                    state: if row.checksum == checksum { MigrationState::Applied } else { MigrationState::ChecksumMismatch },
+                    checksum,
+                    applied_at: Some(row.applied_at),
+                    execution_ms: Some(row.execution_ms),
+                },
+                None => MigrationStatus {
+                    version: m.version,
+                    name: m.name.to_string(),
+                    state: MigrationState::Pending,
+                    checksum,
+                    applied_at: None,
+                    execution_ms: None,
+                },
+            }
+        })
+        .collect();
+    out.extend(done.into_iter().map(|(version, row)| MigrationStatus {
+        version,
+        name: row.name,
+        state: MigrationState::Unknown,
+        checksum: row.checksum,
+        applied_at: Some(row.applied_at),
+        execution_ms: Some(row.execution_ms),
+    }));
+    out.sort_by_key(|s| s.version);
+    out
+}
+
+/// Apply pending migrations in order and return the versions applied.
+/// Refuses to run if an applied migration's checksum no longer matches.
+pub async fn apply_pending(pool: &PgPool) -> Result<Vec<i32>> {
+    let mut client = pool.get().await?;
+    ensure_table(&client).await?;
+
+    // held for the whole run so two workers starting together don't race
+    client.execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY]).await?;
+    let res = apply_locked(&mut client).await;
+    let unlock = client.execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY]).await;
+    let applied = res?;
+    unlock?;
+    Ok(applied)
+}
+
+/// Migrations still to run, lowest version first. Fails if any applied
+/// migration's SQL has changed since.
+fn pending<'a>(migrations: &'a [Migration], done: &HashMap<i32, AppliedRow>) -> Result<Vec<&'a Migration>> {
+    for m in migrations {
+        if let Some(row) = done.get(&m.version) {
+            if row.checksum != m.checksum() {
+                bail!("migration {} ({}) was changed after it was applied", m.version, m.name);
+            }
+        }
+    }
+    let mut todo: Vec<&Migration> = migrations.iter().filter(|m| !done.contains_key(&m.version)).collect();
+    todo.sort_by_key(|m| m.version);
+    Ok(todo)
+}
+
+async fn apply_locked(client: &mut deadpool_postgres::Object) -> Result<Vec<i32>> {
+    let done = applied(client).await?;
+    let mut ran = Vec::new();
+    for m in pending(MIGRATIONS, &done)? {
+        let started = Instant::now();
+        let tx = client.transaction().await?;
+        tx.batch_execute(m.sql).await.with_context(|| format!("migration {} ({})", m.version, m.name))?;
+        let ms = i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX);
+        tx.execute(
+            "INSERT INTO public.schema_migrations (version, name, checksum, execution_ms) VALUES ($1, $2, $3, $4)",
+            &[&m.version, &m.name, &m.checksum(), &ms],
+        )
+        .await?;
+        tx.commit().await?;
+        info!(version = m.version, name = m.name, ms, "applied migration");
+        ran.push(m.version);
+    }
+    Ok(ran)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: Migration = Migration { version: 1, name: "0001_a", sql: "CREATE TABLE a ();" };
+    const V2: Migration = Migration { version: 2, name: "0002_b", sql: "CREATE TABLE b ();" };
+    const V3: Migration = Migration { version: 3, name: "0003_c", sql: "CREATE TABLE c ();" };
+
+    fn row(m: &Migration, checksum: Option<&str>) -> (i32, AppliedRow) {
+        let checksum = checksum.map_or_else(|| m.checksum(), str::to_string);
+        (m.version, AppliedRow { name: m.name.into(), checksum, applied_at: Utc::now(), execution_ms: 1 })
+    }
+
+    #[test]
+    fn embedded_migrations_are_numbered_in_order() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as i32 + 1, "{}", m.name);
+            assert!(m.name.starts_with(&format!("{:04}_", m.version)), "{}", m.name);
+        }
+    }
+
+    #[test]
+    fn pending_runs_in_version_order_after_the_applied_ones() {
+        let done = HashMap::from([row(&V1, None)]);
+        let todo = pending(&[V3, V1, V2], &done).unwrap();
+        assert_eq!(todo.iter().map(|m| m.version).collect::<Vec<_>>(), [2, 3]);
+        assert!(pending(&[V1, V2, V3], &HashMap::from([row(&V1, None), row(&V2, None), row(&V3, None)])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn edited_migration_blocks_the_run_and_shows_in_status() {
+        let done = HashMap::from([row(&V1, None), row(&V2, Some("0000"))]);
+        let err = pending(&[V1, V2, V3], &done).err().expect("edited migration accepted");
+        assert_eq!(err.to_string(), "migration 2 (0002_b) was changed after it was applied");
+
+        let states: Vec<(i32, MigrationState)> = statuses(&[V1, V2, V3], done).into_iter().map(|s| (s.version, s.state)).collect();
+        assert_eq!(
+            states,
+            [(1, MigrationState::Applied), (2, MigrationState::ChecksumMismatch), (3, MigrationState::Pending)]
+        );
+    }
+
+    #[test]
+    fn versions_from_a_newer_build_are_reported_unknown() {
+        let v9 = Migration { version: 9, name: "0009_future", sql: "" };
+        let done = HashMap::from([row(&V1, None), row(&v9, None)]);
+        let st = statuses(&[V1, V2], done);
+        let states: Vec<(i32, MigrationState)> = st.iter().map(|s| (s.version, s.state)).collect();
+        assert_eq!(states, [(1, MigrationState::Applied), (2, MigrationState::Pending), (9, MigrationState::Unknown)]);
+        assert_eq!(st[2].name, "0009_future");
+        assert!(pending(&[V1, V2], &HashMap::from([row(&V1, None), row(&v9, None)])).is_ok());
+    }
+}
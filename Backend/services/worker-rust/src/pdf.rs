@@ -0,0 +1,277 @@
+//! PDF text extraction for reports (sustainability/ESG, IPCC chapters, CDP).
+
+use bytes::Bytes;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use lopdf::{decode_text_string, Dictionary, Document as PdfDoc};
+
+use crate::error::ScrapeError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PdfLimits {
+    /// Refuse PDFs larger than this many bytes outright.
+    pub max_bytes: usize,
+    /// Extract at most this many pages; the rest of the document is ignored.
+    pub max_pages: usize,
+}
+
+impl Default for PdfLimits {
+    fn default() -> Self {
+        Self { max_bytes: 30 * 1024 * 1024, max_pages: 300 }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PdfText {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Pages joined by a blank line.
+    pub text: String,
+    /// Char offset into `text` where each page starts; index 0 is page 1.
+    pub page_offsets: Vec<i32>,
+    pub total_pages: usize,
+}
+
+/// Parse and extract on the blocking pool; lopdf is CPU-bound and synchronous.
+pub async fn extract(body: Bytes, limits: PdfLimits) -> Result<PdfText, ScrapeError> {
+    if body.len() > limits.max_bytes {
+        return Err(ScrapeError::TooLarge { limit: limits.max_bytes });
+    }
+    tokio::task::spawn_blocking(move || extract_blocking(&body, limits))
+        .await
+        .map_err(|e| ScrapeError::Internal(format!("pdf task: {e}")))?
+}
+
+fn extract_blocking(body: &[u8], limits: PdfLimits) -> Result<PdfText, ScrapeError> {
+    let mut doc = PdfDoc::load_mem(body).map_err(|e| ScrapeError::Decode(format!("pdf parse: {e}")))?;
+    if doc.is_encrypted() {
+        // many reports are "encrypted" with an empty user password just to block editing
+        doc.decrypt("").map_err(|e| ScrapeError::Decode(format!("pdf encrypted: {e}")))?;
+    }
+
+    let pages = doc.get_pages();
+    let mut out = PdfText { total_pages: pages.len(), ..Default::default() };
+    let mut chars = 0i32;
+
+    for &n in pages.keys().take(limits.max_pages) {
+        // a single broken content stream shouldn't sink the whole report
+        let page = doc.extract_text(&[n]).unwrap_or_default();
+        let page = tidy(&page);
+        if !out.text.is_empty() {
+            out.text.push_str("\n\n");
+            chars += 2;
+        }
+        out.page_offsets.push(chars);
+        chars += page.chars().count() as i32;
+        out.text.push_str(&page);
+    }
+
+    if let Some(info) = info_dict(&doc) {
+        out.title = info_string(info, b"Title");
+        out.author = info_string(info, b"Author");
+        out.subject = info_string(info, b"Subject");
+        out.created_at = info_string(info, b"CreationDate").and_then(|s| parse_pdf_date(&s));
+    }
+
+    Ok(out)
+}
+
+fn info_dict(doc: &PdfDoc) -> Option<&Dictionary> {
+    let obj = doc.trailer.get(b"Info").ok()?;
+    let (_, obj) = doc.dereference(obj).ok()?;
+    obj.as_dict().ok()
+}
+
+fn info_string(info: &Dictionary, key: &[u8]) -> Option<String> {
+    let s = decode_text_string(info.get(key).ok()?).ok()?;
+    let s = s.trim_matches(|c: char| c.is_whitespace() || c == '\0').to_string();
+    (!s.is_empty()).then_some(s)
+}
+
+/// Collapse the per-glyph line breaks lopdf emits into readable lines.
+fn tidy(page: &str) -> String {
+    page.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// PDF date strings: "D:YYYYMMDDHHmmSSOHH'mm'" with everything after the year optional.
+fn parse_pdf_date(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim().trim_start_matches("D:");
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 4 {
+        return None;
+    }
+    // pad missing month/day/time with their minimums
+    let mut full = digits.clone();
+    let defaults = "0101000000";
+    if full.len() < 14 {
+        full.push_str(&defaults[full.len() - 4..]);
+    }
+    let naive = NaiveDateTime::parse_from_str(&full[..14], "%Y%m%d%H%M%S").ok()?;
+
+    let rest = &s[digits.len()..];
+    let offset = match rest.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let nums: String = rest[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+            let h: i32 = nums.get(0..2).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let m: i32 = nums.get(2..4).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let secs = (h * 3600 + m * 60) * if sign == '-' { -1 } else { 1 };
+            FixedOffset::east_opt(secs)?
+        }
+        _ => FixedOffset::east_opt(0)?,
+    };
+    offset.from_local_datetime(&naive).single().map(|d| d.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Object, Stream};
+
+    /// A PDF with one line of text per page and an optional info dictionary.
+    fn pdf(pages: &[&str], info: Option<Dictionary>) -> Vec<u8> {
+        let mut doc = PdfDoc::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Courier" });
+        let resources_id = doc.add_object(dictionary! { "Font" => dictionary! { "F1" => font_id } });
+        let mut kids = Vec::new();
+        for text in pages {
+            let content = Content {
+                operations: vec![
+                    Operation::new("BT", vec![]),
+                    Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                    Operation::new("Td", vec![72.into(), 720.into()]),
+                    Operation::new("Tj", vec![Object::string_literal(*text)]),
+                    Operation::new("ET", vec![]),
+                ],
+            };
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+            kids.push(doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content_id }).into());
+        }
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        if let Some(info) = info {
+            let info_id = doc.add_object(info);
+            doc.trailer.set("Info", info_id);
+        }
+        let mut out = Vec::new();
+        doc.save_to(&mut out).unwrap();
+        out
+    }
+
+    /// Re-save `body` behind a Standard security handler whose /U entry matches
+    /// no password, so even the empty user password is rejected.
+    fn encrypted(body: &[u8]) -> Vec<u8> {
+        let mut doc = PdfDoc::load_mem(body).unwrap();
+        let encrypt_id = doc.add_object(dictionary! {
+            "Filter" => "Standard", "V" => 1, "R" => 2, "Length" => 40, "P" => -4,
+            "O" => Object::string_literal(vec![1u8; 32]),
+            "U" => Object::string_literal(vec![2u8; 32]),
+        });
+        doc.trailer.set("Encrypt", encrypt_id);
+        doc.trailer.set("ID", vec![Object::string_literal(vec![3u8; 16]), Object::string_literal(vec![3u8; 16])]);
+        let mut out = Vec::new();
+        doc.save_to(&mut out).unwrap();
+        out
+    }
+
+    async fn run(body: Vec<u8>, limits: PdfLimits) -> Result<PdfText, ScrapeError> {
+        extract(body.into(), limits).await
+    }
+
+    #[tokio::test]
+    async fn pages_are_joined_with_char_offsets() {
+        let out = run(pdf(&["First page", "Second page", "Third"], None), PdfLimits::default()).await.unwrap();
+        assert_eq!(out.text, "First page\n\nSecond page\n\nThird");
+        assert_eq!(out.page_offsets, vec![0, 12, 25]);
+        assert_eq!(out.total_pages, 3);
+        for (i, page) in ["First page", "Second page", "Third"].iter().enumerate() {
+            let at = out.page_offsets[i] as usize;
+            assert!(out.text.chars().skip(at).collect::<String>().starts_with(page));
+        }
+    }
+
+    #[tokio::test]
+    async fn max_pages_truncates_but_counts_every_page() {
+        let limits = PdfLimits { max_pages: 2, ..Default::default() };
+        let out = run(pdf(&["One", "Two", "Three"], None), limits).await.unwrap();
+        assert_eq!(out.text, "One\n\nTwo");
+        assert_eq!(out.page_offsets, vec![0, 5]);
+        assert_eq!(out.total_pages, 3);
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_refused_before_parsing() {
+        let body = pdf(&["One"], None);
+        let limits = PdfLimits { max_bytes: body.len() - 1, ..Default::default() };
+        let err = run(body, limits).await.unwrap_err();
+        assert!(matches!(err, ScrapeError::TooLarge { .. }), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_decode_errors() {
+        let body = pdf(&["One", "Two"], None);
+        let truncated = body[..body.len() / 2].to_vec();
+        for body in [b"not a pdf".to_vec(), truncated] {
+            let err = run(body, PdfLimits::default()).await.unwrap_err();
+            assert!(matches!(&err, ScrapeError::Decode(m) if m.starts_with("pdf parse")), "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn password_protected_pdf_is_a_decode_error() {
+        let body = encrypted(&pdf(&["Secret"], None));
+        assert!(PdfDoc::load_mem(&body).unwrap().is_encrypted());
+        let err = run(body, PdfLimits::default()).await.unwrap_err();
+        assert!(matches!(&err, ScrapeError::Decode(m) if m.starts_with("pdf encrypted")), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn info_dictionary_fills_metadata() {
+        let info = dictionary! {
+            "Title" => Object::string_literal("Annual Report 2023 "),
+            "Author" => Object::string_literal("ACME Corp"),
+            "Subject" => Object::string_literal(""),
+            "CreationDate" => Object::string_literal("D:20240131120000+01'00'"),
+        };
+        let out = run(pdf(&["Body"], Some(info)), PdfLimits::default()).await.unwrap();
+        assert_eq!(out.title.as_deref(), Some("Annual Report 2023"));
+        assert_eq!(out.author.as_deref(), Some("ACME Corp"));
+        assert_eq!(out.subject, None);
+        assert_eq!(out.created_at.unwrap().to_rfc3339(), "2024-01-31T11:00:00+00:00");
+    }
+
+    #[test]
+    fn pdf_dates() {
+        let cases = [
+            ("D:20240131120000+01'00'", Some("2024-01-31T11:00:00+00:00")),
+            ("D:20240131120000-05'30'", Some("2024-01-31T17:30:00+00:00")),
+            ("D:202401311200Z", Some("2024-01-31T12:00:00+00:00")),
+            ("D:20240131", Some("2024-01-31T00:00:00+00:00")),
+            ("D:2024", Some("2024-01-01T00:00:00+00:00")),
+            ("20240131120000", Some("2024-01-31T12:00:00+00:00")),
+            ("D:20241341", None),
+            ("D:12", None),
+            ("", None),
+        ];
+        for (input, want) in cases {
+            assert_eq!(parse_pdf_date(input).map(|d| d.to_rfc3339()).as_deref(), want, "{input}");
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Per-host politeness: each registrable domain gets its own concurrency cap
+//! and minimum spacing between request starts, stretched when it pushes back.
+//! Subdomains share their domain's slot, so a robots.txt `Crawl-delay` for
+//! one origin spaces its requests against everything sent to the domain.
+
+use anyhow::Result;
+use dashmap::DashMap;
+use reqwest::StatusCode;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use url::Url;
+
+/// Interval multiplier is 2^penalty; 429/503 bump it, successes decay it.
+const MAX_PENALTY: u32 = 6;
+const MAX_INTERVAL: Duration = Duration::from_secs(120);
+/// Spacing a penalised host gets at least, so pushback slows us down even
+/// with no configured delay.
+const PENALTY_FLOOR: Duration = Duration::from_secs(1);
+/// Domains with nothing in flight and no request for this long are dropped,
+/// penalty included.
+const IDLE_EVICT: Duration = Duration::from_secs(10 * 60);
+/// Idle domains are swept once per this many acquires.
+const SWEEP_EVERY: u64 = 1024;
+
+/// "news.bbc.co.uk" -> "bbc.co.uk". Falls back to the host itself for IPs and
+/// names without a known public suffix.
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    // the suffix list would make "0.1" of 127.0.0.1
+    if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() {
+        return host;
+    }
+    psl::domain_str(&host).map(str::to_string).unwrap_or(host)
+}
+
+fn interval(base: Duration, penalty: u32) -> Duration {
+    if penalty == 0 {
+        return base.min(MAX_INTERVAL);
+    }
+    (base.max(PENALTY_FLOOR) * 2u32.pow(penalty)).min(MAX_INTERVAL)
+}
+
+struct SlotState {
+    next_start: Instant,
+    penalty: u32,
+}
+
+struct HostSlot {
+    sem: Arc<Semaphore>,
+    state: Mutex<SlotState>,
+}
+
+pub struct HostScheduler {
+    per_host: usize,
+    min_interval: Duration,
+    hosts: DashMap<String, Arc<HostSlot>>,
+    acquires: AtomicU64,
+}
+
+/// Held for the duration of one request; dropping it frees the host slot.
+pub struct HostPermit {
+    slot: Arc<HostSlot>,
+    base: Duration,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl HostScheduler {
+    pub fn new(per_host: usize, min_interval: Duration) -> Self {
+        Self { per_host: per_host.max(1), min_interval, hosts: DashMap::new(), acquires: AtomicU64::new(0) }
+    }
+
+    /// Wait for a concurrency slot on `url`'s domain and for its next start
+    /// time. `crawl_delay` (from robots.txt) raises the spacing of this
+    /// request, measured against the domain's previous one.
+    pub async fn acquire(&self, url: &Url, crawl_delay: Option<Duration>) -> Result<HostPermit> {
+        if self.acquires.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.evict_idle(Instant::now());
+        }
+        let key = registrable_domain(url.host_str().unwrap_or(""));
+        let slot = self
+            .hosts
+            .entry(key)
+            .or_insert_with(|| {
+                Arc::new(HostSlot {
+                    sem: Arc::new(Semaphore::new(self.per_host)),
+                    state: Mutex::new(SlotState { next_start: Instant::now(), penalty: 0 }),
+                })
+            })
+            .clone();
+
+        let permit = slot.sem.clone().acquire_owned().await?;
+
+        let base = crawl_delay.map_or(self.min_interval, |d| d.max(self.min_interval));
+        let start = {
+            let mut st = slot.state.lock().unwrap();
+            let start = st.next_start.max(Instant::now());
+            st.next_start = start + interval(base, st.penalty);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+
+        Ok(HostPermit { slot, base, _permit: permit })
+    }
+
+    /// Drop domains idle for `IDLE_EVICT`. A slot someone holds or is
+    /// waiting on is referenced outside the map and stays.
+    fn evict_idle(&self, now: Instant) {
+        self.hosts.retain(|_, slot| {
+            Arc::strong_count(slot) > 1 || slot.state.lock().unwrap().next_start + IDLE_EVICT > now
+        });
+    }
+}
+
+impl HostPermit {
+    /// Feed the response status back so the host's spacing adapts.
+    pub fn record(&self, status: StatusCode) {
+        let mut st = self.slot.state.lock().unwrap();
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            st.penalty = (st.penalty + 1).min(MAX_PENALTY);
+            // the next reservation waits out the longer interval measured from now
+            st.next_start = st.next_start.max(Instant::now() + interval(self.base, st.penalty));
+        } else if status.is_success() {
+            st.penalty = st.penalty.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    /// Start time of a request to `u`, releasing the slot at once.
+    async fn start(s: &HostScheduler, u: &str, crawl_delay: Option<Duration>) -> Instant {
+        drop(s.acquire(&url(u), crawl_delay).await.unwrap());
+        Instant::now()
+    }
+
+    #[test]
+    fn registrable_domain_groups_subdomains() {
+        assert_eq!(registrable_domain("news.bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(registrable_domain("WWW.Example.ORG."), "example.org");
+        assert_eq!(registrable_domain("127.0.0.1"), "127.0.0.1");
+        assert_eq!(registrable_domain("[::1]"), "[::1]");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_requests_per_domain() {
+        let s = HostScheduler::new(2, Duration::from_millis(500));
+        let t0 = start(&s, "https://a.example.org/1", None).await;
+        let t1 = start(&s, "https://b.example.org/2", None).await;
+        let t2 = start(&s, "https://a.example.org/3", None).await;
+        assert_eq!(t1 - t0, Duration::from_millis(500));
+        assert_eq!(t2 - t1, Duration::from_millis(500));
+        // another domain doesn't wait on this one
+        let t3 = start(&s, "https://other.example/", None).await;
+        assert_eq!(t3, t2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_concurrency_per_domain() {
+        let s = HostScheduler::new(1, Duration::ZERO);
+        let held = s.acquire(&url("https://example.org/a"), None).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_secs(5), s.acquire(&url("https://www.example.org/b"), None)).await;
+        assert!(waiting.is_err());
+        drop(held);
+        s.acquire(&url("https://example.org/c"), None).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pushback_slows_down_even_without_a_delay() {
+        let s = HostScheduler::new(1, Duration::ZERO);
+        let u = url("https://example.org/");
+        let t0 = Instant::now();
+        s.acquire(&u, None).await.unwrap().record(StatusCode::TOO_MANY_REQUESTS);
+        let t1 = start(&s, u.as_str(), None).await;
+        assert_eq!(t1 - t0, PENALTY_FLOOR * 2);
+
+        let permit = s.acquire(&u, None).await.unwrap();
+        let t2 = Instant::now();
+        permit.record(StatusCode::SERVICE_UNAVAILABLE);
+        drop(permit);
+        let t3 = start(&s, u.as_str(), None).await;
+        assert_eq!(t3 - t2, PENALTY_FLOOR * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn successes_decay_the_penalty() {
+        let s = HostScheduler::new(1, Duration::from_secs(1));
+        let u = url("https://example.org/");
+        for _ in 0..2 {
+            s.acquire(&u, None).await.unwrap().record(StatusCode::TOO_MANY_REQUESTS);
+        }
+        // penalty 2, then each success takes one off; a start is spaced by
+        // the penalty when it was reserved, one request earlier
+        let mut last = start(&s, u.as_str(), None).await;
+        for expect in [4, 4, 2, 1] {
+            s.acquire(&u, None).await.unwrap().record(StatusCode::OK);
+            let now = Instant::now();
+            assert_eq!(now - last, Duration::from_secs(expect));
+            last = now;
+        }
+    }
+
+    #[test]
+    fn interval_is_capped() {
+        assert_eq!(interval(Duration::from_secs(10), MAX_PENALTY), MAX_INTERVAL);
+        assert_eq!(interval(Duration::ZERO, 0), Duration::ZERO);
+        assert_eq!(interval(Duration::ZERO, 1), PENALTY_FLOOR * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crawl_delay_overrides_a_shorter_interval() {
+        let s = HostScheduler::new(1, Duration::from_millis(100));
+        let t0 = start(&s, "https://example.org/a", Some(Duration::from_secs(3))).await;
+        let t1 = start(&s, "https://example.org/b", Some(Duration::from_secs(3))).await;
+        assert_eq!(t1 - t0, Duration::from_secs(3));
+        // never below the configured spacing
+        let t2 = start(&s, "https://example.org/c", Some(Duration::from_millis(10))).await;
+        let t3 = start(&s, "https://example.org/d", None).await;
+        assert_eq!(t2 - t1, Duration::from_secs(3));
+        assert_eq!(t3 - t2, Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_idle_domains_only() {
+        let s = HostScheduler::new(1, Duration::ZERO);
+        start(&s, "https://idle.example/", None).await;
+        let held = s.acquire(&url("https://busy.example/"), None).await.unwrap();
+        tokio::time::advance(IDLE_EVICT).await;
+        start(&s, "https://recent.example/", None).await;
+
+        s.evict_idle(Instant::now());
+        let mut left: Vec<String> = s.hosts.iter().map(|e| e.key().clone()).collect();
+        left.sort();
+        assert_eq!(left, ["busy.example", "recent.example"]);
+        drop(held);
+    }
+}
@@ -0,0 +1,353 @@
+//! Re-derive the extracted fields of stored documents from their archived
+//! raw responses once `EXTRACTOR_VERSION` has moved past the version that
+//! produced them, instead of waiting for recrawls. Runs from
+//! `worker-rust reprocess` or in the background via `/admin/reprocess`.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::archive::Archive;
+use crate::pdf::PdfLimits;
+use crate::scrape::{self, EXTRACTOR_VERSION};
+use crate::store::{self, DocumentRow, PgPool};
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ReprocessProgress {
+    /// Documents with the row or a version below the current version when
+    /// the run started.
+    pub total: i64,
+    pub scanned: usize,
+    /// Document rows brought up to date.
+    pub updated: usize,
+    /// Older versions brought up to date.
+    pub versions_updated: usize,
+    /// Outdated bodies with no raw response archived under their hash.
+    pub missing_raw: usize,
+    /// Re-extraction failed, e.g. a content type no longer handled.
+    pub failed: usize,
+    /// Recrawled to different content while the run was going.
+    pub skipped: usize,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Idle,
+    Running,
+    Done,
+    Failed,
+}
+
+/// The current or most recent background run.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReprocessStatus {
+    pub state: JobState,
+    pub extractor_version: i32,
+    pub batch: i64,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub progress: ReprocessProgress,
+    pub error: Option<String>,
+}
+
+pub struct Reprocessor {
+    pool: PgPool,
+    archive: Option<Archive>,
+    pdf_limits: PdfLimits,
+    status: Mutex<ReprocessStatus>,
+}
+
+impl Reprocessor {
+    pub fn new(pool: PgPool, archive: Option<Archive>, pdf_limits: PdfLimits) -> Self {
+        let status = ReprocessStatus {
+            state: JobState::Idle,
+            extractor_version: EXTRACTOR_VERSION,
+            batch: 0,
+            started_at: None,
+            finished_at: None,
+            progress: ReprocessProgress::default(),
+            error: None,
+        };
+        Self { pool, archive, pdf_limits, status: Mutex::new(status) }
+    }
+
+    pub fn status(&self) -> ReprocessStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Start a background run unless one is already going.
+    pub fn start(self: &Arc<Self>, batch: i64, limit: Option<usize>) -> Result<()> {
+        if self.archive.is_none() {
+            bail!("no raw archive configured; set ARCHIVE=disk|postgres");
+        }
+        {
+            let mut st = self.status.lock().unwrap();
+            if st.state == JobState::Running {
+                bail!("a reprocess run is already in progress");
+            }
+            *st = ReprocessStatus {
+                state: JobState::Running,
+                extractor_version: EXTRACTOR_VERSION,
+                batch,
+                started_at: Some(Utc::now()),
+                finished_at: None,
+                progress: ReprocessProgress::default(),
+                error: None,
+            };
+        }
+
+        let run = self.clone();
+        self.supervise(async move { run.run(batch, limit, &|_| {}).await });
+        Ok(())
+    }
+
+    /// Drive `job` on its own task and record how it ended in the status.
+    fn supervise(self: &Arc<Self>, job: impl Future<Output = Result<ReprocessProgress>> + Send + 'static) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            // a panic must not leave the state at Running, which refuses every later start
+            let res = tokio::spawn(job).await.unwrap_or_else(|e| Err(anyhow!("reprocess task panicked: {e}")));
+            let mut st = this.status.lock().unwrap();
+            st.finished_at = Some(Utc::now());
+            match res {
+                Ok(progress) => {
+                    st.state = JobState::Done;
+                    st.progress = progress;
+                }
+                Err(e) => {
+                    error!(error=?e, "reprocess run failed");
+                    st.state = JobState::Failed;
+                    st.error = Some(format!("{e:#}"));
+                }
+            }
+        });
+    }
+
+    /// Walk documents with an outdated row or version in id order, `batch`
+    /// at a time, stopping after `limit` if given, and re-extract every
+    /// outdated body from the archive. `on_batch` sees the progress after
+    /// every batch. Bodies that can't be re-extracted keep their version and
+    /// are counted, not retried.
+    pub async fn run(
+        &self,
+        batch: i64,
+        limit: Option<usize>,
+        on_batch: &(dyn Fn(&ReprocessProgress) + Send + Sync),
+    ) -> Result<ReprocessProgress> {
+        let Some(archive) = &self.archive else { bail!("no raw archive configured; set ARCHIVE=disk|postgres") };
+        let started = Instant::now();
+        let mut p = ReprocessProgress { total: store::count_stale_documents(&self.pool, EXTRACTOR_VERSION).await?, ..Default::default() };
+        info!(total = p.total, version = EXTRACTOR_VERSION, "reprocessing documents");
+
+        let mut after_id = 0;
+        loop {
+            let room = limit.map_or(batch, |l| batch.min(l.saturating_sub(p.scanned) as i64));
+            if room <= 0 {
+                break;
+            }
+            let docs = store::stale_documents(&self.pool, EXTRACTOR_VERSION, after_id, room).await?;
+            let Some(last) = docs.last() else { break };
+            after_id = last.id;
+
+            for d in &docs {
+                p.scanned += 1;
+                let Ok(url) = Url::parse(&d.fetched_url) else {
+                    p.failed += 1;
+                    continue;
+                };
+                let mut bodies = d.stale_bodies.clone();
+                if let Some(h) = d.content_hash.as_ref().filter(|h| d.current_stale && !bodies.contains(h)) {
+                    bodies.push(h.clone());
+                }
+                for hash in &bodies {
+                    let raw = match archive.get(hash).await {
+                        Ok(Some(raw)) => raw,
+                        Ok(None) => {
+                            p.missing_raw += 1;
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!(error=?e, id = d.id, hash=%hash, "raw archive read failed");
+                            p.failed += 1;
+                            continue;
+                        }
+                    };
+                    let doc = match scrape::extract_document(&url, &raw, self.pdf_limits).await {
+                        Ok(doc) => doc,
+                        Err(e) => {
+                            warn!(error=%e, id = d.id, url=%url, hash=%hash, "re-extraction failed");
+                            p.failed += 1;
+                            continue;
+                        }
+                    };
+                    let row = DocumentRow::from(&doc);
+                    // the current body also refreshes the row; older ones only their versions
+                    if d.current_stale && d.content_hash.as_ref() == Some(hash) {
+                        if store::update_extraction(&self.pool, d.id, &row).await? {
+                            p.updated += 1;
+                            continue;
+                        }
+                        p.skipped += 1;
+                    }
+                    p.versions_updated += store::update_version_extraction(&self.pool, d.id, &row).await? as usize;
+                }
+            }
+
+            p.elapsed_ms = started.elapsed().as_millis() as u64;
+            self.status.lock().unwrap().progress = p.clone();
+            on_batch(&p);
+        }
+        p.elapsed_ms = started.elapsed().as_millis() as u64;
+        info!(scanned = p.scanned, updated = p.updated, versions_updated = p.versions_updated, missing_raw = p.missing_raw, failed = p.failed, "reprocess finished");
+        Ok(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{RawMeta, RawResponse};
+    use crate::migrate;
+    use sha2::{Digest, Sha256};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const PAGE: &str = "https://reprocess.example/report";
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("reprocess-{:016x}", rand::random::<u64>()))
+    }
+
+    fn raw(body: &'static str) -> RawResponse {
+        RawResponse {
+            meta: RawMeta {
+                content_hash: format!("{:x}", Sha256::digest(body)),
+                url: PAGE.into(),
+                fetched_at: Utc::now(),
+                http_status: 200,
+                headers: vec![("Content-Type".into(), "text/html".into())],
+                truncated: false,
+            },
+            body: body.into(),
+        }
+    }
+
+    async fn wait_until_stopped(rp: &Reprocessor) -> ReprocessStatus {
+        for _ in 0..200 {
+            let st = rp.status();
+            if st.state != JobState::Running {
+                return st;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("reprocess run never finished");
+    }
+
+    #[tokio::test]
+    async fn a_panicking_run_fails_and_the_next_one_can_start() {
+        // the pool connects lazily; nothing here reaches it until the second run
+        let pool = store::init_pool("host=/nonexistent dbname=none").await.unwrap();
+        let rp = Arc::new(Reprocessor::new(pool, Some(Archive::Disk(temp_dir())), PdfLimits::default()));
+        rp.status.lock().unwrap().state = JobState::Running;
+        rp.supervise(async { panic!("extractor bug") });
+
+        let st = wait_until_stopped(&rp).await;
+        assert_eq!(st.state, JobState::Failed);
+        assert!(st.error.as_deref().unwrap().starts_with("reprocess task panicked"), "{:?}", st.error);
+        assert!(st.finished_at.is_some());
+
+        // the failed run doesn't block the next one, which fails on its own terms
+        rp.start(10, None).unwrap();
+        assert_eq!(rp.status().state, JobState::Running);
+        assert!(rp.start(10, None).is_err());
+        let st = wait_until_stopped(&rp).await;
+        assert_eq!(st.state, JobState::Failed);
+        assert!(!st.error.unwrap().contains("panicked"));
+    }
+
+    #[tokio::test]
+    async fn start_needs_an_archive() {
+        let pool = store::init_pool("host=/nonexistent dbname=none").await.unwrap();
+        let rp = Arc::new(Reprocessor::new(pool, None, PdfLimits::default()));
+        assert!(rp.start(10, None).is_err());
+        assert_eq!(rp.status().state, JobState::Idle);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn every_archived_version_is_re_extracted() {
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let dir = temp_dir();
+        let archive = Archive::Disk(dir.clone());
+        let old = raw("<html><head><title>Report 2023</title></head><body><p>Emissions were 1.2 Mt.</p></body></html>");
+        let current = raw("<html><head><title>Report 2024</title></head><body><p>Emissions were 1.1 Mt.</p></body></html>");
+        archive.put(&old).await.unwrap();
+        archive.put(&current).await.unwrap();
+        let (h_old, h_current, h_missing) = (&old.meta.content_hash, &current.meta.content_hash, &"f".repeat(64));
+
+        // a row and versions written by extractor version 0; one body was never archived
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.ingested_documents WHERE url = $1", &[&PAGE]).await.unwrap();
+        let doc: i64 = client
+            .query_one(
+                "INSERT INTO public.ingested_documents (url, fetched_at, body_text, http_status, content_hash, extractor_version) \
+                 VALUES ($1, now(), 'stale', 200, $2, 0) RETURNING id",
+                &[&PAGE, h_current],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        for (version, hash) in [(1, h_old), (2, h_missing), (3, h_old), (4, h_current)] {
+            client
+                .execute(
+                    "INSERT INTO public.document_versions (document_id, version, content_hash, body_text, http_status, fetched_at, extractor_version) \
+                     VALUES ($1, $2, $3, 'stale', 200, now(), 0)",
+                    &[&doc, &version, hash],
+                )
+                .await
+                .unwrap();
+        }
+
+        let rp = Reprocessor::new(pg.clone(), Some(archive), PdfLimits::default());
+        let batches = Mutex::new(0);
+        let p = rp.run(1, None, &|_| *batches.lock().unwrap() += 1).await.unwrap();
+        // other tests' documents may be stale too, and have no archived bodies here
+        assert!(p.updated >= 1 && p.versions_updated >= 2 && p.missing_raw >= 1, "{p:?}");
+        assert_eq!(*batches.lock().unwrap(), p.scanned);
+        assert_eq!(rp.status().progress.scanned, p.scanned);
+
+        let row = client
+            .query_one("SELECT title, body_text, extractor_version FROM public.ingested_documents WHERE id = $1", &[&doc])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("Report 2024"));
+        assert!(row.get::<_, String>(1).contains("1.1 Mt"));
+        assert_eq!(row.get::<_, i32>(2), EXTRACTOR_VERSION);
+
+        let versions: Vec<(i32, String, i32)> = client
+            .query("SELECT version, body_text, extractor_version FROM public.document_versions WHERE document_id = $1 ORDER BY version", &[&doc])
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get(0), r.get(1), r.get(2)))
+            .collect();
+        for (version, text, extractor) in &versions {
+            match version {
+                2 => assert_eq!((text.as_str(), *extractor), ("stale", 0)),
+                4 => assert!(text.contains("1.1 Mt") && *extractor == EXTRACTOR_VERSION),
+                _ => assert!(text.contains("1.2 Mt") && *extractor == EXTRACTOR_VERSION, "v{version}: {text}"),
+            }
+        }
+        assert_eq!(versions.len(), 4);
+
+        client.execute("DELETE FROM public.ingested_documents WHERE id = $1", &[&doc]).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
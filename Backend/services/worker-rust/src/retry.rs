@@ -0,0 +1,104 @@
+//! How long a failed queue item waits before its next attempt, and when it
+//! gives up: exponential backoff on `tries` with jitter, `Retry-After` for
+//! rate limits, and a dead-letter state for permanent or exhausted failures.
+
+use rand::Rng;
+use std::time::Duration;
+
+use crate::error::ErrorClass;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Wait after the first failure; doubles with each further one.
+    pub base: Duration,
+    pub max_backoff: Duration,
+    /// Failures after which an item is dead-lettered.
+    pub max_attempts: i32,
+    /// Backoff is scaled by a random factor in `1 ± jitter`.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(5 * 60),
+            max_backoff: Duration::from_secs(24 * 3600),
+            max_attempts: 8,
+            jitter: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    RetryIn(Duration),
+    DeadLetter,
+}
+
+impl RetryPolicy {
+    /// `tries` is the number of failures before this one.
+    pub fn decide(&self, class: ErrorClass, retry_after: Option<Duration>, tries: i32) -> RetryDecision {
+        if class == ErrorClass::Permanent || tries + 1 >= self.max_attempts {
+            return RetryDecision::DeadLetter;
+        }
+        let wait = match (class, retry_after) {
+            // the server told us when; capped so a silly value can't park the URL for months
+            (ErrorClass::RateLimited, Some(after)) => after.min(self.max_backoff),
+            _ => self.backoff(tries),
+        };
+        RetryDecision::RetryIn(wait)
+    }
+
+    fn backoff(&self, tries: i32) -> Duration {
+        let exp = self.base.saturating_mul(1u32 << tries.clamp(0, 20)).min(self.max_backoff);
+        let j = self.jitter.clamp(0.0, 1.0);
+        let factor = if j > 0.0 { rand::thread_rng().gen_range(1.0 - j..=1.0 + j) } else { 1.0 };
+        exp.mul_f64(factor).min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { base: Duration::from_secs(60), max_backoff: Duration::from_secs(3600), max_attempts: 5, jitter: 0.0 }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let p = policy();
+        let wait = |tries| p.decide(ErrorClass::Transient, None, tries);
+        assert_eq!(wait(0), RetryDecision::RetryIn(Duration::from_secs(60)));
+        assert_eq!(wait(2), RetryDecision::RetryIn(Duration::from_secs(240)));
+        let p = RetryPolicy { max_attempts: 100, ..p };
+        assert_eq!(p.decide(ErrorClass::Transient, None, 30), RetryDecision::RetryIn(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn dead_letters_permanent_and_exhausted_failures() {
+        let p = policy();
+        assert_eq!(p.decide(ErrorClass::Permanent, None, 0), RetryDecision::DeadLetter);
+        assert_eq!(p.decide(ErrorClass::Transient, None, 4), RetryDecision::DeadLetter);
+        assert!(matches!(p.decide(ErrorClass::Transient, None, 3), RetryDecision::RetryIn(_)));
+    }
+
+    #[test]
+    fn rate_limits_follow_retry_after_within_the_cap() {
+        let p = policy();
+        let after = |secs| p.decide(ErrorClass::RateLimited, Some(Duration::from_secs(secs)), 0);
+        assert_eq!(after(5), RetryDecision::RetryIn(Duration::from_secs(5)));
+        assert_eq!(after(86_400), RetryDecision::RetryIn(Duration::from_secs(3600)));
+        // without one it's ordinary backoff
+        assert_eq!(p.decide(ErrorClass::RateLimited, None, 1), RetryDecision::RetryIn(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let p = RetryPolicy { jitter: 0.2, ..policy() };
+        for _ in 0..100 {
+            let RetryDecision::RetryIn(wait) = p.decide(ErrorClass::Transient, None, 0) else { panic!() };
+            assert!(wait >= Duration::from_secs(48) && wait <= Duration::from_secs(72), "{wait:?}");
+        }
+    }
+}
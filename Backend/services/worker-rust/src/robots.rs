@@ -0,0 +1,439 @@
+//! robots.txt handling per RFC 9309: parsing, group selection, longest-match
+//! rule precedence with `*`/`$` wildcards, and a per-origin cache.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use reqwest::{header::HeaderMap, StatusCode};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+use url::Url;
+
+use crate::breaker::{self, DomainHealth};
+use crate::fetch::Fetcher;
+
+/// RFC 9309 §2.5: parsers must handle at least 500 KiB.
+const MAX_ROBOTS_BYTES: usize = 500 * 1024;
+/// How long an "unreachable" verdict (5xx / network error) is kept before retrying.
+const UNREACHABLE_RETRY: Duration = Duration::from_secs(10 * 60);
+/// Upper bound on honored `Crawl-delay` so one site can't stall a worker.
+pub const MAX_CRAWL_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Robots {
+    groups: Vec<Group>,
+    sitemaps: Vec<String>,
+    disallow_all: bool,
+    /// The file as fetched, kept for `domain_state`.
+    text: String,
+}
+
+impl Robots {
+    /// No restrictions (robots.txt missing or 4xx).
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Complete disallow (robots.txt unreachable).
+    pub fn disallow_all() -> Self {
+        Self { disallow_all: true, ..Self::default() }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut sitemaps = Vec::new();
+        let mut cur: Option<Group> = None;
+        // true while we're still reading consecutive user-agent lines
+        let mut in_agents = false;
+
+        for raw in text.lines() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            let Some((key, value)) = line.split_once(':') else { continue };
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+
+            match key.as_str() {
+                "user-agent" => {
+                    if !in_agents {
+                        if let Some(g) = cur.take() {
+                            groups.push(g);
+                        }
+                        cur = Some(Group::default());
+                        in_agents = true;
+                    }
+                    // tolerate "Bot/1.0" style values; only the product token matters
+                    let token = value.split('/').next().unwrap_or("").trim().to_ascii_lowercase();
+                    if let Some(g) = cur.as_mut() {
+                        g.agents.push(token);
+                    }
+                }
+                "allow" | "disallow" => {
+                    in_agents = false;
+                    // rules before any user-agent line belong to no group
+                    let Some(g) = cur.as_mut() else { continue };
+                    if value.is_empty() {
+                        continue;
+                    }
+                    g.rules.push(Rule { allow: key == "allow", pattern: normalize(value) });
+                }
+                "crawl-delay" => {
+                    in_agents = false;
+                    if let (Some(g), Ok(secs)) = (cur.as_mut(), value.parse::<f64>()) {
+                        if secs.is_finite() && secs >= 0.0 {
+                            g.crawl_delay = Some(secs);
+                        }
+                    }
+                }
+                // "sitemap" is not group-member data and doesn't end a group (§2.2.4)
+                "sitemap" => {
+                    if !value.is_empty() {
+                        sitemaps.push(value.to_string());
+                    }
+                }
+                _ => {
+                    in_agents = false;
+                }
+            }
+        }
+        if let Some(g) = cur.take() {
+            groups.push(g);
+        }
+
+        Self { groups, sitemaps, disallow_all: false, text: text.to_string() }
+    }
+
+    /// Groups that apply to `agent`: every group naming it (merged), else every `*` group.
+    fn groups_for<'a>(&'a self, agent: &str) -> Vec<&'a Group> {
+        let agent = agent.to_ascii_lowercase();
+        let named: Vec<&Group> = self.groups.iter().filter(|g| g.agents.contains(&agent)).collect();
+        if !named.is_empty() {
+            return named;
+        }
+        self.groups.iter().filter(|g| g.agents.iter().any(|a| a == "*")).collect()
+    }
+
+    pub fn is_allowed(&self, agent: &str, url: &Url) -> bool {
+        if url.path() == "/robots.txt" {
+            return true;
+        }
+        if self.disallow_all {
+            return false;
+        }
+
+        let mut target = url.path().to_string();
+        if let Some(q) = url.query() {
+            target.push('?');
+            target.push_str(q);
+        }
+        let target = normalize(&target);
+
+        // longest match wins; on a tie, allow wins (§2.2.2)
+        let mut best: Option<(usize, bool)> = None;
+        for g in self.groups_for(agent) {
+            for r in &g.rules {
+                if !wildcard_match(r.pattern.as_bytes(), target.as_bytes()) {
+                    continue;
+                }
+                let len = r.pattern.len();
+                best = match best {
+                    Some((l, a)) if l > len || (l == len && a) => Some((l, a)),
+                    _ => Some((len, r.allow)),
+                };
+            }
+        }
+        best.map(|(_, allow)| allow).unwrap_or(true)
+    }
+
+    pub fn crawl_delay(&self, agent: &str) -> Option<Duration> {
+        self.groups_for(agent)
+            .iter()
+            .filter_map(|g| g.crawl_delay)
+            .reduce(f64::max)
+            .map(|s| Duration::from_secs_f64(s).min(MAX_CRAWL_DELAY))
+    }
+
+    /// `Sitemap:` URLs, which apply regardless of user-agent group.
+    pub fn sitemaps(&self) -> &[String] {
+        &self.sitemaps
+    }
+
+    /// The robots.txt these rules came from; empty if there was none.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The blanket disallow stands in for a robots.txt we couldn't fetch.
+    pub fn is_unreachable(&self) -> bool {
+        self.disallow_all
+    }
+}
+
+/// Bring a path or pattern into one canonical percent-encoded form so the two
+/// can be compared byte-wise: uppercase `%xx` escapes, decode escaped
+/// unreserved characters, and encode raw non-ASCII / control bytes.
+fn normalize(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = String::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        if c == b'%' && i + 2 < b.len() {
+            if let (Some(h), Some(l)) = (hex(b[i + 1]), hex(b[i + 2])) {
+                let v = h * 16 + l;
+                if v.is_ascii_alphanumeric() || matches!(v, b'-' | b'.' | b'_' | b'~') {
+                    out.push(v as char);
+                } else {
+                    out.push_str(&format!("%{v:02X}"));
+                }
+                i += 3;
+                continue;
+            }
+        }
+        if c > 0x7e || c <= 0x20 {
+            out.push_str(&format!("%{c:02X}"));
+        } else {
+            out.push(c as char);
+        }
+        i += 1;
+    }
+    out
+}
+
+fn hex(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Match `pat` (with `*` = any run of bytes, trailing `$` = end anchor) as a
+/// prefix of `text`.
+fn wildcard_match(pat: &[u8], text: &[u8]) -> bool {
+    let (pat, anchored) = match pat.split_last() {
+        Some((b'$', rest)) => (rest, true),
+        _ => (pat, false),
+    };
+
+    let (mut p, mut t) = (0usize, 0usize);
+    let mut star: Option<(usize, usize)> = None;
+    loop {
+        if p == pat.len() {
+            if !anchored || t == text.len() {
+                return true;
+            }
+        } else if pat[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+            continue;
+        } else if t < text.len() && pat[p] == text[t] {
+            p += 1;
+            t += 1;
+            continue;
+        }
+        // mismatch: let the last `*` swallow one more byte
+        match star {
+            Some((sp, st)) if st < text.len() => {
+                star = Some((sp, st + 1));
+                p = sp + 1;
+                t = st + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Product token used for group matching, e.g. "climateimpactbot" for
+/// "ClimateImpactBot/1.0 (+https://...)".
+pub fn agent_token(user_agent: &str) -> String {
+    user_agent
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+/* ------------------------ per-origin cache ------------------------ */
+
+#[derive(Clone)]
+struct Cached {
+    robots: Arc<Robots>,
+    expires: Instant,
+    fetched_at: DateTime<Utc>,
+}
+
+pub struct RobotsCache {
+    ttl: Duration,
+    // one async lock per origin so concurrent scrapes of a host share a single fetch
+    slots: DashMap<String, Arc<Mutex<Option<Cached>>>>,
+}
+
+impl RobotsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, slots: DashMap::new() }
+    }
+
+    /// Rules for `url`'s origin, fetching them on a miss. A fetch counts
+    /// towards the host's health like any other request.
+    pub async fn get(&self, fetcher: &dyn Fetcher, health: &DomainHealth, url: &Url) -> Arc<Robots> {
+        let origin = url.origin().ascii_serialization();
+        let slot = self.slots.entry(origin.clone()).or_default().clone();
+        let mut guard = slot.lock().await;
+
+        if let Some(c) = guard.as_ref() {
+            if c.expires > Instant::now() {
+                return c.robots.clone();
+            }
+        }
+
+        let fetched = fetch(fetcher, health, &origin).await;
+        let (robots, ttl) = match fetched {
+            Fetched::Ok(r) => (Arc::new(r), self.ttl),
+            Fetched::Unavailable => (Arc::new(Robots::allow_all()), self.ttl),
+            Fetched::Unreachable => {
+                // keep serving a previously good copy rather than flapping to disallow
+                let stale = guard.as_ref().map(|c| c.robots.clone());
+                let r = stale.unwrap_or_else(|| Arc::new(Robots::disallow_all()));
+                (r, UNREACHABLE_RETRY.min(self.ttl))
+            }
+        };
+        *guard = Some(Cached { robots: robots.clone(), expires: Instant::now() + ttl, fetched_at: Utc::now() });
+        robots
+    }
+
+    /// The cached rules for `origin` and when they were fetched, without
+    /// fetching or waiting on a fetch in progress.
+    pub fn peek(&self, origin: &str) -> Option<(Arc<Robots>, DateTime<Utc>)> {
+        let slot = self.slots.get(origin)?.clone();
+        let guard = slot.try_lock().ok()?;
+        guard.as_ref().map(|c| (c.robots.clone(), c.fetched_at))
+    }
+}
+
+enum Fetched {
+    Ok(Robots),
+    Unavailable,
+    Unreachable,
+}
+
+async fn fetch(fetcher: &dyn Fetcher, health: &DomainHealth, origin: &str) -> Fetched {
+    let robots_url = format!("{origin}/robots.txt");
+    let Ok(url) = Url::parse(&robots_url) else { return Fetched::Unavailable };
+    let sent = Instant::now();
+    let mut res = match fetcher.get(&url, HeaderMap::new()).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(error=?e, url=%robots_url, "robots.txt unreachable");
+            health.record(&url, sent.elapsed(), breaker::is_host_failure(&e).then(|| e.to_string()));
+            return Fetched::Unreachable;
+        }
+    };
+
+    let status = res.status;
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        health.record(&url, sent.elapsed(), Some(format!("robots.txt: http status {}", status.as_u16())));
+        return Fetched::Unreachable;
+    }
+    health.record(&url, sent.elapsed(), None);
+    if status.is_client_error() {
+        return Fetched::Unavailable;
+    }
+    if !status.is_success() {
+        return Fetched::Unavailable;
+    }
+
+    match res.read_capped(MAX_ROBOTS_BYTES).await {
+        Ok((body, _)) => Fetched::Ok(Robots::parse(&String::from_utf8_lossy(&body))),
+        Err(e) => {
+            warn!(error=?e, url=%robots_url, "robots.txt body read failed");
+            Fetched::Unreachable
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(robots: &Robots, agent: &str, path: &str) -> bool {
+        robots.is_allowed(agent, &Url::parse(&format!("https://example.org{path}")).unwrap())
+    }
+
+    #[test]
+    fn longest_match_wins_and_allow_wins_ties() {
+        let r = Robots::parse("User-agent: *\nDisallow: /docs\nAllow: /docs/public\nDisallow: /x\nAllow: /x\n");
+        assert!(!allowed(&r, "bot", "/docs/internal"));
+        assert!(allowed(&r, "bot", "/docs/public/a"));
+        assert!(allowed(&r, "bot", "/x"));
+        assert!(allowed(&r, "bot", "/elsewhere"));
+    }
+
+    #[test]
+    fn wildcards_and_end_anchor() {
+        let r = Robots::parse("User-agent: *\nDisallow: /*.pdf$\nDisallow: /search*q=\n");
+        assert!(!allowed(&r, "bot", "/files/report.pdf"));
+        assert!(allowed(&r, "bot", "/files/report.pdf?download=1"));
+        assert!(!allowed(&r, "bot", "/search?lang=en&q=heat"));
+        assert!(allowed(&r, "bot", "/search?lang=en"));
+    }
+
+    #[test]
+    fn named_groups_replace_the_star_group() {
+        let text = "User-agent: *\nDisallow: /\n\nUser-agent: TestBot/2.0\nUser-agent: other\nDisallow: /private\nCrawl-delay: 2.5\n\nUser-agent: testbot\nCrawl-delay: 99\n";
+        let r = Robots::parse(text);
+        assert!(allowed(&r, "testbot", "/page"));
+        assert!(!allowed(&r, "testbot", "/private/x"));
+        assert!(!allowed(&r, "somebot", "/page"));
+        // groups naming the agent are merged; the delay is capped
+        assert_eq!(r.crawl_delay("testbot"), Some(MAX_CRAWL_DELAY));
+        assert_eq!(r.crawl_delay("other"), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn percent_encoding_is_normalized() {
+        let r = Robots::parse("User-agent: *\nDisallow: /caf%c3%a9\nDisallow: /%7Euser\n");
+        assert!(!allowed(&r, "bot", "/café/menu"));
+        assert!(!allowed(&r, "bot", "/~user/home"));
+    }
+
+    #[test]
+    fn comments_and_stray_rules() {
+        let r = Robots::parse("Disallow: /ignored\n# comment\nUser-agent: * # all\nDisallow: /tmp # scratch\n");
+        assert!(allowed(&r, "bot", "/ignored"));
+        assert!(!allowed(&r, "bot", "/tmp/a"));
+    }
+
+    #[test]
+    fn sitemaps_belong_to_no_group() {
+        let r = Robots::parse("User-agent: a\nSitemap: https://example.org/s.xml\nDisallow: /x\n\nUser-agent: b\nsitemap: https://example.org/news.xml\n");
+        assert_eq!(r.sitemaps(), ["https://example.org/s.xml", "https://example.org/news.xml"]);
+        // the group carries on past the Sitemap line
+        assert!(!allowed(&r, "a", "/x"));
+    }
+
+    #[test]
+    fn unreachable_disallows_all_but_robots_txt() {
+        let r = Robots::disallow_all();
+        assert!(!allowed(&r, "bot", "/"));
+        assert!(allowed(&r, "bot", "/robots.txt"));
+        assert!(r.is_unreachable());
+    }
+
+    #[test]
+    fn agent_token_is_the_product_name() {
+        assert_eq!(agent_token("ClimateImpactBot/1.0 (+https://example.org/bot)"), "climateimpactbot");
+        assert_eq!(agent_token("SimpleBot"), "simplebot");
+    }
+}
@@ -1,116 +1,298 @@
-use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
-use chrono::Utc;
-use reqwest::{header, redirect::Policy, Client, StatusCode};
+use chrono::{DateTime, Utc};
+use reqwest::{header::{self, HeaderMap, HeaderValue}, Client, StatusCode};
 use scraper::{Html, Selector};
 use sha2::{Digest, Sha256};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::Semaphore;
+use std::{sync::Arc, time::{Duration, Instant}};
 use url::Url;
+use tracing::warn;
 use whatlang::detect;
 
-use crate::types::Document;
+use crate::archive::{Archive, RawMeta, RawResponse};
+use crate::breaker::{self, BreakerConfig, DomainHealth};
+use crate::canon;
+use crate::charset;
+use crate::error::ScrapeError;
+use crate::egress::{self, EgressPolicy, PolicyResolver};
+use crate::extract;
+use crate::fetch::{Fetcher, LiveFetcher};
+use crate::links;
+use crate::meta::{self, PageMeta};
+use crate::pdf::{self, PdfLimits};
+use crate::politeness::{registrable_domain, HostScheduler};
+use crate::robots::{agent_token, Robots, RobotsCache};
+use crate::types::{Document, Validators};
 
 #[derive(Clone)]
 pub struct ScrapeClient {
+    /// Plain HTTP client for requests that aren't page fetches (webhooks);
+    /// bound by the same egress policy.
     pub http: Client,
+    fetcher: Arc<dyn Fetcher>,
+    egress: Arc<EgressPolicy>,
     pub user_agent: String,
-    // polite throttling
-    domain_limit: Arc<Semaphore>,
-    delay: Duration,
+    robots: Arc<RobotsCache>,
+    // polite throttling, per registrable domain
+    hosts: Arc<HostScheduler>,
+    // circuit breaker and health figures, per host
+    health: Arc<DomainHealth>,
+    pdf_limits: PdfLimits,
+    body_limits: BodyLimits,
+    archive: Option<Archive>,
+}
+
+/// Per-kind caps on downloaded body size. Bodies over the cap are cut off
+/// where parsing a prefix still makes sense (HTML, XML) and refused otherwise
+/// (PDF, whose cap is `PdfLimits::max_bytes`).
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub html: usize,
+    /// Sitemaps and feeds, including gzipped sitemaps.
+    pub xml: usize,
+    pub other: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self { html: 5 * 1024 * 1024, xml: 50 * 1024 * 1024, other: 2 * 1024 * 1024 }
+    }
+}
+
+/// A response with its (possibly truncated) body.
+#[derive(Debug)]
+pub struct FetchedBody {
+    pub status: StatusCode,
+    pub content_type: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// All response headers, for the raw archive.
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    /// The body hit its byte cap and the rest was not downloaded.
+    pub truncated: bool,
+    /// Full body size: what was read if complete, else `Content-Length` if sent.
+    pub original_size: Option<u64>,
+    /// `Retry-After` on 429/503 responses.
+    pub retry_after: Option<Duration>,
 }
 
 impl ScrapeClient {
     pub fn new(user_agent: &str, concurrent_per_domain: usize, delay: Duration) -> Self {
-        let http = Client::builder()
-            .user_agent(user_agent)
-            .gzip(true)
-            .brotli(true)
-            .deflate(true)
-            .redirect(Policy::limited(8))
-            .timeout(Duration::from_secs(20))
-            .build()
-            .unwrap();
+        let egress = Arc::new(EgressPolicy::default());
+        let http = build_http(user_agent, &egress);
 
         Self {
+            fetcher: Arc::new(LiveFetcher::new(http.clone(), egress.clone())),
             http,
+            egress,
             user_agent: user_agent.to_string(),
-            domain_limit: Arc::new(Semaphore::new(concurrent_per_domain)),
-            delay,
+            robots: Arc::new(RobotsCache::new(Duration::from_secs(24 * 3600))),
+            hosts: Arc::new(HostScheduler::new(concurrent_per_domain, delay)),
+            health: Arc::new(DomainHealth::new(BreakerConfig::default())),
+            pdf_limits: PdfLimits::default(),
+            body_limits: BodyLimits::default(),
+            archive: None,
         }
     }
 
-    pub async fn fetch_bytes(&self, url: &Url) -> Result<(StatusCode, String, Option<String>, Option<String>, Bytes)> {
-        let _permit = self.domain_limit.acquire().await?;
-        tokio::time::sleep(self.delay).await;
+    /// Replace the default egress policy (public addresses only). Rebuilds
+    /// the HTTP client, so call it before `with_fetcher`.
+    pub fn with_egress(mut self, egress: EgressPolicy) -> Self {
+        self.egress = Arc::new(egress);
+        self.http = build_http(&self.user_agent, &self.egress);
+        self.fetcher = Arc::new(LiveFetcher::new(self.http.clone(), self.egress.clone()));
+        self
+    }
+
+    pub fn egress(&self) -> Arc<EgressPolicy> {
+        self.egress.clone()
+    }
 
-        let res = self.http.get(url.clone()).send().await?;
-        let status = res.status();
+    /// Refuse `url` up front if the egress policy won't let us reach it.
+    pub async fn vet(&self, url: &Url) -> Result<(), ScrapeError> {
+        self.fetcher.vet(url).await
+    }
+
+    /// Fetch pages and robots.txt through `fetcher` instead of the network.
+    pub fn with_fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = fetcher;
+        self
+    }
+
+    pub fn with_breaker(mut self, cfg: BreakerConfig) -> Self {
+        self.health = Arc::new(DomainHealth::new(cfg));
+        self
+    }
+
+    pub fn health(&self) -> &DomainHealth {
+        &self.health
+    }
+
+    /// Cache robots.txt for `ttl` instead of the default 24h.
+    pub fn with_robots_ttl(mut self, ttl: Duration) -> Self {
+        self.robots = Arc::new(RobotsCache::new(ttl));
+        self
+    }
+
+    pub fn with_pdf_limits(mut self, limits: PdfLimits) -> Self {
+        self.pdf_limits = limits;
+        self
+    }
+
+    pub fn with_body_limits(mut self, limits: BodyLimits) -> Self {
+        self.body_limits = limits;
+        self
+    }
+
+    /// Keep the raw response of every page that is extracted.
+    pub fn with_archive(mut self, archive: Option<Archive>) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    /// Byte cap for a response, and whether a prefix of it is still useful.
+    fn body_cap(&self, ct_lower: &str, url: &Url) -> (usize, bool) {
+        if is_pdf(ct_lower, url) {
+            (self.pdf_limits.max_bytes, false)
+        } else if is_html(ct_lower) {
+            (self.body_limits.html, true)
+        } else if ct_lower.contains("xml") || ct_lower.contains("gzip") || ct_lower.contains("rss") || ct_lower.contains("atom") || is_xml_path(url) {
+            (self.body_limits.xml, true)
+        } else {
+            (self.body_limits.other, true)
+        }
+    }
+
+    pub async fn robots(&self, url: &Url) -> Arc<Robots> {
+        self.robots.get(&*self.fetcher, &self.health, url).await
+    }
+
+    /// Cached robots.txt for `origin` and when it was fetched, if any.
+    pub fn cached_robots(&self, origin: &str) -> Option<(Arc<Robots>, DateTime<Utc>)> {
+        self.robots.peek(origin)
+    }
+
+    pub async fn fetch_bytes(
+        &self,
+        url: &Url,
+        crawl_delay: Option<Duration>,
+        prior: Option<&Validators>,
+    ) -> Result<FetchedBody, ScrapeError> {
+        let host = breaker::host_key(url);
+        if let Err(retry_in) = self.health.allow(&host) {
+            return Err(ScrapeError::CircuitOpen { host, retry_in });
+        }
+        let permit = self.hosts.acquire(url, crawl_delay).await.map_err(|e| ScrapeError::Internal(e.to_string()))?;
+
+        let mut conditional = HeaderMap::new();
+        if let Some(v) = prior {
+            if let Some(etag) = v.etag.as_deref().and_then(|e| HeaderValue::from_str(e).ok()) {
+                conditional.insert(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(lm) = v.last_modified.as_deref().and_then(|l| HeaderValue::from_str(l).ok()) {
+                conditional.insert(header::IF_MODIFIED_SINCE, lm);
+            }
+        }
+        let sent = Instant::now();
+        let mut res = match self.fetcher.get(url, conditional).await {
+            Ok(res) => res,
+            Err(e) => {
+                let failure = breaker::is_host_failure(&e).then(|| e.to_string());
+                self.health.record(url, sent.elapsed(), failure);
+                return Err(e);
+            }
+        };
+        let status = res.status;
+        permit.record(status);
+        let failure = (status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS).then(|| format!("http status {}", status.as_u16()));
+        self.health.record(url, sent.elapsed(), failure);
 
         let ct = res
-            .headers()
+            .headers
             .get(header::CONTENT_TYPE)
             .and_then(|v| v.to_str().ok())
             .unwrap_or("")
             .to_string();
 
         let etag = res
-            .headers()
+            .headers
             .get(header::ETAG)
             .and_then(|v| v.to_str().ok())
             .map(|s| s.to_string());
 
         let last_modified = res
-            .headers()
+            .headers
             .get(header::LAST_MODIFIED)
             .and_then(|v| v.to_str().ok())
             .map(|s| s.to_string());
 
-        let body = res.bytes().await?;
-        Ok((status, ct, etag, last_modified, body))
-    }
-}
-
-/// Minimal robots.txt check
-async fn allowed_by_robots(sc: &ScrapeClient, url: &Url) -> bool {
-    let mut robots_url = url.clone();
-    robots_url.set_path("/robots.txt");
-    robots_url.set_query(None);
-    robots_url.set_fragment(None);
+        let retry_after = res
+            .headers
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
 
-    let text = match sc.http.get(robots_url).send().await {
-        Ok(r) if r.status().is_success() => r.text().await.unwrap_or_default(),
-        _ => return true,
-    };
+        let headers = res
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+            .collect();
 
-    let mut allows: Vec<String> = Vec::new();
-    let mut disallows: Vec<String> = Vec::new();
-    let ua_l = sc.user_agent.to_lowercase();
-    let mut in_group = false;
-    for line in text.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') { continue; }
-        let lower = line.to_lowercase();
-        if let Some(rest) = lower.strip_prefix("user-agent:") {
-            let agent = rest.trim();
-            in_group = agent == "*" || ua_l.contains(agent);
-            continue;
+        // stream into a capped buffer: never hold more than the cap in memory
+        let (cap, partial_ok) = self.body_cap(&ct.to_lowercase(), url);
+        let content_length = res.content_length();
+        if let Some(len) = content_length {
+            if len > cap as u64 && !partial_ok {
+                return Err(ScrapeError::TooLarge { limit: cap });
+            }
         }
-        if !in_group { continue; }
-        if let Some(path) = lower.strip_prefix("allow:") {
-            allows.push(path.trim().to_string());
-        } else if let Some(path) = lower.strip_prefix("disallow:") {
-            disallows.push(path.trim().to_string());
+        let (body, truncated) = res.read_capped(cap).await?;
+        if truncated && !partial_ok {
+            return Err(ScrapeError::TooLarge { limit: cap });
         }
+        let original_size = if truncated { content_length } else { Some(body.len() as u64) };
+
+        Ok(FetchedBody {
+            status,
+            content_type: ct,
+            etag,
+            last_modified,
+            headers,
+            body,
+            truncated,
+            original_size,
+            retry_after,
+        })
     }
+}
 
-    let p = url.path();
-    let blocked = disallows.iter().any(|d| p.starts_with(d)) &&
-                  !allows.iter().any(|a| p.starts_with(a) && !a.is_empty());
-    !blocked
+/// Resolves through the egress policy, so the address vetted is the one
+/// connected to, and checks every redirect hop. Proxy env vars are ignored:
+/// through a proxy only the proxy's address would be resolved here.
+fn build_http(user_agent: &str, egress: &Arc<EgressPolicy>) -> Client {
+    Client::builder()
+        .no_proxy()
+        .user_agent(user_agent)
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .dns_resolver(Arc::new(PolicyResolver::new(egress.clone())))
+        .redirect(egress::redirect_policy(egress.clone(), 8))
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap()
 }
 
-fn html_to_text(html: &str) -> (Option<String>, Option<String>, String) {
+struct HtmlPage {
+    canonical: Option<Url>,
+    title: Option<String>,
+    description: Option<String>,
+    body_text: String,
+    links: Vec<Url>,
+    meta: PageMeta,
+}
+
+fn html_to_text(html: &str, page_url: &Url) -> HtmlPage {
     let doc = Html::parse_document(html);
 
     let title_sel = Selector::parse("title").unwrap();
@@ -122,58 +304,333 @@ fn html_to_text(html: &str) -> (Option<String>, Option<String>, String) {
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty());
 
-    let body_sel = Selector::parse("body").unwrap();
-    let body_text = doc
-        .select(&body_sel)
-        .flat_map(|b| b.text())
-        .map(|t| t.trim())
-        .filter(|t| !t.is_empty())
-        .collect::<Vec<_>>()
-        .join("\n");
+    let canonical_sel = Selector::parse(r#"link[rel~="canonical" i][href]"#).unwrap();
+    let canonical = doc.select(&canonical_sel).next()
+        .and_then(|n| n.value().attr("href"))
+        .and_then(|h| page_url.join(h.trim()).ok())
+        .filter(|u| matches!(u.scheme(), "http" | "https"))
+        .map(|u| canon::canonicalize(&u));
+
+    let body_text = extract::main_text(&doc);
+    let links = links::extract_links(&doc, page_url);
+    let meta = meta::extract(&doc, page_url);
+
+    // social tags fill in for pages that skip the plain ones
+    let title = title.or_else(|| meta.title.clone());
+    let description = description.or_else(|| meta.description.clone());
 
-    (title, description, body_text)
+    HtmlPage { canonical, title, description, body_text, links, meta }
 }
 
-pub async fn scrape_one(sc: &ScrapeClient, url_raw: &str) -> Result<Document> {
-    let url = Url::parse(url_raw).map_err(|e| anyhow!("bad url: {e}"))?;
+/// Cap on stored text for HTML pages.
+const MAX_TEXT_CHARS: usize = 200_000;
+
+/// Version of the extraction pipeline (`html_to_text`, metadata, PDF text).
+/// Bump it whenever a change would alter what's stored for the same bytes;
+/// rows extracted by an older version are picked up by `reprocess`.
+pub const EXTRACTOR_VERSION: i32 = 2;
+
+pub enum ScrapeOutcome {
+    Fetched(Box<Document>),
+    /// 304 against the validators we sent; the stored copy is still current.
+    NotModified { url: String, fetched_at: DateTime<Utc> },
+}
+
+pub async fn scrape_one(sc: &ScrapeClient, url_raw: &str, prior: Option<&Validators>) -> Result<ScrapeOutcome, ScrapeError> {
+    let url = Url::parse(url_raw).map_err(|e| ScrapeError::BadUrl(e.to_string()))?;
     if !(url.scheme() == "https" || url.scheme() == "http") {
-        bail!("unsupported scheme");
+        return Err(ScrapeError::UnsupportedScheme(url.scheme().to_string()));
+    }
+
+    sc.vet(&url).await?;
+    // before robots.txt, whose fetch would otherwise be the request that waits out a dead host
+    let host = breaker::host_key(&url);
+    if let Err(retry_in) = sc.health.check(&host) {
+        return Err(ScrapeError::CircuitOpen { host, retry_in });
     }
 
-    if !allowed_by_robots(sc, &url).await {
-        bail!("blocked by robots.txt");
+    let agent = agent_token(&sc.user_agent);
+    let robots = sc.robots(&url).await;
+    if !robots.is_allowed(&agent, &url) {
+        return Err(if robots.is_unreachable() { ScrapeError::RobotsUnavailable } else { ScrapeError::RobotsBlocked });
     }
 
-    let (status, ct, etag, last_modified, body) = sc.fetch_bytes(&url).await?;
+    let FetchedBody { status, headers, body, truncated, original_size, retry_after, .. } =
+        sc.fetch_bytes(&url, robots.crawl_delay(&agent), prior).await?;
+    if status == StatusCode::NOT_MODIFIED && prior.is_some() {
+        return Ok(ScrapeOutcome::NotModified { url: canon::canonicalize(&url).to_string(), fetched_at: Utc::now() });
+    }
     if !status.is_success() {
-        bail!("http status {}", status.as_u16());
+        return Err(ScrapeError::HttpStatus { status: status.as_u16(), retry_after });
     }
 
-    if !ct.to_lowercase().starts_with("text/html") {
-        bail!("content-type not html: {ct}");
+    let raw = RawResponse {
+        meta: RawMeta {
+            content_hash: format!("{:x}", Sha256::digest(&body)),
+            url: canon::canonicalize(&url).to_string(),
+            fetched_at: Utc::now(),
+            http_status: status.as_u16(),
+            headers,
+            truncated,
+        },
+        body,
+    };
+    let mut doc = extract_document(&url, &raw, sc.pdf_limits).await?;
+    doc.original_size = original_size.and_then(|n| i64::try_from(n).ok());
+
+    if let Some(archive) = &sc.archive {
+        // losing the raw copy shouldn't lose the document
+        if let Err(e) = archive.put(&raw).await {
+            warn!(error=?e, url=%url, "raw archive write failed");
+        }
     }
+    Ok(ScrapeOutcome::Fetched(Box::new(doc)))
+}
 
-    let html = String::from_utf8_lossy(&body).to_string();
-    let (title, description, text) = html_to_text(&html);
-    let trimmed = text.chars().take(200_000).collect::<String>();
+/// Everything stored about a page that is derived from the response alone,
+/// for a live fetch of `url` or one replayed from the raw archive.
+/// `original_size` is only known for live fetches and is left to the caller
+/// unless the body is complete.
+pub async fn extract_document(url: &Url, raw: &RawResponse, pdf_limits: PdfLimits) -> Result<Document, ScrapeError> {
+    let header = |name: &str| raw.meta.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+    let ct = header("content-type").unwrap_or_default();
+    let body = &raw.body;
+    let mut truncated = raw.meta.truncated;
 
-    let lang = detect(&trimmed).map(|i| i.lang().code().to_string());
+    let ct_l = ct.to_lowercase();
+    let (author, published_at);
+    let (mut page_offsets, mut encoding) = (None, None);
+    let (mut publisher, mut section, mut image_url, mut metadata) = (None, None, None, None);
+    let mut links = Vec::new();
+    let fetched_url = canon::canonicalize(url);
+    let mut doc_url = fetched_url.clone();
+    let (title, description, trimmed) = if is_html(&ct_l) {
+        let (html, enc) = charset::decode_html(body, &ct, url);
+        encoding = Some(enc.name().to_string());
+        // parsing and scoring are CPU-bound; keep them off the async workers like PDFs
+        let page_url = url.clone();
+        let page = tokio::task::spawn_blocking(move || html_to_text(&html, &page_url))
+            .await
+            .map_err(|e| ScrapeError::Internal(format!("html task: {e}")))?;
+        links = page.links.iter().map(Url::to_string).collect();
+        // rel=canonical folds syndicated and parameterised copies onto one row,
+        // but a page may only speak for its own site's rows
+        if let Some(c) = page.canonical.filter(|c| same_site(c, &fetched_url)) {
+            doc_url = c;
+        }
+        author = page.meta.author;
+        published_at = page.meta.published_at;
+        publisher = page.meta.publisher;
+        section = page.meta.section;
+        image_url = page.meta.image_url;
+        metadata = Some(page.meta.raw);
+        truncated |= page.body_text.chars().count() > MAX_TEXT_CHARS;
+        (page.title, page.description, page.body_text.chars().take(MAX_TEXT_CHARS).collect::<String>())
+    } else if is_pdf(&ct_l, url) {
+        // bounded by PdfLimits::max_pages rather than a char cap, so page offsets stay valid
+        let pdf = pdf::extract(body.clone(), pdf_limits).await?;
+        if pdf.page_offsets.len() < pdf.total_pages {
+            warn!(url=%url, pages=pdf.total_pages, kept=pdf.page_offsets.len(), "pdf page limit hit");
+            truncated = true;
+        }
+        author = pdf.author;
+        published_at = pdf.created_at;
+        page_offsets = Some(pdf.page_offsets);
+        (pdf.title, pdf.subject, pdf.text)
+    } else {
+        return Err(ScrapeError::UnsupportedContentType(ct));
+    };
 
-    let mut hasher = Sha256::new();
-    hasher.update(&body);
-    let hash_hex = format!("{:x}", hasher.finalize());
+    let lang = detect(&trimmed).map(|i| i.lang().code().to_string());
 
     Ok(Document {
-        url: url.to_string(),
-        fetched_at: Utc::now(),
+        url: doc_url.to_string(),
+        fetched_url: fetched_url.to_string(),
+        fetched_at: raw.meta.fetched_at,
         title,
         description,
         body_text: trimmed,
         content_type: Some(ct),
-        http_status: status.as_u16() as i32,
-        content_hash: Some(hash_hex),
-        etag,
+        http_status: i32::from(raw.meta.http_status),
+        content_hash: Some(raw.meta.content_hash.clone()),
+        etag: header("etag"),
         lang,
-        last_modified,
+        last_modified: header("last-modified"),
+        author,
+        published_at,
+        page_offsets,
+        encoding,
+        publisher,
+        section,
+        image_url,
+        metadata,
+        truncated,
+        original_size: if raw.meta.truncated { None } else { i64::try_from(body.len()).ok() },
+        extractor_version: EXTRACTOR_VERSION,
+        links,
     })
-}
\ No newline at end of file
+}
+
+/// `Retry-After` as delta-seconds or an HTTP date.
+fn parse_retry_after(v: &str) -> Option<Duration> {
+    let v = v.trim();
+    if let Ok(secs) = v.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(v).ok()?.with_timezone(&Utc);
+    (at - Utc::now()).to_std().ok()
+}
+
+/// Both URLs are on the same registrable domain.
+fn same_site(a: &Url, b: &Url) -> bool {
+    registrable_domain(a.host_str().unwrap_or("")) == registrable_domain(b.host_str().unwrap_or(""))
+}
+
+/// Servers often label PDFs as octet-stream; fall back to the extension then.
+/// Sitemaps and feeds are often served as `application/octet-stream` or
+/// `text/plain`; go by the file name then.
+fn is_xml_path(url: &Url) -> bool {
+    let path = url.path().to_lowercase();
+    [".xml", ".gz", ".rss", ".atom"].iter().any(|ext| path.ends_with(ext))
+}
+
+/// Content types parsed as HTML, both for the body cap and for extraction.
+fn is_html(ct_lower: &str) -> bool {
+    ct_lower.starts_with("text/html") || ct_lower.starts_with("application/xhtml")
+}
+
+fn is_pdf(ct_lower: &str, url: &Url) -> bool {
+    ct_lower.starts_with("application/pdf")
+        || (ct_lower.starts_with("application/octet-stream") && url.path().to_lowercase().ends_with(".pdf"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch;
+
+    fn client() -> ScrapeClient {
+        ScrapeClient::new("TestBot/1.0 (+https://bot.example/)", 4, Duration::ZERO).with_fetcher(fetch::test_fixtures())
+    }
+
+    async fn fetched(sc: &ScrapeClient, url: &str) -> Document {
+        match scrape_one(sc, url, None).await {
+            Ok(ScrapeOutcome::Fetched(doc)) => *doc,
+            Ok(ScrapeOutcome::NotModified { .. }) => panic!("{url}: unexpected 304"),
+            Err(e) => panic!("{url}: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn robots_allow() {
+        let sc = client();
+        let doc = fetched(&sc, "https://allowed.example/article").await;
+        assert_eq!(doc.title.as_deref(), Some("Heat pumps in winter"));
+        assert!(doc.body_text.contains("below freezing"));
+        // no recorded robots.txt replays as 404: no restrictions
+        fetched(&sc, "https://open.example/page").await;
+    }
+
+    #[tokio::test]
+    async fn robots_deny() {
+        let sc = client();
+        for url in ["https://allowed.example/private/page", "https://closed.example/page"] {
+            assert!(matches!(scrape_one(&sc, url, None).await, Err(ScrapeError::RobotsBlocked)), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn robots_5xx_disallows_everything() {
+        let sc = client();
+        let res = scrape_one(&sc, "https://down.example/page", None).await;
+        assert!(matches!(res, Err(ScrapeError::RobotsUnavailable)));
+        assert!(sc.robots(&Url::parse("https://down.example/").unwrap()).await.is_unreachable());
+    }
+
+    #[tokio::test]
+    async fn not_modified_against_stored_validators() {
+        let sc = client();
+        let url = "https://allowed.example/article";
+        for prior in [
+            Validators { etag: Some("\"a1\"".into()), last_modified: None },
+            Validators { etag: None, last_modified: Some("Tue, 06 Oct 2026 08:00:00 GMT".into()) },
+        ] {
+            match scrape_one(&sc, url, Some(&prior)).await {
+                Ok(ScrapeOutcome::NotModified { url: u, .. }) => assert_eq!(u, url),
+                _ => panic!("expected 304 for {prior:?}"),
+            }
+        }
+        let changed = Validators { etag: Some("\"a0\"".into()), last_modified: None };
+        assert!(matches!(scrape_one(&sc, url, Some(&changed)).await, Ok(ScrapeOutcome::Fetched(_))));
+    }
+
+    #[tokio::test]
+    async fn canonical_within_the_site_becomes_the_row_key() {
+        let doc = fetched(&client(), "https://allowed.example/article").await;
+        // fragment and tracking parameters dropped
+        assert_eq!(doc.url, "https://www.allowed.example/article");
+        assert_eq!(doc.fetched_url, "https://allowed.example/article");
+    }
+
+    #[tokio::test]
+    async fn canonical_on_another_site_is_ignored() {
+        let doc = fetched(&client(), "https://allowed.example/syndicated").await;
+        assert_eq!(doc.url, "https://allowed.example/syndicated");
+    }
+
+    #[tokio::test]
+    async fn html_over_the_cap_is_cut_off() {
+        let sc = client().with_body_limits(BodyLimits { html: 200, ..BodyLimits::default() });
+        let doc = fetched(&sc, "https://allowed.example/article").await;
+        assert!(doc.truncated);
+        // known from the length the response announced
+        assert_eq!(doc.original_size, Some(389));
+        assert_eq!(doc.title.as_deref(), Some("Heat pumps in winter"));
+
+        let doc = fetched(&client(), "https://allowed.example/article").await;
+        assert!(!doc.truncated);
+        assert_eq!(doc.original_size, Some(389));
+    }
+
+    #[tokio::test]
+    async fn pdf_over_the_cap_is_refused() {
+        let sc = client().with_pdf_limits(PdfLimits { max_bytes: 128, ..PdfLimits::default() });
+        let res = scrape_one(&sc, "https://allowed.example/report.pdf", None).await;
+        assert!(matches!(res, Err(ScrapeError::TooLarge { limit: 128 })));
+    }
+
+    #[tokio::test]
+    async fn sitemap_by_file_name_gets_the_xml_cap() {
+        let sc = client().with_body_limits(BodyLimits { html: 10, xml: 100, other: 10 });
+        let url = Url::parse("https://allowed.example/sitemap.xml.gz").unwrap();
+        let got = sc.fetch_bytes(&url, None, None).await.unwrap();
+        assert_eq!(got.body.len(), 100);
+        assert!(got.truncated);
+        assert_eq!(got.original_size, Some(256));
+    }
+
+    #[tokio::test]
+    async fn xhtml_is_capped_and_extracted_as_html() {
+        let sc = client().with_body_limits(BodyLimits { html: 10, xml: 100, other: 1000 });
+        let url = Url::parse("https://open.example/page.xhtml").unwrap();
+        assert_eq!(sc.body_cap("application/xhtml+xml; charset=utf-8", &url), (10, true));
+
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <html xmlns="http://www.w3.org/1999/xhtml"><head><title>Ledger</title></head>
+            <body><p>An XHTML page is still a page.</p></body></html>"#;
+        let raw = RawResponse {
+            meta: RawMeta {
+                content_hash: String::new(),
+                url: url.to_string(),
+                fetched_at: Utc::now(),
+                http_status: 200,
+                headers: vec![("Content-Type".into(), "application/xhtml+xml; charset=utf-8".into())],
+                truncated: false,
+            },
+            body: Bytes::from_static(body.as_bytes()),
+        };
+        let doc = extract_document(&url, &raw, PdfLimits::default()).await.unwrap();
+        assert_eq!(doc.title.as_deref(), Some("Ledger"));
+        assert_eq!(doc.body_text, "An XHTML page is still a page.");
+    }
+}
@@ -0,0 +1,348 @@
+//! Sitemap discovery: `Sitemap:` lines from robots.txt plus `/sitemap.xml`,
+//! following sitemap indexes breadth-first within limits. Plain, gzipped and
+//! Google News sitemaps are all read through the `sitemap` crate.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use flate2::read::GzDecoder;
+use sitemap::reader::{SiteMapEntity, SiteMapReader};
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use tracing::warn;
+use url::Url;
+
+use crate::canon;
+use crate::politeness::registrable_domain;
+use crate::robots::agent_token;
+use crate::scrape::{FetchedBody, ScrapeClient};
+
+const NEWS_NS: &[u8] = b"google.com/schemas/sitemap-news/";
+
+#[derive(Debug, Clone, Copy)]
+pub struct SitemapLimits {
+    /// Index nesting followed below the root sitemaps.
+    pub max_depth: usize,
+    /// Sitemap files fetched per origin.
+    pub max_sitemaps: usize,
+    /// URLs collected per origin.
+    pub max_urls: usize,
+    /// Decompressed size cap per file (the protocol's own limit is 50 MiB).
+    pub max_bytes: usize,
+}
+
+impl Default for SitemapLimits {
+    fn default() -> Self {
+        Self { max_depth: 3, max_sitemaps: 50, max_urls: 50_000, max_bytes: 50 * 1024 * 1024 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SitemapUrl {
+    pub url: String,
+    pub sitemap: String,
+    pub lastmod: Option<DateTime<Utc>>,
+    pub changefreq: Option<String>,
+    pub priority: Option<f32>,
+    /// Listed in a Google News sitemap (articles from the last two days).
+    pub news: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub sitemaps_read: usize,
+    pub urls: Vec<SitemapUrl>,
+    pub errors: Vec<String>,
+}
+
+pub async fn discover(sc: &ScrapeClient, origin: &Url, limits: SitemapLimits) -> Discovery {
+    if let Err(e) = sc.vet(origin).await {
+        return Discovery { errors: vec![e.to_string()], ..Default::default() };
+    }
+    let agent = agent_token(&sc.user_agent);
+    let robots = sc.robots(origin).await;
+    let site = registrable_domain(origin.host_str().unwrap_or(""));
+
+    let mut queue: VecDeque<(String, usize)> = robots.sitemaps().iter().map(|s| (s.clone(), 0)).collect();
+    if let Ok(default) = origin.join("/sitemap.xml") {
+        queue.push_back((default.to_string(), 0));
+    }
+
+    let mut seen_maps = HashSet::new();
+    let mut seen_urls = HashSet::new();
+    let mut out = Discovery::default();
+
+    while let Some((map_url, depth)) = queue.pop_front() {
+        if out.sitemaps_read >= limits.max_sitemaps || out.urls.len() >= limits.max_urls {
+            break;
+        }
+        if !seen_maps.insert(map_url.clone()) {
+            continue;
+        }
+        let Ok(parsed) = Url::parse(&map_url) else { continue };
+        // robots.txt may point at another host (cross-submission); that host's robots still apply
+        let robots_for_map = if parsed.origin() == origin.origin() { robots.clone() } else { sc.robots(&parsed).await };
+        if !robots_for_map.is_allowed(&agent, &parsed) {
+            continue;
+        }
+
+        let body = match fetch_sitemap(sc, &parsed, robots_for_map.crawl_delay(&agent), limits.max_bytes).await {
+            Ok(b) => b,
+            Err(e) => {
+                // the /sitemap.xml guess 404s on plenty of sites; don't log that one
+                if depth > 0 || robots.sitemaps().contains(&map_url) {
+                    warn!(error=?e, url=%map_url, "sitemap fetch failed");
+                }
+                out.errors.push(format!("{map_url}: {e}"));
+                continue;
+            }
+        };
+        out.sitemaps_read += 1;
+
+        let news = body.windows(NEWS_NS.len()).any(|w| w == NEWS_NS);
+        let entries = match tokio::task::spawn_blocking(move || parse(&body)).await {
+            Ok(v) => v,
+            Err(e) => {
+                out.errors.push(format!("{map_url}: {e}"));
+                continue;
+            }
+        };
+
+        for entity in entries {
+            match entity {
+                Entry::Index(loc) => {
+                    let same_site = Url::parse(&loc).is_ok_and(|u| registrable_domain(u.host_str().unwrap_or("")) == site);
+                    if depth < limits.max_depth && same_site {
+                        queue.push_back((loc, depth + 1));
+                    }
+                }
+                Entry::Url(mut u) => {
+                    if out.urls.len() >= limits.max_urls {
+                        break;
+                    }
+                    let Ok(loc) = Url::parse(&u.url) else { continue };
+                    if registrable_domain(loc.host_str().unwrap_or("")) != site {
+                        continue;
+                    }
+                    u.url = canon::canonicalize(&loc).to_string();
+                    if seen_urls.insert(u.url.clone()) {
+                        u.sitemap = map_url.clone();
+                        u.news = news;
+                        out.urls.push(u);
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+async fn fetch_sitemap(sc: &ScrapeClient, url: &Url, crawl_delay: Option<std::time::Duration>, max_bytes: usize) -> Result<Vec<u8>> {
+    let FetchedBody { status, content_type: ct, body, truncated, .. } = sc.fetch_bytes(url, crawl_delay, None).await?;
+    if !status.is_success() {
+        bail!("http status {}", status.as_u16());
+    }
+    // a cut-off gzip stream or XML document only fails later with a confusing error
+    if truncated {
+        bail!("sitemap larger than the body size limit ({} bytes fetched)", body.len());
+    }
+    // reqwest already undid Content-Encoding; this is for .xml.gz files served as-is
+    let gz = body.starts_with(&[0x1f, 0x8b]) || ct.to_lowercase().contains("gzip");
+    if gz {
+        let mut out = Vec::new();
+        GzDecoder::new(&body[..]).take(max_bytes as u64 + 1).read_to_end(&mut out)?;
+        if out.len() > max_bytes {
+            bail!("sitemap larger than {max_bytes} bytes");
+        }
+        return Ok(out);
+    }
+    if body.len() > max_bytes {
+        bail!("sitemap larger than {max_bytes} bytes");
+    }
+    Ok(body.to_vec())
+}
+
+enum Entry {
+    Index(String),
+    Url(SitemapUrl),
+}
+
+fn parse(body: &[u8]) -> Vec<Entry> {
+    let mut out = Vec::new();
+    for entity in SiteMapReader::new(body) {
+        match entity {
+            SiteMapEntity::SiteMap(m) => {
+                if let Some(loc) = m.loc.get_url() {
+                    out.push(Entry::Index(loc.to_string()));
+                }
+            }
+            SiteMapEntity::Url(u) => {
+                let Some(loc) = u.loc.get_url() else { continue };
+                let changefreq = match u.changefreq.as_str() {
+                    "" => None,
+                    f => Some(f.to_string()),
+                };
+                out.push(Entry::Url(SitemapUrl {
+                    url: loc.to_string(),
+                    sitemap: String::new(),
+                    lastmod: u.lastmod.get_time().map(|t| t.with_timezone(&Utc)),
+                    changefreq,
+                    priority: u.priority.get_priority(),
+                    news: false,
+                }));
+            }
+            // a malformed tail shouldn't discard what parsed fine before it
+            SiteMapEntity::Err(_) => break,
+        }
+    }
+    out
+}
+
+/* ------------------------ scheduling ------------------------ */
+
+fn changefreq_interval(freq: Option<&str>) -> ChronoDuration {
+    match freq {
+        Some("always") | Some("hourly") => ChronoDuration::hours(1),
+        Some("daily") => ChronoDuration::days(1),
+        Some("weekly") => ChronoDuration::days(7),
+        Some("monthly") => ChronoDuration::days(30),
+        Some("yearly") | Some("never") => ChronoDuration::days(365),
+        _ => ChronoDuration::days(7),
+    }
+}
+
+/// Queue priority for a sitemap URL, relative to the site's seed priority:
+/// the sitemap's own 0.0–1.0 priority shifts it by ±10, news and recently
+/// modified pages get a boost.
+pub fn queue_priority(u: &SitemapUrl, base: i32, now: DateTime<Utc>) -> i32 {
+    let mut p = base + ((u.priority.unwrap_or(0.5) - 0.5) * 20.0).round() as i32;
+    if u.news {
+        p += 20;
+    }
+    if u.lastmod.is_some_and(|m| now - m < ChronoDuration::days(2)) {
+        p += 10;
+    }
+    p.max(0)
+}
+
+/// When to first fetch a sitemap URL: now if it changed within its own
+/// changefreq window (or we can't tell), otherwise a quarter-interval out so
+/// fresh pages go first.
+pub fn next_fetch_at(u: &SitemapUrl, now: DateTime<Utc>) -> DateTime<Utc> {
+    let interval = changefreq_interval(u.changefreq.as_deref());
+    match u.lastmod {
+        Some(m) if !u.news && now - m > interval => now + interval / 4,
+        _ => now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch;
+    use std::time::Duration;
+
+    async fn run(limits: SitemapLimits) -> Discovery {
+        let sc = ScrapeClient::new("TestBot/1.0 (+https://bot.example/)", 4, Duration::ZERO).with_fetcher(fetch::test_fixtures());
+        discover(&sc, &Url::parse("https://maps.example/").unwrap(), limits).await
+    }
+
+    fn urls(d: &Discovery) -> Vec<&str> {
+        d.urls.iter().map(|u| u.url.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn follows_indexes_and_reads_gzip_and_news_sitemaps() {
+        let d = run(SitemapLimits::default()).await;
+        assert_eq!(
+            urls(&d),
+            [
+                "https://maps.example/2024/05/flood-warning",
+                "https://maps.example/about",
+                "https://www.maps.example/contact",
+                "https://maps.example/2023/12/year-in-review",
+            ]
+        );
+        assert_eq!(d.sitemaps_read, 6);
+        // only the /sitemap.xml guess, which has no fixture; elsewhere.example is never fetched
+        assert_eq!(d.errors, ["https://maps.example/sitemap.xml: http status 404"]);
+
+        let news = &d.urls[0];
+        assert!(news.news);
+        assert_eq!(news.sitemap, "https://maps.example/news.xml.gz");
+        let about = &d.urls[1];
+        assert!(!about.news);
+        assert_eq!(about.sitemap, "https://maps.example/pages.xml");
+        assert_eq!(about.changefreq.as_deref(), Some("monthly"));
+        assert_eq!(about.priority, Some(0.8));
+        assert_eq!(about.lastmod.unwrap().to_rfc3339(), "2024-04-01T00:00:00+00:00");
+    }
+
+    #[tokio::test]
+    async fn index_nesting_stops_at_max_depth() {
+        let d = run(SitemapLimits { max_depth: 2, ..Default::default() }).await;
+        assert_eq!(d.sitemaps_read, 5);
+        assert!(!urls(&d).contains(&"https://maps.example/2023/12/year-in-review"));
+
+        let d = run(SitemapLimits { max_depth: 0, ..Default::default() }).await;
+        assert_eq!(d.sitemaps_read, 1);
+        assert!(d.urls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_and_url_caps() {
+        let d = run(SitemapLimits { max_sitemaps: 2, ..Default::default() }).await;
+        assert_eq!(d.sitemaps_read, 2);
+        assert_eq!(urls(&d), ["https://maps.example/2024/05/flood-warning"]);
+
+        let d = run(SitemapLimits { max_urls: 2, ..Default::default() }).await;
+        assert_eq!(urls(&d), ["https://maps.example/2024/05/flood-warning", "https://maps.example/about"]);
+
+        // decompressed size is what counts for gzip files
+        let d = run(SitemapLimits { max_bytes: 400, ..Default::default() }).await;
+        assert!(d.errors.iter().any(|e| e.starts_with("https://maps.example/news.xml.gz: sitemap larger than 400")), "{:?}", d.errors);
+    }
+
+    fn entry(lastmod: Option<&str>, changefreq: Option<&str>, news: bool) -> SitemapUrl {
+        SitemapUrl {
+            url: "https://maps.example/a".into(),
+            sitemap: "https://maps.example/sitemap.xml".into(),
+            lastmod: lastmod.map(|d| d.parse().unwrap()),
+            changefreq: changefreq.map(str::to_string),
+            priority: None,
+            news,
+        }
+    }
+
+    #[test]
+    fn stale_entries_are_pushed_back_a_quarter_interval() {
+        let now: DateTime<Utc> = "2024-05-10T00:00:00Z".parse().unwrap();
+        let cases = [
+            (None, Some("daily"), false, now),
+            (Some("2024-05-09T12:00:00Z"), Some("daily"), false, now),
+            (Some("2024-05-01T00:00:00Z"), Some("daily"), false, now + ChronoDuration::hours(6)),
+            (Some("2024-05-01T00:00:00Z"), Some("weekly"), false, now + ChronoDuration::hours(42)),
+            (Some("2024-05-01T00:00:00Z"), None, false, now + ChronoDuration::hours(42)),
+            (Some("2024-05-01T00:00:00Z"), Some("monthly"), false, now),
+            (Some("2023-01-01T00:00:00Z"), Some("yearly"), false, now + ChronoDuration::hours(2190)),
+            (Some("2024-05-01T00:00:00Z"), Some("hourly"), true, now),
+        ];
+        for (lastmod, freq, news, want) in cases {
+            assert_eq!(next_fetch_at(&entry(lastmod, freq, news), now), want, "{lastmod:?} {freq:?} news={news}");
+        }
+    }
+
+    #[test]
+    fn priority_shifts_with_sitemap_priority_news_and_freshness() {
+        let now: DateTime<Utc> = "2024-05-10T00:00:00Z".parse().unwrap();
+        let mut u = entry(Some("2024-01-01T00:00:00Z"), None, false);
+        assert_eq!(queue_priority(&u, 50, now), 50);
+        u.priority = Some(1.0);
+        assert_eq!(queue_priority(&u, 50, now), 60);
+        u.priority = Some(0.0);
+        assert_eq!(queue_priority(&u, 5, now), 0);
+        u.priority = None;
+        u.news = true;
+        u.lastmod = Some("2024-05-09T00:00:00Z".parse().unwrap());
+        assert_eq!(queue_priority(&u, 50, now), 80);
+    }
+}
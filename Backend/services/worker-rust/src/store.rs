@@ -2,7 +2,14 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod, Pool};
 use tokio_postgres::NoTls;
+use std::collections::HashMap;
 use std::str::FromStr; // <- needed for Config::from_str
+use std::time::Duration;
+
+use crate::archive::RawMeta;
+use crate::breaker::{Circuit, DomainSnapshot, Unsaved, EWMA_ALPHA};
+use crate::canon;
+use crate::types::{Document, Validators};
 
 pub type PgPool = Pool;
 
@@ -13,60 +20,13 @@ pub async fn init_pool(pg_url: &str) -> Result<PgPool> {
         recycling_method: RecyclingMethod::Fast
     });
     let pool = Pool::builder(mgr).max_size(8).build().unwrap();
-
-    // Ensure tables exist
-    ensure_tables(&pool).await
-        .context("ensure tables")?;
-
     Ok(pool)
 }
 
-pub async fn ensure_tables(pool: &PgPool) -> Result<()> {
-    let conn = pool.get().await?;
-    // 1) Ingested documents (content store)
-    conn.batch_execute(r#"
-    CREATE TABLE IF NOT EXISTS public.ingested_documents (
-      id            bigserial PRIMARY KEY,
-      url           text NOT NULL UNIQUE,
-      fetched_at    timestamptz NOT NULL,
-      title         text,
-      description   text,
-      body_text     text NOT NULL,
-      content_type  text,
-      http_status   int NOT NULL,
-      content_hash  text,
-      lang          text,
-      etag          text,
-      created_at    timestamptz NOT NULL DEFAULT now(),
-      updated_at    timestamptz NOT NULL DEFAULT now()   -- <- needed by UPSERT
-    );
-    CREATE INDEX IF NOT EXISTS idx_ingested_fetched_at
-      ON public.ingested_documents (fetched_at DESC);
-    "#).await.context("ensure ingested_documents")?;
-
-    // 2) Crawl queue
-    conn.batch_execute(r#"
-    CREATE TABLE IF NOT EXISTS public.crawl_queue (
-      id            bigserial PRIMARY KEY,
-      url           text NOT NULL UNIQUE,
-      priority      int  NOT NULL DEFAULT 0,
-      next_fetch_at timestamptz NOT NULL DEFAULT now(),
-      last_status   int,
-      last_error    text,
-      tries         int  NOT NULL DEFAULT 0,
-      created_at    timestamptz NOT NULL DEFAULT now(),
-      updated_at    timestamptz NOT NULL DEFAULT now()
-    );
-    CREATE INDEX IF NOT EXISTS idx_crawl_next
-      ON public.crawl_queue (next_fetch_at, priority DESC);
-    "#).await.context("ensure crawl_queue")?;
-
-    Ok(())
-}
-
 #[derive(Debug, Clone)]
 pub struct DocumentRow<'a> {
     pub url: &'a str,
+    pub fetched_url: &'a str,
     pub fetched_at: DateTime<Utc>,
     pub title: Option<&'a str>,
     pub description: Option<&'a str>,
@@ -76,16 +36,131 @@ pub struct DocumentRow<'a> {
     pub content_hash: Option<&'a str>,
     pub lang: Option<&'a str>,
     pub etag: Option<&'a str>,
+    pub last_modified: Option<&'a str>,
+    pub author: Option<&'a str>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub page_offsets: Option<&'a [i32]>,
+    pub encoding: Option<&'a str>,
+    pub publisher: Option<&'a str>,
+    pub section: Option<&'a str>,
+    pub image_url: Option<&'a str>,
+    pub metadata: Option<&'a serde_json::Value>,
+    pub truncated: bool,
+    pub original_size: Option<i64>,
+    pub extractor_version: i32,
 }
 
-pub async fn upsert_document(pool: &PgPool, d: &DocumentRow<'_>) -> Result<()> {
-    let client = pool.get().await?;
-    client.execute(
+impl<'a> From<&'a Document> for DocumentRow<'a> {
+    fn from(doc: &'a Document) -> Self {
+        Self {
+            url: &doc.url,
+            fetched_url: &doc.fetched_url,
+            fetched_at: doc.fetched_at,
+            title: doc.title.as_deref(),
+            description: doc.description.as_deref(),
+            body_text: &doc.body_text,
+            content_type: doc.content_type.as_deref(),
+            http_status: doc.http_status,
+            content_hash: doc.content_hash.as_deref(),
+            lang: doc.lang.as_deref(),
+            etag: doc.etag.as_deref(),
+            last_modified: doc.last_modified.as_deref(),
+            author: doc.author.as_deref(),
+            published_at: doc.published_at,
+            page_offsets: doc.page_offsets.as_deref(),
+            encoding: doc.encoding.as_deref(),
+            publisher: doc.publisher.as_deref(),
+            section: doc.section.as_deref(),
+            image_url: doc.image_url.as_deref(),
+            metadata: doc.metadata.as_ref(),
+            truncated: doc.truncated,
+            original_size: doc.original_size,
+            extractor_version: doc.extractor_version,
+        }
+    }
+}
+
+/// Store the latest copy of a page and, if its `content_hash` differs from
+/// the newest recorded version, add a `document_versions` row. Returns the
+/// new version number when one was added.
+pub async fn upsert_document(pool: &PgPool, d: &DocumentRow<'_>) -> Result<Option<i32>> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let document_id = write_document(&tx, d, false).await?;
+
+    let version = tx.query_opt(
+        r#"
+        WITH latest AS (
+          SELECT version, content_hash FROM public.document_versions
+          WHERE document_id = $1
+          ORDER BY version DESC
+          LIMIT 1
+        )
+        INSERT INTO public.document_versions
+          (document_id, version, content_hash, title, body_text, http_status, fetched_at, extractor_version)
+        SELECT $1, COALESCE((SELECT version FROM latest), 0) + 1, $2, $3, $4, $5, $6, $7
+        WHERE NOT EXISTS (SELECT 1 FROM latest WHERE content_hash IS NOT DISTINCT FROM $2)
+        RETURNING version
+        "#,
+        &[&document_id, &d.content_hash, &d.title, &d.body_text, &d.http_status, &d.fetched_at, &d.extractor_version],
+    ).await?;
+    tx.commit().await?;
+    Ok(version.map(|r| r.get(0)))
+}
+
+/// Record a capture imported from a WARC. Captures can arrive in any order:
+/// the document row only takes this one if it is newer than what it holds,
+/// and it becomes a version unless the same body was already recorded for
+/// the same second (e.g. re-importing our own export). Returns the new
+/// version number when one was added.
+pub async fn import_capture(pool: &PgPool, d: &DocumentRow<'_>) -> Result<Option<i32>> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let document_id = write_document(&tx, d, true).await?;
+
+    let version = tx.query_opt(
+        r#"
+        INSERT INTO public.document_versions
+          (document_id, version, content_hash, title, body_text, http_status, fetched_at, extractor_version)
+        SELECT $1, COALESCE((SELECT max(version) FROM public.document_versions WHERE document_id = $1), 0) + 1,
+               $2, $3, $4, $5, $6, $7
+        WHERE NOT EXISTS (
+          SELECT 1 FROM public.document_versions
+          WHERE document_id = $1 AND content_hash IS NOT DISTINCT FROM $2
+            AND date_trunc('second', fetched_at) = date_trunc('second', $6::timestamptz)
+        )
+        RETURNING version
+        "#,
+        &[&document_id, &d.content_hash, &d.title, &d.body_text, &d.http_status, &d.fetched_at, &d.extractor_version],
+    ).await?;
+    tx.commit().await?;
+    Ok(version.map(|r| r.get(0)))
+}
+
+/// Insert or update the `ingested_documents` row for `d` and return its id,
+/// holding the row lock until `tx` ends. With `only_if_newer` an existing row
+/// is left alone when it was fetched after `d`.
+async fn write_document(tx: &tokio_postgres::Transaction<'_>, d: &DocumentRow<'_>, only_if_newer: bool) -> Result<i64> {
+    let url = canon::canonical_str(d.url);
+    let fetched_url = canon::canonical_str(d.fetched_url);
+    // the upsert's row lock serialises concurrent writers of one URL until commit
+    let row = tx.query_opt(
         r#"
+        WITH fi AS (
+          -- pages without their own byline/date inherit the feed entry's
+          SELECT author, published_at FROM public.feed_items
+          WHERE url IN ($1, $16)
+          ORDER BY first_seen_at
+          LIMIT 1
+        )
         INSERT INTO public.ingested_documents
-          (url, fetched_at, title, description, body_text, content_type, http_status, content_hash, lang, etag)
+          (url, fetched_at, title, description, body_text, content_type, http_status, content_hash, lang, etag, last_modified,
+           author, published_at, page_offsets, encoding, fetched_url, publisher, section, image_url, metadata,
+           truncated, original_size, extractor_version)
         VALUES
-          ($1,  $2,        $3,    $4,         $5,        $6,           $7,          $8,          $9,   $10)
+          ($1,  $2,        $3,    $4,         $5,        $6,           $7,          $8,          $9,   $10,  $11,
+           COALESCE($12, (SELECT author FROM fi)), COALESCE($13, (SELECT published_at FROM fi)), $14, $15, $16, $17, $18, $19, $20,
+           $21, $22, $23)
         ON CONFLICT (url) DO UPDATE SET
           fetched_at   = EXCLUDED.fetched_at,
           title        = EXCLUDED.title,
@@ -96,10 +171,25 @@ pub async fn upsert_document(pool: &PgPool, d: &DocumentRow<'_>) -> Result<()> {
           content_hash = EXCLUDED.content_hash,
           lang         = EXCLUDED.lang,
           etag         = EXCLUDED.etag,
+          last_modified = EXCLUDED.last_modified,
+          author       = EXCLUDED.author,
+          published_at = EXCLUDED.published_at,
+          page_offsets = EXCLUDED.page_offsets,
+          encoding     = EXCLUDED.encoding,
+          fetched_url  = EXCLUDED.fetched_url,
+          publisher    = EXCLUDED.publisher,
+          section      = EXCLUDED.section,
+          image_url    = EXCLUDED.image_url,
+          metadata     = EXCLUDED.metadata,
+          truncated    = EXCLUDED.truncated,
+          original_size = EXCLUDED.original_size,
+          extractor_version = EXCLUDED.extractor_version,
           updated_at   = now()
+        WHERE NOT $24 OR public.ingested_documents.fetched_at <= EXCLUDED.fetched_at
+        RETURNING id
         "#,
         &[
-            &d.url,
+            &url,
             &d.fetched_at,
             &d.title,
             &d.description,
@@ -109,11 +199,355 @@ pub async fn upsert_document(pool: &PgPool, d: &DocumentRow<'_>) -> Result<()> {
             &d.content_hash,
             &d.lang,
             &d.etag,
+            &d.last_modified,
+            &d.author,
+            &d.published_at,
+            &d.page_offsets,
+            &d.encoding,
+            &fetched_url,
+            &d.publisher,
+            &d.section,
+            &d.image_url,
+            &d.metadata,
+            &d.truncated,
+            &d.original_size,
+            &d.extractor_version,
+            &only_if_newer,
         ],
     ).await?;
+    if let Some(row) = row {
+        return Ok(row.get(0));
+    }
+    let row = tx.query_one("SELECT id FROM public.ingested_documents WHERE url = $1 FOR UPDATE", &[&url]).await?;
+    Ok(row.get(0))
+}
+
+/// Looks the URL up both as a row key and as the fetched URL of a row that
+/// was filed under its rel=canonical target.
+pub async fn get_validators(pool: &PgPool, url: &str) -> Result<Option<Validators>> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let row = client.query_opt(
+        r#"
+        SELECT etag, last_modified FROM public.ingested_documents
+        WHERE url = $1 OR fetched_url = $1
+        ORDER BY (url = $1) DESC
+        LIMIT 1
+        "#,
+        &[&url],
+    ).await?;
+    Ok(row.map(|r| Validators { etag: r.get(0), last_modified: r.get(1) }))
+}
+
+/// 304 path: the stored copy is still current, only record that we checked.
+pub async fn touch_document(pool: &PgPool, url: &str, fetched_at: DateTime<Utc>) -> Result<()> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    client.execute(
+        r#"
+        UPDATE public.ingested_documents
+        SET fetched_at = $2,
+            updated_at = now()
+        WHERE url = $1 OR fetched_url = $1
+        "#,
+        &[&url, &fetched_at],
+    ).await?;
     Ok(())
 }
 
+/* --------------------- Reprocessing --------------------- */
+
+#[derive(Debug, Clone)]
+pub struct StaleDocument {
+    pub id: i64,
+    pub fetched_url: String,
+    pub content_hash: Option<String>,
+    /// The row itself was extracted by an older version.
+    pub current_stale: bool,
+    /// Bodies of versions extracted by an older version, current one included.
+    pub stale_bodies: Vec<String>,
+}
+
+/// Documents whose row or any version was extracted by a version below `below`.
+const STALE_DOCUMENTS: &str = r#"
+    (d.extractor_version < $1 AND d.content_hash IS NOT NULL
+     OR EXISTS (SELECT 1 FROM public.document_versions v
+                WHERE v.document_id = d.id AND v.extractor_version < $1 AND v.content_hash IS NOT NULL))
+"#;
+
+pub async fn count_stale_documents(pool: &PgPool, below: i32) -> Result<i64> {
+    let client = pool.get().await?;
+    let row = client.query_one(
+        &format!("SELECT count(*) FROM public.ingested_documents d WHERE {STALE_DOCUMENTS}"),
+        &[&below],
+    ).await?;
+    Ok(row.get(0))
+}
+
+/// Stale documents (see `STALE_DOCUMENTS`) in id order after `after_id`.
+pub async fn stale_documents(pool: &PgPool, below: i32, after_id: i64, limit: i64) -> Result<Vec<StaleDocument>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        &format!(r#"
+        SELECT d.id, COALESCE(d.fetched_url, d.url), d.content_hash,
+               d.extractor_version < $1 AND d.content_hash IS NOT NULL,
+               ARRAY(SELECT DISTINCT v.content_hash FROM public.document_versions v
+                     WHERE v.document_id = d.id AND v.extractor_version < $1 AND v.content_hash IS NOT NULL)
+        FROM public.ingested_documents d
+        WHERE d.id > $2 AND {STALE_DOCUMENTS}
+        ORDER BY d.id
+        LIMIT $3
+        "#),
+        &[&below, &after_id, &limit],
+    ).await?;
+    Ok(rows.iter().map(|r| StaleDocument {
+        id: r.get(0),
+        fetched_url: r.get(1),
+        content_hash: r.get(2),
+        current_stale: r.get(3),
+        stale_bodies: r.get(4),
+    }).collect())
+}
+
+/// Replace a document's derived fields with a re-extraction of the same
+/// bytes, along with the title and text of the versions holding them. Fetch
+/// state (URL, validators, size, timestamps) is left alone, as are byline
+/// and date if the new extraction found none. A no-op returning `false` if
+/// the row was recrawled to different content in the meantime.
+pub async fn update_extraction(pool: &PgPool, id: i64, d: &DocumentRow<'_>) -> Result<bool> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let n = tx.execute(
+        r#"
+        UPDATE public.ingested_documents SET
+          title        = $3,
+          description  = $4,
+          body_text    = $5,
+          lang         = $6,
+          author       = COALESCE($7, author),
+          published_at = COALESCE($8, published_at),
+          page_offsets = $9,
+          encoding     = $10,
+          publisher    = $11,
+          section      = $12,
+          image_url    = $13,
+          metadata     = $14,
+          truncated    = $15,
+          extractor_version = $16,
+          updated_at   = now()
+        WHERE id = $1 AND content_hash = $2
+        "#,
+        &[
+            &id,
+            &d.content_hash,
+            &d.title,
+            &d.description,
+            &d.body_text,
+            &d.lang,
+            &d.author,
+            &d.published_at,
+            &d.page_offsets,
+            &d.encoding,
+            &d.publisher,
+            &d.section,
+            &d.image_url,
+            &d.metadata,
+            &d.truncated,
+            &d.extractor_version,
+        ],
+    ).await?;
+    if n == 0 {
+        return Ok(false);
+    }
+    tx.execute(
+        "UPDATE public.document_versions SET title = $3, body_text = $4, extractor_version = $5 WHERE document_id = $1 AND content_hash = $2",
+        &[&id, &d.content_hash, &d.title, &d.body_text, &d.extractor_version],
+    ).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Replace title and text of the versions of a document that hold an older
+/// body with a re-extraction of it. Returns the number of versions updated.
+pub async fn update_version_extraction(pool: &PgPool, id: i64, d: &DocumentRow<'_>) -> Result<u64> {
+    let client = pool.get().await?;
+    let n = client.execute(
+        r#"
+        UPDATE public.document_versions SET title = $3, body_text = $4, extractor_version = $5
+        WHERE document_id = $1 AND content_hash = $2 AND extractor_version < $5
+        "#,
+        &[&id, &d.content_hash, &d.title, &d.body_text, &d.extractor_version],
+    ).await?;
+    Ok(n)
+}
+
+/* --------------------- Document versions --------------------- */
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VersionSummary {
+    pub version: i32,
+    pub content_hash: Option<String>,
+    pub title: Option<String>,
+    pub http_status: i32,
+    pub fetched_at: DateTime<Utc>,
+    pub chars: i32,
+}
+
+/// Versions of the document stored under (or fetched from) `url`, oldest first.
+pub async fn list_versions(pool: &PgPool, url: &str) -> Result<Vec<VersionSummary>> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let rows = client.query(
+        r#"
+        SELECT v.version, v.content_hash, v.title, v.http_status, v.fetched_at, char_length(v.body_text)
+        FROM public.document_versions v
+        WHERE v.document_id = (
+          SELECT id FROM public.ingested_documents
+          WHERE url = $1 OR fetched_url = $1
+          ORDER BY (url = $1) DESC
+          LIMIT 1
+        )
+        ORDER BY v.version
+        "#,
+        &[&url],
+    ).await?;
+    Ok(rows.iter().map(|r| VersionSummary {
+        version: r.get(0),
+        content_hash: r.get(1),
+        title: r.get(2),
+        http_status: r.get(3),
+        fetched_at: r.get(4),
+        chars: r.get(5),
+    }).collect())
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionText {
+    pub version: i32,
+    pub title: Option<String>,
+    pub body_text: String,
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// One version's text; a negative `version` counts back from the newest
+/// (-1 is the latest).
+pub async fn get_version(pool: &PgPool, url: &str, version: i32) -> Result<Option<VersionText>> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let row = client.query_opt(
+        r#"
+        WITH d AS (
+          SELECT id FROM public.ingested_documents
+          WHERE url = $1 OR fetched_url = $1
+          ORDER BY (url = $1) DESC
+          LIMIT 1
+        )
+        SELECT v.version, v.title, v.body_text, v.fetched_at
+        FROM public.document_versions v
+        WHERE v.document_id = (SELECT id FROM d)
+          AND ($2 > 0 AND v.version = $2
+               OR $2 < 0 AND v.version = (SELECT max(version) + 1 + $2 FROM public.document_versions
+                                          WHERE document_id = (SELECT id FROM d)))
+        "#,
+        &[&url, &version],
+    ).await?;
+    Ok(row.map(|r| VersionText { version: r.get(0), title: r.get(1), body_text: r.get(2), fetched_at: r.get(3) }))
+}
+
+/// The version of `url` that `watch` compares new versions against: the one
+/// it last alerted on, else the newest one stored before the watch was
+/// created, else the first.
+pub async fn watch_baseline(pool: &PgPool, watch: &Watch, url: &str) -> Result<Option<VersionText>> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let row = client.query_opt(
+        r#"
+        WITH d AS (
+          SELECT id FROM public.ingested_documents
+          WHERE url = $1 OR fetched_url = $1
+          ORDER BY (url = $1) DESC
+          LIMIT 1
+        )
+        SELECT v.version, v.title, v.body_text, v.fetched_at
+        FROM public.document_versions v
+        WHERE v.document_id = (SELECT id FROM d)
+          AND v.version = coalesce(
+            (SELECT version FROM public.watch_baselines WHERE watch_id = $2 AND document_id = (SELECT id FROM d)),
+            (SELECT max(version) FROM public.document_versions
+             WHERE document_id = (SELECT id FROM d) AND created_at <= $3),
+            (SELECT min(version) FROM public.document_versions WHERE document_id = (SELECT id FROM d)))
+        "#,
+        &[&url, &watch.id, &watch.created_at],
+    ).await?;
+    Ok(row.map(|r| VersionText { version: r.get(0), title: r.get(1), body_text: r.get(2), fetched_at: r.get(3) }))
+}
+
+/* --------------------- Raw archive --------------------- */
+
+/// Returns false if the hash was already archived.
+pub async fn put_raw(pool: &PgPool, meta: &RawMeta, body_gz: &[u8], body_size: i64) -> Result<bool> {
+    let client = pool.get().await?;
+    let headers = serde_json::to_value(&meta.headers)?;
+    let n = client.execute(
+        r#"
+        INSERT INTO public.raw_responses
+          (content_hash, url, fetched_at, http_status, headers, truncated, body_size, body_gz)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (content_hash) DO NOTHING
+        "#,
+        &[&meta.content_hash, &meta.url, &meta.fetched_at, &i32::from(meta.http_status), &headers, &meta.truncated,
+          &body_size, &body_gz],
+    ).await?;
+    Ok(n > 0)
+}
+
+pub async fn get_raw(pool: &PgPool, content_hash: &str) -> Result<Option<(RawMeta, Vec<u8>)>> {
+    let client = pool.get().await?;
+    let row = client.query_opt(
+        r#"
+        SELECT content_hash, url, fetched_at, http_status, headers, truncated, body_gz
+        FROM public.raw_responses WHERE content_hash = $1
+        "#,
+        &[&content_hash],
+    ).await?;
+    let Some(r) = row else { return Ok(None) };
+    let status: i32 = r.get(3);
+    let meta = RawMeta {
+        content_hash: r.get(0),
+        url: r.get(1),
+        fetched_at: r.get(2),
+        http_status: u16::try_from(status).unwrap_or(0),
+        headers: serde_json::from_value(r.get::<_, serde_json::Value>(4))?,
+        truncated: r.get(5),
+    };
+    Ok(Some((meta, r.get(6))))
+}
+
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    pub content_hash: String,
+}
+
+/// Every stored document version with a body hash, oldest first.
+pub async fn list_captures(pool: &PgPool, url_prefix: Option<&str>, since: Option<DateTime<Utc>>) -> Result<Vec<Capture>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        r#"
+        SELECT d.url, v.fetched_at, v.content_hash
+        FROM public.document_versions v
+        JOIN public.ingested_documents d ON d.id = v.document_id
+        WHERE v.content_hash IS NOT NULL
+          AND ($1::text IS NULL OR starts_with(d.url, $1))
+          AND ($2::timestamptz IS NULL OR v.fetched_at >= $2)
+        ORDER BY v.fetched_at, v.id
+        "#,
+        &[&url_prefix, &since],
+    ).await?;
+    Ok(rows.iter().map(|r| Capture { url: r.get(0), fetched_at: r.get(1), content_hash: r.get(2) }).collect())
+}
+
 /* --------------------- Crawl queue helpers --------------------- */
 
 #[derive(Debug, Clone)]
@@ -121,81 +555,828 @@ pub struct QueueItem {
     pub id: i64,
     pub url: String,
     pub priority: i32,
+    pub depth: i32,
+    /// Consecutive failures so far.
+    pub tries: i32,
 }
 
-pub async fn enqueue_many(pool: &PgPool, items: &[(&str, i32)]) -> Result<usize> {
+#[derive(Debug, Clone)]
+pub struct Enqueue<'a> {
+    pub url: &'a str,
+    pub priority: i32,
+    /// Link hops from a seed; seeds are 0.
+    pub depth: i32,
+    pub discovered_from: Option<&'a str>,
+    /// First fetch time for new rows (default now); only ever pulls existing rows forward.
+    pub next_fetch_at: Option<DateTime<Utc>>,
+}
+
+/// Re-discovering a known URL only ever raises its priority and lowers its depth.
+/// Rows go in as one UNNEST statement per chunk; a sitemap can yield 50k URLs.
+pub async fn enqueue_many(pool: &PgPool, items: &[Enqueue<'_>]) -> Result<usize> {
     if items.is_empty() { return Ok(0); }
+
+    // one INSERT .. ON CONFLICT can't touch the same row twice, so merge duplicates first
+    let mut merged: HashMap<String, Enqueue<'_>> = HashMap::new();
+    for it in items {
+        let url = canon::canonical_str(it.url);
+        merged.entry(url)
+            .and_modify(|e| {
+                e.priority = e.priority.max(it.priority);
+                e.depth = e.depth.min(it.depth);
+                e.next_fetch_at = match (e.next_fetch_at, it.next_fetch_at) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            })
+            .or_insert_with(|| it.clone());
+    }
+    // rows without a requested time must not drag an existing next_fetch_at forward
+    let (scheduled, plain): (Vec<_>, Vec<_>) = merged.into_iter().partition(|(_, e)| e.next_fetch_at.is_some());
+
     let client = pool.get().await?;
     let mut n = 0;
-    for (url, prio) in items {
-        let _ = client.execute(
+    for (rows, pull_forward) in [(scheduled, true), (plain, false)] {
+        let next_clause = if pull_forward {
+            "next_fetch_at = LEAST(crawl_queue.next_fetch_at, EXCLUDED.next_fetch_at),"
+        } else {
+            ""
+        };
+        let sql = format!(
             r#"
-            INSERT INTO public.crawl_queue (url, priority)
-            VALUES ($1, $2)
+            INSERT INTO public.crawl_queue (url, priority, depth, discovered_from, next_fetch_at)
+            SELECT u, p, d, f, COALESCE(nf, now())
+            FROM UNNEST($1::text[], $2::int[], $3::int[], $4::text[], $5::timestamptz[]) AS t(u, p, d, f, nf)
             ON CONFLICT (url) DO UPDATE
             SET priority = GREATEST(crawl_queue.priority, EXCLUDED.priority),
+                depth    = LEAST(crawl_queue.depth, EXCLUDED.depth),
+                discovered_from = COALESCE(crawl_queue.discovered_from, EXCLUDED.discovered_from),
+                {next_clause}
                 updated_at = now()
-            "#,
-            &[url, prio],
-        ).await?;
-        n += 1;
+            "#
+        );
+        for chunk in rows.chunks(1000) {
+            let urls: Vec<&str> = chunk.iter().map(|(u, _)| u.as_str()).collect();
+            let prios: Vec<i32> = chunk.iter().map(|(_, e)| e.priority).collect();
+            let depths: Vec<i32> = chunk.iter().map(|(_, e)| e.depth).collect();
+            let froms: Vec<Option<&str>> = chunk.iter().map(|(_, e)| e.discovered_from).collect();
+            let nexts: Vec<Option<DateTime<Utc>>> = chunk.iter().map(|(_, e)| e.next_fetch_at).collect();
+            n += client.execute(&sql, &[&urls, &prios, &depths, &froms, &nexts]).await? as usize;
+        }
     }
     Ok(n)
 }
 
-pub async fn dequeue_due(pool: &PgPool, batch: i64) -> Result<Vec<QueueItem>> {
-    let mut client = pool.get().await?;       // <- must be mutable for build_transaction()
-    let tx = client.build_transaction().start().await?;
-    let rows = tx.query(
+/// Who holds a lease on dequeued rows, and for how long.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    /// Stable per process (`WORKER_ID`), so operators can see who holds what.
+    pub owner: String,
+    /// Renewed every third of this while the item is held; expired leases
+    /// are up for grabs.
+    pub ttl: Duration,
+}
+
+/// Lease up to `batch` due rows. The UPDATE marks them in the same statement
+/// that picks them, so concurrent ticks and other worker processes skip them
+/// until the lease is released by a reschedule or runs out.
+/// Hosts in `skip_hosts` (lowercase, as `breaker::host_key`) are left alone,
+/// so items for hosts whose circuit is open aren't leased only to be deferred.
+pub async fn dequeue_due(pool: &PgPool, batch: i64, lease: &Lease, skip_hosts: &[String]) -> Result<Vec<QueueItem>> {
+    let client = pool.get().await?;
+    let rows = client.query(
         r#"
-        SELECT id, url, priority
-        FROM public.crawl_queue
-        WHERE next_fetch_at <= now()
-        ORDER BY priority DESC, id
-        LIMIT $1
-        FOR UPDATE SKIP LOCKED
+        UPDATE public.crawl_queue
+        SET leased_until = now() + $3::bigint * interval '1 millisecond',
+            leased_by    = $2,
+            updated_at   = now()
+        WHERE id IN (
+          SELECT id
+          FROM public.crawl_queue
+          WHERE next_fetch_at <= now() AND state = 'active'
+            AND (leased_until IS NULL OR leased_until < now())
+            AND (cardinality($4::text[]) = 0
+                 OR lower(coalesce(substring(url from '^[^:/]+://(?:[^/?#@]*@)?(\[[^]]*\]|[^/:?#]*)'), '')) <> ALL($4))
+          ORDER BY priority DESC, id
+          LIMIT $1
+          FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, url, priority, depth, tries
         "#,
-        &[&batch],
+        &[&batch, &lease.owner, &(lease.ttl.as_millis() as i64), &skip_hosts],
     ).await?;
-    let items = rows.iter().map(|r| QueueItem {
+    let mut items: Vec<QueueItem> = rows.iter().map(|r| QueueItem {
         id: r.get(0),
         url: r.get(1),
         priority: r.get(2),
+        depth: r.get(3),
+        tries: r.get(4),
     }).collect();
-    tx.commit().await?;
+    // RETURNING doesn't keep the subquery's order
+    items.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
     Ok(items)
 }
 
-pub async fn reschedule_success(pool: &PgPool, id: i64, http_status: i32) -> Result<()> {
+/// Push out the lease on rows we still hold, so items waiting on host slots
+/// or crawl-delay aren't re-leased by another worker. Returns how many rows
+/// were renewed; rows another worker took over are left alone.
+pub async fn renew_leases(pool: &PgPool, ids: &[i64], lease: &Lease) -> Result<u64> {
+    let client = pool.get().await?;
+    let n = client.execute(
+        r#"
+        UPDATE public.crawl_queue
+        SET leased_until = now() + $3::bigint * interval '1 millisecond'
+        WHERE id = ANY($1) AND leased_by = $2
+        "#,
+        &[&ids, &lease.owner, &(lease.ttl.as_millis() as i64)],
+    ).await?;
+    Ok(n)
+}
+
+/// The reschedule functions below only touch rows still leased by `owner`:
+/// if our lease ran out and another worker took the row, its result wins.
+pub async fn reschedule_success(pool: &PgPool, id: i64, owner: &str, http_status: i32) -> Result<()> {
     let client = pool.get().await?;
     client.execute(
         r#"
         UPDATE public.crawl_queue
         SET last_status   = $2,
             last_error    = NULL,
+            error_code    = NULL,
             tries         = 0,
             next_fetch_at = now() + interval '6 hours',
+            leased_until  = NULL,
+            leased_by     = NULL,
             updated_at    = now()
-        WHERE id = $1
+        WHERE id = $1 AND leased_by = $3
         "#,
-        &[&id, &http_status],
+        &[&id, &http_status, &owner],
     ).await?;
     Ok(())
 }
 
-pub async fn reschedule_failure(pool: &PgPool, id: i64, err: &str, backoff_minutes: i64) -> Result<()> {
+/// `error_code` is the stable category (`ScrapeError::code`), `err` the detail.
+pub async fn reschedule_failure(
+    pool: &PgPool,
+    id: i64,
+    owner: &str,
+    http_status: Option<i32>,
+    error_code: &str,
+    err: &str,
+    backoff: Duration,
+) -> Result<()> {
     let client = pool.get().await?;
     client.execute(
         r#"
         UPDATE public.crawl_queue
-        SET last_status   = NULL,
-            last_error    = $2,
+        SET last_status   = $2,
+            last_error    = $4,
+            error_code    = $3,
             tries         = tries + 1,
-            next_fetch_at = now() + make_interval(mins := $3),
+            next_fetch_at = now() + $5::bigint * interval '1 millisecond',
+            leased_until  = NULL,
+            leased_by     = NULL,
+            updated_at    = now()
+        WHERE id = $1 AND leased_by = $6
+        "#,
+        &[&id, &http_status, &error_code, &err, &(backoff.as_millis() as i64), &owner],
+    ).await?;
+    Ok(())
+}
+
+/// Put an item back untouched until `wait` has passed: no try is counted and
+/// the last error stays as it was. For items we chose not to fetch.
+pub async fn defer(pool: &PgPool, id: i64, owner: &str, wait: Duration) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        r#"
+        UPDATE public.crawl_queue
+        SET next_fetch_at = now() + $2::bigint * interval '1 millisecond',
+            leased_until  = NULL,
+            leased_by     = NULL,
+            updated_at    = now()
+        WHERE id = $1 AND leased_by = $3
+        "#,
+        &[&id, &(wait.as_millis() as i64), &owner],
+    ).await?;
+    Ok(())
+}
+
+/// Park an item that won't be retried; `dequeue_due` skips it until requeued.
+pub async fn dead_letter(pool: &PgPool, id: i64, owner: &str, http_status: Option<i32>, error_code: &str, err: &str) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        r#"
+        UPDATE public.crawl_queue
+        SET state       = 'dead',
+            dead_at     = now(),
+            last_status = $2,
+            error_code  = $3,
+            last_error  = $4,
+            tries       = tries + 1,
+            leased_until = NULL,
+            leased_by   = NULL,
+            updated_at  = now()
+        WHERE id = $1 AND leased_by = $5
+        "#,
+        &[&id, &http_status, &error_code, &err, &owner],
+    ).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeadItem {
+    pub id: i64,
+    pub url: String,
+    pub tries: i32,
+    pub last_status: Option<i32>,
+    pub error_code: Option<String>,
+    pub last_error: Option<String>,
+    pub dead_at: Option<DateTime<Utc>>,
+}
+
+pub async fn list_dead(pool: &PgPool, error_code: Option<&str>, limit: i64) -> Result<Vec<DeadItem>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        r#"
+        SELECT id, url, tries, last_status, error_code, last_error, dead_at
+        FROM public.crawl_queue
+        WHERE state = 'dead' AND ($1::text IS NULL OR error_code = $1)
+        ORDER BY dead_at DESC NULLS LAST, id
+        LIMIT $2
+        "#,
+        &[&error_code, &limit],
+    ).await?;
+    Ok(rows.iter().map(|r| DeadItem {
+        id: r.get(0),
+        url: r.get(1),
+        tries: r.get(2),
+        last_status: r.get(3),
+        error_code: r.get(4),
+        last_error: r.get(5),
+        dead_at: r.get(6),
+    }).collect())
+}
+
+/// Put dead items (all, or those with `error_code`) back in rotation with a
+/// clean retry count.
+pub async fn requeue_dead(pool: &PgPool, error_code: Option<&str>) -> Result<u64> {
+    let client = pool.get().await?;
+    let n = client.execute(
+        r#"
+        UPDATE public.crawl_queue
+        SET state         = 'active',
+            dead_at       = NULL,
+            tries         = 0,
+            next_fetch_at = now(),
             updated_at    = now()
+        WHERE state = 'dead' AND ($1::text IS NULL OR error_code = $1)
+        "#,
+        &[&error_code],
+    ).await?;
+    Ok(n)
+}
+
+/* --------------------- Feeds --------------------- */
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Feed {
+    pub id: i64,
+    pub url: String,
+    pub title: Option<String>,
+    pub priority: i32,
+    pub poll_interval_secs: i32,
+    pub next_poll_at: DateTime<Utc>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub last_polled_at: Option<DateTime<Utc>>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+const FEED_COLUMNS: &str = "id, url, title, priority, poll_interval_secs, next_poll_at, etag, last_modified, \
+                            last_polled_at, last_status, last_error";
+
+fn feed_from_row(r: &tokio_postgres::Row) -> Feed {
+    Feed {
+        id: r.get(0),
+        url: r.get(1),
+        title: r.get(2),
+        priority: r.get(3),
+        poll_interval_secs: r.get(4),
+        next_poll_at: r.get(5),
+        etag: r.get(6),
+        last_modified: r.get(7),
+        last_polled_at: r.get(8),
+        last_status: r.get(9),
+        last_error: r.get(10),
+    }
+}
+
+/// Subscribe to a feed, or update priority/interval of an existing
+/// subscription. Either way it becomes due immediately.
+pub async fn upsert_feed(pool: &PgPool, url: &str, priority: i32, poll_interval_secs: i32) -> Result<Feed> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let row = client.query_one(
+        &format!(r#"
+        INSERT INTO public.feeds (url, priority, poll_interval_secs)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (url) DO UPDATE
+        SET priority           = EXCLUDED.priority,
+            poll_interval_secs = EXCLUDED.poll_interval_secs,
+            next_poll_at       = now(),
+            updated_at         = now()
+        RETURNING {FEED_COLUMNS}
+        "#),
+        &[&url, &priority, &poll_interval_secs],
+    ).await?;
+    Ok(feed_from_row(&row))
+}
+
+pub async fn list_feeds(pool: &PgPool) -> Result<Vec<Feed>> {
+    let client = pool.get().await?;
+    let rows = client.query(&format!("SELECT {FEED_COLUMNS} FROM public.feeds ORDER BY priority DESC, id"), &[]).await?;
+    Ok(rows.iter().map(feed_from_row).collect())
+}
+
+/// Claims due feeds by pushing their next poll out one interval, so a
+/// concurrent poller doesn't pick them up too.
+pub async fn claim_due_feeds(pool: &PgPool, limit: i64) -> Result<Vec<Feed>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        &format!(r#"
+        UPDATE public.feeds
+        SET next_poll_at = now() + make_interval(secs := poll_interval_secs),
+            updated_at   = now()
+        WHERE id IN (
+          SELECT id FROM public.feeds
+          WHERE next_poll_at <= now()
+          ORDER BY next_poll_at
+          LIMIT $1
+          FOR UPDATE SKIP LOCKED
+        )
+        RETURNING {FEED_COLUMNS}
+        "#),
+        &[&limit],
+    ).await?;
+    Ok(rows.iter().map(feed_from_row).collect())
+}
+
+/// Record a completed poll. Validators and title are only replaced when the
+/// response carried new ones (a 304 keeps the old ones).
+pub async fn feed_polled(
+    pool: &PgPool,
+    id: i64,
+    http_status: i32,
+    title: Option<&str>,
+    validators: Option<&Validators>,
+) -> Result<()> {
+    let client = pool.get().await?;
+    let (etag, last_modified) = match validators {
+        Some(v) => (v.etag.as_deref(), v.last_modified.as_deref()),
+        None => (None, None),
+    };
+    client.execute(
+        r#"
+        UPDATE public.feeds
+        SET last_status    = $2,
+            last_error     = NULL,
+            last_polled_at = now(),
+            title          = COALESCE($3, title),
+            etag           = CASE WHEN $6 THEN $4 ELSE etag END,
+            last_modified  = CASE WHEN $6 THEN $5 ELSE last_modified END,
+            updated_at     = now()
+        WHERE id = $1
+        "#,
+        &[&id, &http_status, &title, &etag, &last_modified, &validators.is_some()],
+    ).await?;
+    Ok(())
+}
+
+pub async fn feed_failed(pool: &PgPool, id: i64, http_status: Option<i32>, err: &str) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        r#"
+        UPDATE public.feeds
+        SET last_status    = $2,
+            last_error     = $3,
+            last_polled_at = now(),
+            updated_at     = now()
+        WHERE id = $1
+        "#,
+        &[&id, &http_status, &err],
+    ).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedItemRow<'a> {
+    pub guid: &'a str,
+    pub url: &'a str,
+    pub title: Option<&'a str>,
+    pub author: Option<&'a str>,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+/// Store entries of one feed and return the canonical URLs of those not seen
+/// before. An entry is old if either its GUID or its link is already known
+/// for this feed (some feeds regenerate GUIDs on every build).
+pub async fn insert_feed_items(pool: &PgPool, feed_id: i64, items: &[FeedItemRow<'_>]) -> Result<Vec<String>> {
+    if items.is_empty() { return Ok(Vec::new()); }
+
+    let mut guids: Vec<&str> = Vec::new();
+    let mut urls: Vec<String> = Vec::new();
+    let mut titles: Vec<Option<&str>> = Vec::new();
+    let mut authors: Vec<Option<&str>> = Vec::new();
+    let mut published: Vec<Option<DateTime<Utc>>> = Vec::new();
+    let mut seen_guids = std::collections::HashSet::new();
+    let mut seen_urls = std::collections::HashSet::new();
+    for it in items {
+        let url = canon::canonical_str(it.url);
+        if !seen_guids.insert(it.guid) || !seen_urls.insert(url.clone()) {
+            continue;
+        }
+        guids.push(it.guid);
+        urls.push(url);
+        titles.push(it.title);
+        authors.push(it.author);
+        published.push(it.published_at);
+    }
+
+    let client = pool.get().await?;
+    let rows = client.query(
+        r#"
+        INSERT INTO public.feed_items (feed_id, guid, url, title, author, published_at)
+        SELECT $1, g, u, ti, a, p
+        FROM UNNEST($2::text[], $3::text[], $4::text[], $5::text[], $6::timestamptz[]) AS x(g, u, ti, a, p)
+        WHERE NOT EXISTS (
+          SELECT 1 FROM public.feed_items fi WHERE fi.feed_id = $1 AND fi.url = x.u
+        )
+        ON CONFLICT (feed_id, guid) DO NOTHING
+        RETURNING url
+        "#,
+        &[&feed_id, &guids, &urls, &titles, &authors, &published],
+    ).await?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+/* --------------------- Watches and alerts --------------------- */
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Watch {
+    pub id: i64,
+    /// "url" or "domain".
+    pub kind: String,
+    pub target: String,
+    pub label: Option<String>,
+    pub min_change: f64,
+    pub webhook_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+const WATCH_COLUMNS: &str = "id, kind, target, label, min_change, webhook_url, created_at";
+
+fn watch_from_row(r: &tokio_postgres::Row) -> Watch {
+    Watch {
+        id: r.get(0),
+        kind: r.get(1),
+        target: r.get(2),
+        label: r.get(3),
+        min_change: r.get(4),
+        webhook_url: r.get(5),
+        created_at: r.get(6),
+    }
+}
+
+/// Add a watch, or update label/threshold/webhook of an existing one.
+pub async fn upsert_watch(
+    pool: &PgPool,
+    kind: &str,
+    target: &str,
+    label: Option<&str>,
+    min_change: f64,
+    webhook_url: Option<&str>,
+) -> Result<Watch> {
+    let client = pool.get().await?;
+    let row = client.query_one(
+        &format!(r#"
+        INSERT INTO public.watches (kind, target, label, min_change, webhook_url)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (kind, target) DO UPDATE
+        SET label       = EXCLUDED.label,
+            min_change  = EXCLUDED.min_change,
+            webhook_url = EXCLUDED.webhook_url
+        RETURNING {WATCH_COLUMNS}
+        "#),
+        &[&kind, &target, &label, &min_change, &webhook_url],
+    ).await?;
+    Ok(watch_from_row(&row))
+}
+
+pub async fn list_watches(pool: &PgPool) -> Result<Vec<Watch>> {
+    let client = pool.get().await?;
+    let rows = client.query(&format!("SELECT {WATCH_COLUMNS} FROM public.watches ORDER BY id"), &[]).await?;
+    Ok(rows.iter().map(watch_from_row).collect())
+}
+
+pub async fn delete_watch(pool: &PgPool, id: i64) -> Result<bool> {
+    let client = pool.get().await?;
+    Ok(client.execute("DELETE FROM public.watches WHERE id = $1", &[&id]).await? > 0)
+}
+
+/// Watches covering `url`: the URL itself, its host, or a parent domain of it.
+pub async fn watches_for(pool: &PgPool, url: &str, host: &str) -> Result<Vec<Watch>> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(url);
+    let host = host.to_ascii_lowercase();
+    let rows = client.query(
+        &format!(r#"
+        SELECT {WATCH_COLUMNS} FROM public.watches
+        WHERE (kind = 'url' AND target = $1)
+           OR (kind = 'domain' AND ($2 = target OR right($2, length(target) + 1) = '.' || target))
+        ORDER BY id
+        "#),
+        &[&url, &host],
+    ).await?;
+    Ok(rows.iter().map(watch_from_row).collect())
+}
+
+#[derive(Debug, Clone)]
+pub struct NewAlert<'a> {
+    pub watch_id: i64,
+    pub url: &'a str,
+    pub from_version: i32,
+    pub to_version: i32,
+    pub similarity: f64,
+    pub lines_added: i32,
+    pub lines_removed: i32,
+    pub diff: &'a str,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Alert {
+    pub id: i64,
+    pub watch_id: i64,
+    pub url: String,
+    pub from_version: i32,
+    pub to_version: i32,
+    pub similarity: f64,
+    pub lines_added: i32,
+    pub lines_removed: i32,
+    pub diff: String,
+    pub created_at: DateTime<Utc>,
+    pub webhook_status: Option<i32>,
+    pub webhook_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+const ALERT_COLUMNS: &str = "id, watch_id, url, from_version, to_version, similarity, lines_added, lines_removed, \
+                             diff, created_at, webhook_status, webhook_error, delivered_at";
+
+fn alert_from_row(r: &tokio_postgres::Row) -> Alert {
+    Alert {
+        id: r.get(0),
+        watch_id: r.get(1),
+        url: r.get(2),
+        from_version: r.get(3),
+        to_version: r.get(4),
+        similarity: r.get(5),
+        lines_added: r.get(6),
+        lines_removed: r.get(7),
+        diff: r.get(8),
+        created_at: r.get(9),
+        webhook_status: r.get(10),
+        webhook_error: r.get(11),
+        delivered_at: r.get(12),
+    }
+}
+
+/// Record an alert and make its `to_version` the watch's new baseline for
+/// the document.
+pub async fn insert_alert(pool: &PgPool, a: &NewAlert<'_>) -> Result<Alert> {
+    let client = pool.get().await?;
+    let url = canon::canonical_str(a.url);
+    let row = client.query_one(
+        &format!(r#"
+        WITH a AS (
+          INSERT INTO public.alerts
+            (watch_id, document_id, url, from_version, to_version, similarity, lines_added, lines_removed, diff)
+          SELECT $1, id, url, $3, $4, $5, $6, $7, $8
+          FROM public.ingested_documents
+          WHERE url = $2
+          RETURNING {ALERT_COLUMNS}, document_id
+        ), b AS (
+          INSERT INTO public.watch_baselines (watch_id, document_id, version)
+          SELECT watch_id, document_id, to_version FROM a
+          ON CONFLICT (watch_id, document_id) DO UPDATE
+          SET version = EXCLUDED.version, updated_at = now()
+        )
+        SELECT {ALERT_COLUMNS} FROM a
+        "#),
+        &[&a.watch_id, &url, &a.from_version, &a.to_version, &a.similarity, &a.lines_added, &a.lines_removed, &a.diff],
+    ).await?;
+    Ok(alert_from_row(&row))
+}
+
+/// Record the outcome of a webhook POST for an alert.
+pub async fn alert_delivered(pool: &PgPool, id: i64, http_status: Option<i32>, err: Option<&str>) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        r#"
+        UPDATE public.alerts
+        SET webhook_status = $2,
+            webhook_error  = $3,
+            delivered_at   = CASE WHEN $3::text IS NULL THEN now() END
         WHERE id = $1
         "#,
-        &[&id, &err, &backoff_minutes],
+        &[&id, &http_status, &err],
     ).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn list_alerts(pool: &PgPool, watch_id: Option<i64>, limit: i64) -> Result<Vec<Alert>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        &format!(r#"
+        SELECT {ALERT_COLUMNS} FROM public.alerts
+        WHERE $1::bigint IS NULL OR watch_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT $2
+        "#),
+        &[&watch_id, &limit],
+    ).await?;
+    Ok(rows.iter().map(alert_from_row).collect())
+}
+
+/* --------------------- Domain state --------------------- */
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DomainState {
+    pub host: String,
+    pub origin: String,
+    pub circuit: String,
+    pub consecutive_failures: i32,
+    pub open_until: Option<DateTime<Utc>>,
+    pub requests: i64,
+    pub failures: i64,
+    pub avg_latency_ms: Option<f64>,
+    pub error_rate: f64,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_failure_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub robots_fetched_at: Option<DateTime<Utc>>,
+    pub crawl_delay_ms: Option<i32>,
+    pub updated_at: DateTime<Utc>,
+    /// Only filled in for single-host lookups.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub robots_txt: Option<String>,
+}
+
+const DOMAIN_COLUMNS: &str = "host, origin, circuit, consecutive_failures, open_until, requests, failures, avg_latency_ms, \
+    error_rate, last_success_at, last_failure_at, last_error, robots_fetched_at, crawl_delay_ms, updated_at";
+
+fn domain_from_row(r: &tokio_postgres::Row) -> DomainState {
+    DomainState {
+        host: r.get(0),
+        origin: r.get(1),
+        circuit: r.get(2),
+        consecutive_failures: r.get(3),
+        open_until: r.get(4),
+        requests: r.get(5),
+        failures: r.get(6),
+        avg_latency_ms: r.get(7),
+        error_rate: r.get(8),
+        last_success_at: r.get(9),
+        last_failure_at: r.get(10),
+        last_error: r.get(11),
+        robots_fetched_at: r.get(12),
+        crawl_delay_ms: r.get(13),
+        updated_at: r.get(14),
+        robots_txt: None,
+    }
+}
+
+/// Write the breaker's view of each host. Circuit state is replaced; the
+/// request counts and averages take in the snapshot's unsaved requests, so
+/// every worker adds to the same lifetime figures. The robots fields are
+/// left as they are unless the snapshot has a `robots_fetched_at`.
+pub async fn save_domain_states(pool: &PgPool, states: &[DomainSnapshot]) -> Result<()> {
+    let client = pool.get().await?;
+    // weight of the new requests' mean in the stored averages: what n EWMA
+    // steps would give it, or a plain mean while the host has few requests
+    let stmt = client.prepare(
+        r#"
+        INSERT INTO public.domain_state
+          (host, origin, circuit, consecutive_failures, open_until, requests, failures, avg_latency_ms,
+           error_rate, last_success_at, last_failure_at, last_error, robots_txt, robots_fetched_at, crawl_delay_ms)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (host) DO UPDATE
+        SET origin               = EXCLUDED.origin,
+            circuit              = EXCLUDED.circuit,
+            consecutive_failures = EXCLUDED.consecutive_failures,
+            open_until           = EXCLUDED.open_until,
+            requests             = domain_state.requests + EXCLUDED.requests,
+            failures             = domain_state.failures + EXCLUDED.failures,
+            avg_latency_ms       = CASE
+              WHEN EXCLUDED.requests = 0 THEN domain_state.avg_latency_ms
+              WHEN domain_state.avg_latency_ms IS NULL THEN EXCLUDED.avg_latency_ms
+              ELSE domain_state.avg_latency_ms + (EXCLUDED.avg_latency_ms - domain_state.avg_latency_ms)
+                   * greatest(1 - power(1 - $16::float8, EXCLUDED.requests),
+                              EXCLUDED.requests::float8 / (domain_state.requests + EXCLUDED.requests))
+            END,
+            error_rate           = CASE
+              WHEN EXCLUDED.requests = 0 THEN domain_state.error_rate
+              ELSE domain_state.error_rate + (EXCLUDED.error_rate - domain_state.error_rate)
+                   * greatest(1 - power(1 - $16::float8, EXCLUDED.requests),
+                              EXCLUDED.requests::float8 / (domain_state.requests + EXCLUDED.requests))
+            END,
+            last_success_at      = COALESCE(EXCLUDED.last_success_at, domain_state.last_success_at),
+            last_failure_at      = COALESCE(EXCLUDED.last_failure_at, domain_state.last_failure_at),
+            last_error           = COALESCE(EXCLUDED.last_error, domain_state.last_error),
+            robots_txt           = CASE WHEN EXCLUDED.robots_fetched_at IS NULL THEN domain_state.robots_txt ELSE EXCLUDED.robots_txt END,
+            robots_fetched_at    = COALESCE(EXCLUDED.robots_fetched_at, domain_state.robots_fetched_at),
+            crawl_delay_ms       = CASE WHEN EXCLUDED.robots_fetched_at IS NULL THEN domain_state.crawl_delay_ms ELSE EXCLUDED.crawl_delay_ms END,
+            updated_at           = now()
+        "#,
+    ).await?;
+    for s in states {
+        let n = s.unsaved.requests;
+        let avg_latency_ms = (n > 0).then(|| s.unsaved.latency_ms_sum / n as f64);
+        let error_rate = if n > 0 { s.unsaved.failures as f64 / n as f64 } else { 0.0 };
+        client.execute(&stmt, &[
+            &s.host,
+            &s.origin,
+            &s.circuit.as_str(),
+            &s.consecutive_failures,
+            &s.open_until,
+            &n,
+            &s.unsaved.failures,
+            &avg_latency_ms,
+            &error_rate,
+            &s.last_success_at,
+            &s.last_failure_at,
+            &s.last_error,
+            &s.robots_txt,
+            &s.robots_fetched_at,
+            &s.crawl_delay_ms,
+            &EWMA_ALPHA,
+        ]).await?;
+    }
+    Ok(())
+}
+
+/// Hosts, least healthy first: open and half-open circuits, then by error rate.
+pub async fn list_domain_states(pool: &PgPool, circuit: Option<&str>, limit: i64) -> Result<Vec<DomainState>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        &format!(r#"
+        SELECT {DOMAIN_COLUMNS}
+        FROM public.domain_state
+        WHERE ($1::text IS NULL OR circuit = $1)
+        ORDER BY circuit <> 'closed' DESC, error_rate DESC, host
+        LIMIT $2
+        "#),
+        &[&circuit, &limit],
+    ).await?;
+    Ok(rows.iter().map(domain_from_row).collect())
+}
+
+pub async fn get_domain_state(pool: &PgPool, host: &str) -> Result<Option<DomainState>> {
+    let client = pool.get().await?;
+    let row = client.query_opt(
+        &format!("SELECT {DOMAIN_COLUMNS}, robots_txt FROM public.domain_state WHERE host = $1"),
+        &[&host],
+    ).await?;
+    Ok(row.map(|r| DomainState { robots_txt: r.get(15), ..domain_from_row(&r) }))
+}
+
+/// Hosts that were unwell when last saved, for the breaker to start from.
+pub async fn load_domain_health(pool: &PgPool) -> Result<Vec<DomainSnapshot>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        &format!("SELECT {DOMAIN_COLUMNS} FROM public.domain_state WHERE circuit <> 'closed' OR consecutive_failures > 0"),
+        &[],
+    ).await?;
+    Ok(rows.iter().map(|r| {
+        let d = domain_from_row(r);
+        DomainSnapshot {
+            circuit: Circuit::parse(&d.circuit),
+            host: d.host,
+            origin: d.origin,
+            consecutive_failures: d.consecutive_failures,
+            open_until: d.open_until,
+            requests: d.requests,
+            failures: d.failures,
+            avg_latency_ms: d.avg_latency_ms,
+            error_rate: d.error_rate,
+            last_success_at: d.last_success_at,
+            last_failure_at: d.last_failure_at,
+            last_error: d.last_error,
+            robots_txt: None,
+            robots_fetched_at: None,
+            crawl_delay_ms: None,
+            unsaved: Unsaved::default(),
+        }
+    }).collect())
+}
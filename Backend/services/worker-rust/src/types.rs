@@ -3,7 +3,10 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
+    /// Canonical URL (rel=canonical when the page declares one); the row key.
     pub url: String,
+    /// Canonicalized URL that was actually requested.
+    pub fetched_url: String,
     pub fetched_at: DateTime<Utc>,
 
     pub title: Option<String>,
@@ -20,6 +23,41 @@ pub struct Document {
 
     // Align with DB schema
     pub last_modified: Option<String>,
+
+    // Report-style documents (PDF metadata)
+    pub author: Option<String>,
+    pub published_at: Option<DateTime<Utc>>,
+    /// Char offset in `body_text` where each page starts (page 1 first).
+    pub page_offsets: Option<Vec<i32>>,
+
+    /// Character encoding the HTML body was decoded with (WHATWG name).
+    pub encoding: Option<String>,
+
+    // Structured metadata (OpenGraph, JSON-LD, microdata)
+    pub publisher: Option<String>,
+    pub section: Option<String>,
+    pub image_url: Option<String>,
+    /// Everything the metadata extractor found, for fields we don't type yet.
+    pub metadata: Option<serde_json::Value>,
+
+    /// Stored text is partial: body over its byte cap, text over the char
+    /// cap, or PDF over the page limit.
+    pub truncated: bool,
+    /// Size of the full response body in bytes, when known.
+    pub original_size: Option<i64>,
+    /// `scrape::EXTRACTOR_VERSION` that produced the derived fields.
+    pub extractor_version: i32,
+
+    /// Outbound links (HTML only), resolved and fragment-free; not stored.
+    pub links: Vec<String>,
+}
+
+/// Cache validators from the last successful fetch, sent back as
+/// `If-None-Match` / `If-Modified-Since` on recrawl.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,413 @@
+//! Just enough WARC 1.1 (ISO 28500:2017) to exchange our raw archive:
+//! per-record gzipped `warcinfo`/`response`/`revisit` output, and a reader
+//! for plain or gzipped files from us or other crawlers.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{bufread::MultiGzDecoder, read::GzDecoder, write::GzEncoder, Compression};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::archive::{RawMeta, RawResponse};
+
+const WARC_VERSION: &str = "WARC/1.1";
+const REVISIT_PROFILE: &str = "http://netpreserve.org/warc/1.1/revisit/identical-payload-digest";
+/// Prefix for response headers that no longer describe the decoded body.
+const ORIG_PREFIX: &str = "X-Archive-Orig-";
+/// Largest record block read, and largest body decoded from one; well above
+/// any body cap the crawler archives with.
+const MAX_BLOCK_BYTES: usize = 64 * 1024 * 1024;
+/// Headers about the transfer rather than the payload we archived.
+const TRANSFER_HEADERS: &[&str] = &["content-encoding", "transfer-encoding", "content-length"];
+
+pub struct WarcWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> WarcWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn finish(self) -> Result<W> {
+        Ok(self.out)
+    }
+
+    /// One record as its own gzip member, so readers can seek between them.
+    fn record(&mut self, headers: &[(&str, String)], block: &[u8]) -> Result<()> {
+        let mut gz = GzEncoder::new(&mut self.out, Compression::default());
+        write!(gz, "{WARC_VERSION}\r\n")?;
+        for (k, v) in headers {
+            write!(gz, "{k}: {v}\r\n")?;
+        }
+        write!(gz, "Content-Length: {}\r\n\r\n", block.len())?;
+        gz.write_all(block)?;
+        gz.write_all(b"\r\n\r\n")?;
+        gz.finish()?;
+        Ok(())
+    }
+
+    pub fn warcinfo(&mut self, software: &str) -> Result<()> {
+        let block = format!(
+            "software: {software}\r\nformat: WARC File Format 1.1\r\n\
+             conformsTo: http://iipc.github.io/warc-specifications/specifications/warc-format/warc-1.1/\r\n"
+        );
+        self.record(
+            &[
+                ("WARC-Type", "warcinfo".into()),
+                ("WARC-Record-ID", record_id()),
+                ("WARC-Date", warc_date(Utc::now())),
+                ("Content-Type", "application/warc-fields".into()),
+            ],
+            block.as_bytes(),
+        )
+    }
+
+    pub fn response(&mut self, raw: &RawResponse, uri: &str, date: DateTime<Utc>) -> Result<()> {
+        let mut block = http_head(&raw.meta, raw.body.len());
+        block.extend_from_slice(&raw.body);
+        let mut headers = vec![
+            ("WARC-Type", "response".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", warc_date(date)),
+            ("WARC-Target-URI", uri.to_string()),
+            ("WARC-Payload-Digest", digest(&raw.body)),
+            ("WARC-Block-Digest", digest(&block)),
+            ("Content-Type", "application/http;msgtype=response".into()),
+        ];
+        if raw.meta.truncated {
+            headers.push(("WARC-Truncated", "length".into()));
+        }
+        self.record(&headers, &block)
+    }
+
+    /// A later capture of a body already written as a `response` record.
+    pub fn revisit(
+        &mut self,
+        meta: &RawMeta,
+        uri: &str,
+        date: DateTime<Utc>,
+        refers_to_uri: &str,
+        refers_to_date: DateTime<Utc>,
+    ) -> Result<()> {
+        let block = http_head(meta, 0);
+        self.record(
+            &[
+                ("WARC-Type", "revisit".into()),
+                ("WARC-Record-ID", record_id()),
+                ("WARC-Date", warc_date(date)),
+                ("WARC-Target-URI", uri.to_string()),
+                ("WARC-Profile", REVISIT_PROFILE.into()),
+                ("WARC-Refers-To-Target-URI", refers_to_uri.to_string()),
+                ("WARC-Refers-To-Date", warc_date(refers_to_date)),
+                ("WARC-Payload-Digest", format!("sha256:{}", base32(&hex_bytes(&meta.content_hash)))),
+                ("Content-Type", "application/http;msgtype=response".into()),
+            ],
+            &block,
+        )
+    }
+}
+
+/// Status line and headers for a decoded body of `body_len` bytes.
+fn http_head(meta: &RawMeta, body_len: usize) -> Vec<u8> {
+    let reason = StatusCode::from_u16(meta.http_status).ok().and_then(|s| s.canonical_reason()).unwrap_or("");
+    let mut out = format!("HTTP/1.1 {} {reason}\r\n", meta.http_status);
+    for (k, v) in &meta.headers {
+        if TRANSFER_HEADERS.contains(&k.to_ascii_lowercase().as_str()) {
+            out.push_str(&format!("{ORIG_PREFIX}{k}: {v}\r\n"));
+        } else {
+            out.push_str(&format!("{k}: {v}\r\n"));
+        }
+    }
+    out.push_str(&format!("Content-Length: {body_len}\r\n\r\n"));
+    out.into_bytes()
+}
+
+/// Undo `http_head`'s renaming, so an import round-trips to the headers
+/// originally received.
+pub fn original_headers(headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    let has_orig = headers.iter().any(|(k, _)| k.starts_with(ORIG_PREFIX));
+    headers
+        .into_iter()
+        .filter(|(k, _)| !(has_orig && k.eq_ignore_ascii_case("content-length")))
+        .map(|(k, v)| match k.strip_prefix(ORIG_PREFIX) {
+            Some(orig) => (orig.to_string(), v),
+            None => (k, v),
+        })
+        .collect()
+}
+
+fn warc_date(d: DateTime<Utc>) -> String {
+    d.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn record_id() -> String {
+    // random (version 4) UUID
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let h: String = b.iter().map(|x| format!("{x:02x}")).collect();
+    format!("<urn:uuid:{}-{}-{}-{}-{}>", &h[..8], &h[8..12], &h[12..16], &h[16..20], &h[20..])
+}
+
+/// `sha256:` plus base32, the form WARC tools expect for digests.
+fn digest(data: &[u8]) -> String {
+    format!("sha256:{}", base32(&Sha256::digest(data)))
+}
+
+fn hex_bytes(hex: &str) -> Vec<u8> {
+    (0..hex.len() / 2).filter_map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()).collect()
+}
+
+/// RFC 4648 base32, unpadded.
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buf, mut bits) = (0u32, 0);
+    for &byte in data {
+        buf = (buf << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buf >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buf << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/* ------------------------ reading ------------------------ */
+
+pub struct WarcRecord {
+    pub headers: Vec<(String, String)>,
+    pub block: Vec<u8>,
+}
+
+impl WarcRecord {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct WarcReader {
+    input: Box<dyn BufRead>,
+}
+
+impl WarcReader {
+    /// Accepts plain WARC or WARC gzipped whole or per record.
+    pub fn new<R: BufRead + 'static>(mut input: R) -> Result<Self> {
+        let gzipped = input.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        let input: Box<dyn BufRead> =
+            if gzipped { Box::new(BufReader::new(MultiGzDecoder::new(input))) } else { Box::new(input) };
+        Ok(Self { input })
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<WarcRecord>> {
+        let mut line = String::new();
+        // skip the blank lines that end the previous record
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        if !line.starts_with("WARC/") {
+            bail!("expected a WARC record, found {:?}", line.trim_end());
+        }
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                bail!("WARC header ended early");
+            }
+            let l = line.trim_end_matches(['\r', '\n']);
+            if l.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = l.split_once(':') {
+                headers.push((k.trim().to_string(), v.trim().to_string()));
+            }
+        }
+        let len: usize = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, v)| v.parse().ok())
+            .context("WARC record without Content-Length")?;
+        // the length comes from the file; don't let it size the allocation
+        if len > MAX_BLOCK_BYTES {
+            bail!("WARC record of {len} bytes is over the {MAX_BLOCK_BYTES} byte limit");
+        }
+        let mut block = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut block)?;
+        if block.len() < len {
+            bail!("WARC record shorter than its Content-Length");
+        }
+        Ok(Some(WarcRecord { headers, block }))
+    }
+}
+
+pub struct HttpMessage {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Split an `application/http` response block into status, headers and a
+/// decoded body. `None` if it isn't a response, uses an encoding we can't
+/// undo, or decodes to more than `MAX_BLOCK_BYTES`.
+pub fn parse_http_response(block: &[u8]) -> Option<HttpMessage> {
+    let (head_len, sep) = match block.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (i, 4),
+        None => (block.windows(2).position(|w| w == b"\n\n")?, 2),
+    };
+    let head = std::str::from_utf8(&block[..head_len]).ok()?;
+    let mut lines = head.lines();
+    let status_line = lines.next()?;
+    if !status_line.starts_with("HTTP/") {
+        return None;
+    }
+    let status: u16 = status_line.split_whitespace().nth(1)?.parse().ok()?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let get = |name: &str| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.to_ascii_lowercase());
+
+    let mut body = block[head_len + sep..].to_vec();
+    if get("transfer-encoding").is_some_and(|te| te.contains("chunked")) {
+        body = dechunk(&body)?;
+    }
+    match get("content-encoding").as_deref() {
+        None | Some("identity") | Some("") => {}
+        Some("gzip") | Some("x-gzip") => {
+            let mut out = Vec::new();
+            GzDecoder::new(&body[..]).take(MAX_BLOCK_BYTES as u64 + 1).read_to_end(&mut out).ok()?;
+            if out.len() > MAX_BLOCK_BYTES {
+                return None;
+            }
+            body = out;
+        }
+        Some(_) => return None,
+    }
+    Some(HttpMessage { status, headers, body })
+}
+
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    loop {
+        let eol = data.windows(2).position(|w| w == b"\r\n")?;
+        let size_str = std::str::from_utf8(&data[..eol]).ok()?;
+        let size = usize::from_str_radix(size_str.split(';').next()?.trim(), 16).ok()?;
+        data = &data[eol + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+
+    fn raw(body: &'static [u8]) -> RawResponse {
+        RawResponse {
+            meta: RawMeta {
+                content_hash: format!("{:x}", Sha256::digest(body)),
+                url: "https://example.org/a".into(),
+                fetched_at: Utc::now(),
+                http_status: 200,
+                headers: vec![("Content-Type".into(), "text/html".into()), ("Content-Encoding".into(), "br".into())],
+                truncated: false,
+            },
+            body: Bytes::from_static(body),
+        }
+    }
+
+    fn read_all(data: Vec<u8>) -> Result<Vec<WarcRecord>> {
+        let mut reader = WarcReader::new(std::io::Cursor::new(data))?;
+        let mut out = Vec::new();
+        while let Some(rec) = reader.next_record()? {
+            out.push(rec);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn written_records_read_back() {
+        let r = raw(b"<p>hello</p>");
+        let date = Utc::now();
+        let mut w = WarcWriter::new(Vec::new());
+        w.warcinfo("test").unwrap();
+        w.response(&r, "https://example.org/a", date).unwrap();
+        w.revisit(&r.meta, "https://example.org/b", date, "https://example.org/a", date).unwrap();
+        let records = read_all(w.finish().unwrap()).unwrap();
+
+        let kinds: Vec<_> = records.iter().map(|r| r.header("warc-type").unwrap()).collect();
+        assert_eq!(kinds, ["warcinfo", "response", "revisit"]);
+        assert_eq!(records[1].header("WARC-Payload-Digest"), records[2].header("WARC-Payload-Digest"));
+        assert_eq!(records[2].header("WARC-Refers-To-Target-URI"), Some("https://example.org/a"));
+
+        let msg = parse_http_response(&records[1].block).unwrap();
+        assert_eq!(msg.status, 200);
+        assert_eq!(msg.body, b"<p>hello</p>");
+        // the body was stored decoded; the encoding it came with is kept aside
+        assert_eq!(original_headers(msg.headers), r.meta.headers);
+        assert!(parse_http_response(&records[2].block).unwrap().body.is_empty());
+    }
+
+    #[test]
+    fn reads_plain_warc() {
+        let rec = b"WARC/1.0\r\nWARC-Type: response\r\nContent-Length: 5\r\n\r\nhello\r\n\r\n";
+        let records = read_all(rec.to_vec()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].block, b"hello");
+    }
+
+    #[test]
+    fn refuses_malformed_records() {
+        assert!(read_all(b"WARC/1.1\r\nContent-Length: 100\r\n\r\nshort".to_vec()).is_err());
+        assert!(read_all(b"WARC/1.1\r\nWARC-Type: response\r\n\r\n".to_vec()).is_err());
+        assert!(read_all(b"<html>not a warc</html>\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn refuses_oversized_records() {
+        let huge = format!("WARC/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BLOCK_BYTES + 1);
+        assert!(read_all(huge.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn parses_chunked_and_gzipped_bodies() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"hello world").unwrap();
+        let gz = gz.finish().unwrap();
+        let mut chunked = format!("{:x}\r\n", gz.len()).into_bytes();
+        chunked.extend_from_slice(&gz);
+        chunked.extend_from_slice(b"\r\n0\r\n\r\n");
+        let mut block = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n".to_vec();
+        block.extend_from_slice(&chunked);
+        let msg = parse_http_response(&block).unwrap();
+        assert_eq!(msg.body, b"hello world");
+
+        assert_eq!(parse_http_response(b"HTTP/1.0 404 Not Found\n\n").unwrap().status, 404);
+        assert!(parse_http_response(b"GET / HTTP/1.1\r\n\r\n").is_none());
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\nContent-Encoding: br\r\n\r\nxx").is_none());
+    }
+
+    #[test]
+    fn digests_are_base32() {
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(hex_bytes("00ff10"), [0x00, 0xff, 0x10]);
+    }
+}
@@ -0,0 +1,292 @@
+//! Change alerts for watched URLs and domains. A new document version only
+//! means the raw bytes changed; whether that matters is decided on the
+//! extracted text, so rotating ads, timestamps and CSRF tokens in the markup
+//! don't raise alerts.
+
+use reqwest::Client;
+use similar::{Algorithm, ChangeTag, TextDiff};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::egress::EgressPolicy;
+use crate::store::{self, Alert, NewAlert, PgPool, Watch};
+
+/// Text diffs give up and fall back to a coarser result after this long.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest unified diff kept with an alert.
+const MAX_DIFF_CHARS: usize = 8000;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// 0.0 (nothing in common) to 1.0 (same words in the same order).
+    pub similarity: f64,
+    pub lines_added: i32,
+    pub lines_removed: i32,
+    pub diff: String,
+}
+
+/// Compare two extracted texts. Similarity is word-based with whitespace
+/// normalised; the diff is line-based for readability.
+pub fn compare(old: &str, new: &str) -> Change {
+    let norm = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (a, b) = (norm(old), norm(new));
+    let words = TextDiff::configure().algorithm(Algorithm::Patience).timeout(DIFF_TIMEOUT).diff_words(&a, &b);
+    let similarity = f64::from(words.ratio());
+
+    let lines = TextDiff::configure().timeout(DIFF_TIMEOUT).diff_lines(old, new);
+    let (mut added, mut removed) = (0, 0);
+    for c in lines.iter_all_changes() {
+        match c.tag() {
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+    let mut diff = lines.unified_diff().context_radius(1).to_string();
+    if diff.len() > MAX_DIFF_CHARS {
+        let cut = (0..=MAX_DIFF_CHARS).rev().find(|&i| diff.is_char_boundary(i)).unwrap_or(0);
+        diff.truncate(cut);
+        diff.push_str("\n[diff truncated]\n");
+    }
+    Change { similarity, lines_added: added, lines_removed: removed, diff }
+}
+
+/// Vet a watch's webhook when it is created, so an address the egress
+/// policy refuses is rejected up front instead of failing every delivery.
+pub async fn check_webhook(policy: &EgressPolicy, hook: &str) -> Result<Url, String> {
+    let url = Url::parse(hook.trim()).map_err(|_| "bad webhook_url".to_string())?;
+    policy.vet(&url).await.map_err(|e| format!("webhook_url not allowed: {e}"))?;
+    Ok(url)
+}
+
+#[derive(Clone)]
+pub struct Alerter {
+    http: Client,
+    /// Used for watches that don't name their own webhook.
+    default_webhook: Option<String>,
+}
+
+impl Alerter {
+    pub fn new(http: Client, default_webhook: Option<String>) -> Self {
+        Self { http, default_webhook }
+    }
+
+    /// Called after `version` of `url` was stored with `text`. For every
+    /// watch covering the URL, compares it to the watch's baseline version
+    /// and records an alert where the change reaches the watch's threshold;
+    /// the alerted version becomes the new baseline. Failures are logged: the
+    /// document itself was stored fine. Returns alerts raised.
+    pub async fn on_new_version(&self, pg: &PgPool, url: &str, version: i32, text: &str) -> usize {
+        if version <= 1 {
+            return 0;
+        }
+        let Some(host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)) else { return 0 };
+        let watches = match store::watches_for(pg, url, &host).await {
+            Ok(w) if w.is_empty() => return 0,
+            Ok(w) => w,
+            Err(e) => {
+                error!(error=?e, url=%url, "watch lookup failed");
+                return 0;
+            }
+        };
+
+        // watches usually share a baseline; compare once per baseline version
+        let mut changes: HashMap<i32, Arc<Change>> = HashMap::new();
+        let mut raised = 0;
+        for w in &watches {
+            let base = match store::watch_baseline(pg, w, url).await {
+                Ok(Some(b)) if b.version < version => b,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!(error=?e, url=%url, watch=w.id, "baseline lookup failed");
+                    continue;
+                }
+            };
+            let change = match changes.get(&base.version) {
+                Some(c) => c.clone(),
+                None => {
+                    let new_text = text.to_string();
+                    match tokio::task::spawn_blocking(move || compare(&base.body_text, &new_text)).await {
+                        Ok(c) => changes.entry(base.version).or_insert(Arc::new(c)).clone(),
+                        Err(e) => {
+                            error!(error=?e, url=%url, "text comparison failed");
+                            continue;
+                        }
+                    }
+                }
+            };
+            if 1.0 - change.similarity < w.min_change {
+                continue;
+            }
+            let new = NewAlert {
+                watch_id: w.id,
+                url,
+                from_version: base.version,
+                to_version: version,
+                similarity: change.similarity,
+                lines_added: change.lines_added,
+                lines_removed: change.lines_removed,
+                diff: &change.diff,
+            };
+            match store::insert_alert(pg, &new).await {
+                Ok(alert) => {
+                    info!(url=%url, watch=w.id, similarity=change.similarity, "content change alert");
+                    raised += 1;
+                    self.deliver(pg, w, alert);
+                }
+                Err(e) => error!(error=?e, url=%url, watch=w.id, "alert insert failed"),
+            }
+        }
+        raised
+    }
+
+    /// POST the alert to the watch's webhook in the background, recording
+    /// the outcome on the alert row.
+    fn deliver(&self, pg: &PgPool, watch: &Watch, alert: Alert) {
+        let Some(hook) = watch.webhook_url.clone().or_else(|| self.default_webhook.clone()) else { return };
+        let (http, pg) = (self.http.clone(), pg.clone());
+        let payload = serde_json::json!({
+            "event": "content_changed",
+            "watch": { "id": watch.id, "kind": watch.kind, "target": watch.target, "label": watch.label },
+            "alert": alert,
+        });
+        tokio::spawn(async move {
+            let res = http.post(&hook).timeout(WEBHOOK_TIMEOUT).json(&payload).send().await;
+            let (status, err) = match res {
+                Ok(r) if r.status().is_success() => (Some(r.status().as_u16() as i32), None),
+                Ok(r) => (Some(r.status().as_u16() as i32), Some(format!("webhook returned {}", r.status()))),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            if let Some(e) = &err {
+                warn!(alert=alert.id, hook=%hook, error=%e, "alert webhook failed");
+            }
+            if let Err(e) = store::alert_delivered(&pg, alert.id, status, err.as_deref()).await {
+                error!(error=?e, alert=alert.id, "recording webhook outcome failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate;
+    use chrono::{Duration as ChronoDuration, Utc};
+
+    #[test]
+    fn similarity_ignores_whitespace_but_the_diff_does_not() {
+        let same = compare("Emissions fell 4%.\nTargets unchanged.", "Emissions  fell 4%.\n\tTargets unchanged.");
+        assert_eq!(same.similarity, 1.0);
+        assert_eq!((same.lines_added, same.lines_removed), (2, 2));
+
+        let identical = compare("a\nb\n", "a\nb\n");
+        assert_eq!((identical.similarity, identical.lines_added, identical.lines_removed), (1.0, 0, 0));
+        assert_eq!(identical.diff, "");
+    }
+
+    #[test]
+    fn similarity_tracks_how_much_text_changed() {
+        let old = "The company reported scope 1 emissions of 1.2 Mt in 2023.\nScope 2 emissions were 0.4 Mt.\n";
+        let small = compare(old, "The company reported scope 1 emissions of 1.3 Mt in 2023.\nScope 2 emissions were 0.4 Mt.\n");
+        let large = compare(old, "Page not found.\n");
+        assert!(small.similarity > 0.9 && small.similarity < 1.0, "{}", small.similarity);
+        assert!(large.similarity < 0.2, "{}", large.similarity);
+        assert_eq!((small.lines_added, small.lines_removed), (1, 1));
+        assert!(small.diff.contains("-The company reported scope 1 emissions of 1.2 Mt in 2023."), "{}", small.diff);
+        assert!(small.diff.contains("+The company reported scope 1 emissions of 1.3 Mt in 2023."), "{}", small.diff);
+    }
+
+    #[test]
+    fn long_diffs_are_cut_on_a_char_boundary() {
+        let old: String = (0..2000).map(|i| format!("ligne {i} été\n")).collect();
+        let new: String = (0..2000).map(|i| format!("ligne {i} hiver\n")).collect();
+        let c = compare(&old, &new);
+        assert!(c.diff.ends_with("\n[diff truncated]\n"));
+        assert!(c.diff.len() <= MAX_DIFF_CHARS + "\n[diff truncated]\n".len());
+        assert_eq!((c.lines_added, c.lines_removed), (2000, 2000));
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_vetted_by_the_egress_policy() {
+        let policy = EgressPolicy::default();
+        for bad in ["not a url", "ftp://hooks.example/x", "http://127.0.0.1:9000/hook", "http://[::1]/hook", "http://localhost/hook"] {
+            assert!(check_webhook(&policy, bad).await.is_err(), "{bad}");
+        }
+        let err = check_webhook(&policy, "http://169.254.169.254/latest").await.unwrap_err();
+        assert!(err.starts_with("webhook_url not allowed: "), "{err}");
+        let ok = check_webhook(&policy, " https://93.184.216.34/hook ").await.unwrap();
+        assert_eq!(ok.as_str(), "https://93.184.216.34/hook");
+    }
+
+    const PAGE: &str = "https://watch.example/report";
+    const V1: &str = "Scope 1 emissions were 1.2 Mt in 2023. Scope 2 emissions were 0.4 Mt. Targets are unchanged.";
+
+    /// A document whose version 1 predates the watch.
+    async fn setup(pg: &PgPool) -> (i64, Watch) {
+        let client = pg.get().await.unwrap();
+        client.execute("DELETE FROM public.watches WHERE target = $1", &[&PAGE]).await.unwrap();
+        client.execute("DELETE FROM public.ingested_documents WHERE url = $1", &[&PAGE]).await.unwrap();
+        let doc: i64 = client
+            .query_one(
+                "INSERT INTO public.ingested_documents (url, fetched_at, body_text, http_status) VALUES ($1, now(), $2, 200) RETURNING id",
+                &[&PAGE, &V1],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        add_version(pg, doc, 1, V1, Utc::now() - ChronoDuration::hours(1)).await;
+        let watch = store::upsert_watch(pg, "url", PAGE, None, 0.1, None).await.unwrap();
+        (doc, watch)
+    }
+
+    async fn add_version(pg: &PgPool, doc: i64, version: i32, text: &str, at: chrono::DateTime<Utc>) {
+        pg.get()
+            .await
+            .unwrap()
+            .execute(
+                "INSERT INTO public.document_versions (document_id, version, body_text, http_status, fetched_at, created_at) \
+                 VALUES ($1, $2, $3, 200, $4, $4)",
+                &[&doc, &version, &text, &at],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_PG_URL"]
+    async fn small_edits_accumulate_against_the_baseline_until_the_threshold() {
+        let pg = store::init_pool(&std::env::var("TEST_PG_URL").expect("TEST_PG_URL")).await.unwrap();
+        migrate::apply_pending(&pg).await.unwrap();
+        let (doc, watch) = setup(&pg).await;
+        let alerter = Alerter::new(Client::new(), None);
+
+        // the first version of a page has nothing to compare with
+        assert_eq!(alerter.on_new_version(&pg, PAGE, 1, V1).await, 0);
+
+        // each edit alone changes ~6% of the text; together they pass the 10% threshold
+        let v2 = "Scope 1 emissions were 1.1 Mt in 2023. Scope 2 emissions were 0.5 Mt. Targets are unchanged.";
+        let v3 = "Scope 1 emissions were 1.1 Mt in 2023. Scope 2 emissions were 0.5 Mt. Targets are under review.";
+        assert!(1.0 - compare(v2, v3).similarity < watch.min_change);
+        add_version(&pg, doc, 2, v2, Utc::now()).await;
+        assert_eq!(alerter.on_new_version(&pg, PAGE, 2, v2).await, 0);
+        assert_eq!(store::watch_baseline(&pg, &watch, PAGE).await.unwrap().unwrap().version, 1);
+
+        add_version(&pg, doc, 3, v3, Utc::now()).await;
+        assert_eq!(alerter.on_new_version(&pg, PAGE, 3, v3).await, 1);
+        let alerts = store::list_alerts(&pg, Some(watch.id), 10).await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].from_version, alerts[0].to_version), (1, 3));
+        assert!(1.0 - alerts[0].similarity >= watch.min_change);
+        assert_eq!(store::watch_baseline(&pg, &watch, PAGE).await.unwrap().unwrap().version, 3);
+
+        // compared with the new baseline, an unchanged text is no alert
+        add_version(&pg, doc, 4, v3, Utc::now()).await;
+        assert_eq!(alerter.on_new_version(&pg, PAGE, 4, v3).await, 0);
+        // nor is a version at or below the baseline
+        assert_eq!(alerter.on_new_version(&pg, PAGE, 3, "Page not found.").await, 0);
+    }
+}